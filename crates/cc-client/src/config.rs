@@ -4,6 +4,7 @@ use serde::Deserialize;
 use anyhow::Result;
 
 use client::config::ServerConfig;
+use tcp_options::TcpOptions;
 
 /// Main application config
 #[derive(Clone, Deserialize)]
@@ -14,6 +15,10 @@ pub struct AppConfig {
     pub proxy_port: u16,
 
     pub servers: Vec<ServerConfig>,
+
+    /// idle and connect timeouts, socket options of local, server and direct connections
+    #[serde(default)]
+    pub tcp: TcpOptions,
 }
 
 fn default_proxy_port() -> u16 {
@@ -37,6 +37,7 @@ async fn main() -> Result<()> {
     tracing::info!(version = env!("CARGO_PKG_VERSION"));
 
     let client = Proxy::new(cfg.proxy_port, ProxyState::All)?;
+    client.set_tcp_options(cfg.tcp).await;
     client.update_pac_content().await;
     for srv in cfg.servers {
         client.add_server(srv).await;
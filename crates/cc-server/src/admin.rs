@@ -0,0 +1,234 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, atomic::Ordering},
+};
+use anyhow::Result;
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{Path, Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+};
+use chrono::Utc;
+use colored::*;
+use hyper::{body::Incoming, server::conn::http1};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tower::util::ServiceExt;
+use crate::{metrics, reload::ConfigHandle};
+
+const UNIX_PREFIX: &str = "unix:";
+const MIN_TOKEN_LEN: usize = 16;
+
+/// Admin HTTP API: active tunnels, counters, Prometheus metrics and tunnel closing
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// loopback address (127.0.0.1:8081) or unix socket (unix:/run/cc-server/admin.sock)
+    pub listen: String,
+
+    /// requests should have "Authorization: Bearer <token>" header
+    pub token: String,
+}
+
+enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl AdminConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.token.len() < MIN_TOKEN_LEN {
+            anyhow::bail!("admin {} should be at least {MIN_TOKEN_LEN} chars", "token".bold());
+        }
+
+        if let Listen::Tcp(addr) = self.listen()?
+            && !addr.ip().is_loopback()
+        {
+            anyhow::bail!("admin {} {} is not a loopback address, use 127.0.0.1 or unix socket",
+                "listen".bold(), addr.to_string().bold());
+        }
+
+        Ok(())
+    }
+
+    fn listen(&self) -> Result<Listen> {
+        match self.listen.strip_prefix(UNIX_PREFIX) {
+            Some("") => anyhow::bail!("admin {} unix socket path is empty", "listen".bold()),
+            Some(path) => Ok(Listen::Unix(PathBuf::from(path))),
+            None => Ok(Listen::Tcp(self.listen.parse()
+                .map_err(|_| anyhow::anyhow!("admin {} {} is not ip:port or unix:path", "listen".bold(), self.listen.bold()))?)),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+/// Binds before the server is started, so wrong address is reported on start
+pub async fn bind(cfg: &AdminConfig) -> Result<Listener> {
+    match cfg.listen()? {
+        Listen::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+        #[cfg(unix)]
+        Listen::Unix(path) => Ok(Listener::Unix(crate::unix::bind(&path, 0o600)?)),
+        #[cfg(not(unix))]
+        Listen::Unix(_) => anyhow::bail!("unix sockets are not supported on this platform"),
+    }
+}
+
+/// Current state is taken from the handle, so reloaded users are shown
+pub async fn serve(listener: Listener, cfg: AdminConfig, handle: Arc<ConfigHandle>) -> Result<()> {
+    let router = router(cfg.token, handle);
+
+    loop {
+        match &listener {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(serve_connection(stream, router.clone()));
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(serve_connection(stream, router.clone()));
+            },
+        }
+    }
+}
+
+async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(stream: S, router: Router) {
+    let service = hyper::service::service_fn(move |request: Request<Incoming>| {
+        router.clone().oneshot(request.map(Body::new))
+    });
+
+    if let Err(err) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+        tracing::debug!("admin connection: {:?}", err);
+    }
+}
+
+fn router(token: String, handle: Arc<ConfigHandle>) -> Router {
+    Router::new()
+        .route("/stats", get(stats))
+        .route("/metrics", get(metrics))
+        .route("/tunnels", get(tunnels))
+        .route("/tunnels/:id", delete(close_tunnel))
+        .layer(middleware::from_fn_with_state(Arc::new(token), auth))
+        .with_state(handle)
+}
+
+async fn auth(State(token): State<Arc<String>>, request: Request, next: Next) -> Response {
+    let authorized = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|value| constant_time_eq(value.as_bytes(), token.as_bytes()));
+
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    next.run(request).await
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    tunnels: u64,
+    active: u64,
+    rx: u64,
+    tx: u64,
+    failed_auth: u64,
+    failed_auth_by_ip: HashMap<IpAddr, u64>,
+    users: Vec<UserStats>,
+}
+
+#[derive(Serialize)]
+struct UserStats {
+    name: String,
+    tunnels: u64,
+    active: u64,
+    rx: u64,
+    tx: u64,
+}
+
+#[derive(Serialize)]
+struct TunnelResponse {
+    id: u64,
+    user: String,
+    peer: SocketAddr,
+    /// None for UDP associate
+    destination: Option<String>,
+    rx: u64,
+    tx: u64,
+    /// seconds
+    age: i64,
+}
+
+async fn stats(State(handle): State<Arc<ConfigHandle>>) -> Json<StatsResponse> {
+    let state = &handle.current().state;
+    let users: Vec<UserStats> = state.users.iter()
+        .map(|user| UserStats {
+            name: user.name.clone(),
+            tunnels: user.state.tunnels.load(Ordering::Relaxed),
+            active: user.state.active.load(Ordering::Relaxed),
+            rx: user.state.rx_total.load(Ordering::Relaxed),
+            tx: user.state.tx_total.load(Ordering::Relaxed),
+        })
+        .collect();
+
+    let (failed_auth, failed_auth_by_ip) = state.stats.failed_auth();
+
+    Json(StatsResponse {
+        tunnels: users.iter().map(|u| u.tunnels).sum(),
+        active: users.iter().map(|u| u.active).sum(),
+        rx: users.iter().map(|u| u.rx).sum(),
+        tx: users.iter().map(|u| u.tx).sum(),
+        failed_auth,
+        failed_auth_by_ip,
+        users,
+    })
+}
+
+async fn metrics(State(handle): State<Arc<ConfigHandle>>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics::render(&handle.current().state))
+}
+
+async fn tunnels(State(handle): State<Arc<ConfigHandle>>) -> Json<Vec<TunnelResponse>> {
+    let now = Utc::now();
+    Json(handle.current().state.stats.tunnels().iter()
+        .map(|tunnel| {
+            let (rx, tx) = tunnel.bytes();
+            TunnelResponse {
+                id: tunnel.id,
+                user: tunnel.user.clone(),
+                peer: tunnel.peer,
+                destination: tunnel.destination.clone(),
+                rx,
+                tx,
+                age: (now - tunnel.started).num_seconds(),
+            }
+        })
+        .collect())
+}
+
+async fn close_tunnel(State(handle): State<Arc<ConfigHandle>>, Path(id): Path<u64>) -> StatusCode {
+    if handle.current().state.stats.close(id) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use anyhow::Result;
+use colored::*;
+use ipnet::IpNet;
+use serde::Deserialize;
+use crate::{limits::limited_ip, persist::write_atomic};
+
+// failure scores are kept for this number of addresses at most
+const MAX_SCORED_ADDRESSES: usize = 65536;
+// decayed scores below it are forgotten
+const MIN_SCORE: f64 = 0.1;
+// failures in quick succession are decayed a bit, they still reach the threshold
+const TOLERANCE: f64 = 0.01;
+
+/// Dynamic banning of probing addresses (fail2ban style).
+/// Every authentication failure adds 1 to the address score, the score decays over time.
+/// Connections from banned addresses are rejected without key derivation.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BanConfig {
+    /// address is banned when its score reaches the threshold
+    #[serde(default = "default_threshold")]
+    pub threshold: u32,
+
+    /// score is halved every decay seconds
+    #[serde(default = "default_decay")]
+    pub decay: u64,
+
+    /// ban duration (sec)
+    #[serde(default = "default_ban_time")]
+    pub ban_time: u64,
+
+    /// never banned, loopback by default (nginx connects from it)
+    #[serde(default = "default_allow_cidr")]
+    pub allow_cidr: Vec<IpNet>,
+
+    /// banned addresses are saved to the file and loaded on start
+    pub file: Option<PathBuf>,
+}
+
+fn default_threshold() -> u32 {
+    10
+}
+
+fn default_decay() -> u64 {
+    600
+}
+
+fn default_ban_time() -> u64 {
+    3600
+}
+
+fn default_allow_cidr() -> Vec<IpNet> {
+    ["127.0.0.0/8", "::1/128"].iter().map(|net| net.parse().unwrap()).collect()
+}
+
+impl BanConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.threshold == 0 {
+            anyhow::bail!("ban {} should be greater than zero", "threshold".bold());
+        }
+
+        if self.decay == 0 {
+            anyhow::bail!("ban {} should be greater than zero", "decay".bold());
+        }
+
+        Ok(())
+    }
+
+    fn is_allowed(&self, ip: &IpAddr) -> bool {
+        self.allow_cidr.iter().any(|net| net.contains(ip))
+    }
+}
+
+struct Score {
+    value: f64,
+    updated: Instant,
+}
+
+impl Score {
+    fn decayed(&self, decay: u64, now: Instant) -> f64 {
+        let half_lifes = now.duration_since(self.updated).as_secs_f64() / decay as f64;
+        self.value * 0.5f64.powf(half_lifes)
+    }
+}
+
+/// Failure scores and banned addresses, kept on config reload
+#[derive(Default)]
+pub struct Bans {
+    scores: Mutex<HashMap<IpAddr, Score>>,
+    /// address (/64 for ipv6) and ban end
+    banned: Mutex<HashMap<IpAddr, SystemTime>>,
+}
+
+impl Bans {
+    /// Loads saved bans if the file is set, missing file is not an error
+    pub fn load(cfg: Option<&BanConfig>) -> Result<Self> {
+        let bans = Self::default();
+        let Some(path) = cfg.and_then(|cfg| cfg.file.as_ref()) else {
+            return Ok(bans);
+        };
+
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(bans),
+            Err(err) => anyhow::bail!("ban file {}: {err}", path.display()),
+        };
+
+        let now = SystemTime::now();
+        let mut banned = bans.banned.lock().unwrap();
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let parsed = line.split_once(' ')
+                .and_then(|(ip, until)| Some((ip.parse::<IpAddr>().ok()?, until.parse::<u64>().ok()?)));
+            let Some((ip, until)) = parsed else {
+                anyhow::bail!("ban file {}: wrong line {line}", path.display());
+            };
+
+            let until = UNIX_EPOCH + Duration::from_secs(until);
+            if until > now {
+                banned.insert(ip, until);
+            }
+        }
+        drop(banned);
+
+        Ok(bans)
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let ip = limited_ip(ip);
+        let mut banned = self.banned.lock().unwrap();
+        match banned.get(&ip) {
+            Some(until) if *until > SystemTime::now() => true,
+            Some(_) => {
+                banned.remove(&ip);
+                false
+            },
+            None => false,
+        }
+    }
+
+    /// Returns true if the address is banned because of this failure
+    pub fn failed(&self, ip: IpAddr, cfg: &BanConfig) -> bool {
+        if cfg.is_allowed(&ip) {
+            return false;
+        }
+
+        let ip = limited_ip(ip);
+        let now = Instant::now();
+
+        let mut scores = self.scores.lock().unwrap();
+        if !scores.contains_key(&ip) && scores.len() >= MAX_SCORED_ADDRESSES {
+            scores.retain(|_, score| score.decayed(cfg.decay, now) >= MIN_SCORE);
+            if scores.len() >= MAX_SCORED_ADDRESSES {
+                return false;
+            }
+        }
+
+        let score = scores.entry(ip).or_insert(Score { value: 0.0, updated: now });
+        score.value = score.decayed(cfg.decay, now) + 1.0;
+        score.updated = now;
+
+        if score.value + TOLERANCE < cfg.threshold as f64 {
+            return false;
+        }
+
+        scores.remove(&ip);
+        drop(scores);
+
+        let until = SystemTime::now() + Duration::from_secs(cfg.ban_time);
+        self.banned.lock().unwrap().insert(ip, until);
+
+        if let Some(path) = &cfg.file
+            && let Err(err) = self.save(path)
+        {
+            tracing::error!("ban file {}: {:?}", path.display(), err);
+        }
+
+        true
+    }
+
+    /// Active bans, one "ip unix_time_of_ban_end" per line
+    fn save(&self, path: &Path) -> Result<()> {
+        let now = SystemTime::now();
+        let content: String = self.banned.lock().unwrap().iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(ip, until)| format!("{ip} {}\n", until.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()))
+            .collect();
+
+        write_atomic(path, &content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BanConfig, Bans};
+    use std::{
+        net::IpAddr,
+        time::{Duration, Instant},
+    };
+
+    fn config() -> BanConfig {
+        BanConfig {
+            threshold: 3,
+            decay: 600,
+            ban_time: 3600,
+            allow_cidr: vec!["192.0.2.0/24".parse().unwrap()],
+            file: None,
+        }
+    }
+
+    #[test]
+    fn banning() {
+        let bans = Bans::default();
+        let cfg = config();
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+
+        assert!(!bans.failed(ip, &cfg));
+        assert!(!bans.failed(ip, &cfg));
+        assert!(!bans.is_banned(ip));
+        assert!(bans.failed(ip, &cfg));
+        assert!(bans.is_banned(ip));
+
+        // allowed addresses are never banned
+        let allowed: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..10 {
+            assert!(!bans.failed(allowed, &cfg));
+        }
+        assert!(!bans.is_banned(allowed));
+
+        // the whole /64 is banned
+        let cfg = BanConfig { threshold: 1, ..config() };
+        assert!(bans.failed("2001:db8::1".parse().unwrap(), &cfg));
+        assert!(bans.is_banned("2001:db8::2".parse().unwrap()));
+        assert!(!bans.is_banned("2001:db8:0:1::1".parse().unwrap()));
+
+        // expired ban
+        let cfg = BanConfig { threshold: 1, ban_time: 0, ..config() };
+        let ip: IpAddr = "198.51.100.2".parse().unwrap();
+        assert!(bans.failed(ip, &cfg));
+        assert!(!bans.is_banned(ip));
+    }
+
+    #[test]
+    fn decay() {
+        let now = Instant::now();
+        let score = super::Score { value: 8.0, updated: now - Duration::from_secs(1200) };
+        assert!((score.decayed(600, now) - 2.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn persistence() {
+        let path = std::env::temp_dir().join(format!("cc-server-bans-{}.txt", std::process::id()));
+        let cfg = BanConfig { threshold: 1, file: Some(path.clone()), ..config() };
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+
+        let bans = Bans::load(Some(&cfg)).unwrap();
+        assert!(bans.failed(ip, &cfg));
+
+        let loaded = Bans::load(Some(&cfg)).unwrap();
+        assert!(loaded.is_banned(ip));
+        assert!(!loaded.is_banned("198.51.100.2".parse().unwrap()));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
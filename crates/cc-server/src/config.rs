@@ -7,6 +7,22 @@ use anyhow::{Result, anyhow};
 use serde::Deserialize;
 use colored::*;
 use crypto::config::{ProtocolConfig, range_from_human_readable};
+use tcp_options::TcpOptions;
+use crate::{
+    admin::AdminConfig,
+    bans::BanConfig,
+    destination::DestinationPolicy,
+    limits::LimitsConfig,
+    outbound::OutboundConfig,
+    proxy_protocol::ProxyProtocolConfig,
+    resolver::ResolverConfig,
+    server::LOCAL_HOST,
+    tls::TlsConfig,
+    traffic::TrafficConfig,
+    udp::UdpConfig,
+    unix::UnixSocketConfig,
+    users::{PreviousKey, UserConfig, DEFAULT_USER},
+};
 
 /// Main application config
 #[derive(Clone, Deserialize)]
@@ -15,26 +31,104 @@ pub struct AppConfig {
     /// server address
     pub address: SocketAddr,
 
+    /// unix socket for nginx, can be used instead of 127.0.0.1 address
+    pub unix_socket: Option<UnixSocketConfig>,
+
     /// outbound address
     pub out_address: Option<IpAddr>,
 
+    /// pool of outbound addresses, can't be used together with out_address
+    pub outbound: Option<OutboundConfig>,
+
     /// protocol configuration
     #[serde(flatten)]
     pub protocol: ProtocolConfig,
 
+    /// previous values of `key` that are still accepted after rotation (--new-key)
+    #[serde(default)]
+    pub previous_keys: Vec<PreviousKey>,
+
+    /// temporary keys derived from `key` (--temp-key) are accepted until they expire
+    #[serde(default)]
+    pub temp_keys: bool,
+
     /// min a max waiting data time (ms) before close the connection
     /// needed to prevent probe for header size
     /// reads random number of bytes, at most u16::MAX (65535)
     /// random timeout for the read is selected in specified range
     #[serde(default = "default_cooldown")]
     #[serde(deserialize_with = "range_from_human_readable")]
-    pub unauth_cooldown: Range<u16>
+    pub unauth_cooldown: Range<u16>,
+
+    /// users with separate keys
+    /// protocol key can be empty if at least one user is set
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+
+    /// allowed and denied destinations
+    /// loopback, link-local and private ranges are denied by default
+    #[serde(default)]
+    pub destination: DestinationPolicy,
+
+    /// max number of accepted headers remembered per max_connect_delay interval (replay protection)
+    /// new connections are rejected if the limit is reached
+    #[serde(default = "default_replay_cache_size")]
+    pub replay_cache_size: usize,
+
+    /// upstream (host:port) for unauthenticated connections, e.g. local nginx with a static site
+    /// received data is replayed to the upstream, so prober sees an ordinary website
+    /// connection is terminated slowly (see unauth_cooldown) if not set
+    pub fallback: Option<String>,
+
+    /// terminate TLS in the server itself instead of nginx
+    /// both https (upgrade) and raw protocol are accepted on the same address
+    pub tls: Option<TlsConfig>,
+
+    /// UDP relay, enabled by default
+    #[serde(default)]
+    pub udp: UdpConfig,
+
+    /// destination resolver, system configuration by default
+    #[serde(default)]
+    pub resolver: ResolverConfig,
+
+    /// admin HTTP API (stats, active tunnels), disabled if not set
+    pub admin: Option<AdminConfig>,
+
+    /// connection and handshake limits
+    #[serde(default)]
+    pub limits: LimitsConfig,
+
+    /// bans addresses with repeated authentication failures, disabled if not set
+    pub ban: Option<BanConfig>,
+
+    /// per-user bandwidth limits and traffic quotas, unlimited if not set
+    pub traffic: Option<TrafficConfig>,
+
+    /// client address is taken from PROXY protocol header sent by trusted reverse proxies, disabled if not set
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
+
+    /// idle and connect timeouts, socket options of inbound and outbound connections
+    #[serde(default)]
+    pub tcp: TcpOptions,
+
+    /// how long (sec) active connections are drained on shutdown before they are cancelled
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
 }
 
 fn default_cooldown() -> Range<u16> {
     50..777
 }
 
+fn default_replay_cache_size() -> usize {
+    65536
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
 impl AppConfig {
     pub fn new<P>(path: P) -> Result<Self> 
     where
@@ -47,7 +141,7 @@ impl AppConfig {
             .map_err(|err| anyhow!("deserialize config: {}", err))
     }
 
-    fn build(cfg_str: &str) -> Result<Self> {
+    pub fn build(cfg_str: &str) -> Result<Self> {
         config::Config::builder()
             .add_source(config::File::from_str(
                 cfg_str,
@@ -58,7 +152,44 @@ impl AppConfig {
             .check()
     }
 
+    /// Reverse proxy that can send https upgrade (and PROXY protocol header), localhost if proxy_protocol is not set
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        match &self.proxy_protocol {
+            Some(proxy_protocol) => proxy_protocol.is_trusted(&ip),
+            None => ip == LOCAL_HOST,
+        }
+    }
+
     fn check(self) -> Result<AppConfig> {
+        self.check_users()?;
+
+        if let Some(outbound) = &self.outbound {
+            if self.out_address.is_some() {
+                anyhow::bail!("{} and {} can't be used together", "out_address".bold(), "outbound".bold());
+            }
+
+            outbound.check(&self.address)?;
+        }
+
+        if let Some(admin) = &self.admin {
+            admin.check()?;
+        }
+
+        if let Some(unix_socket) = &self.unix_socket {
+            unix_socket.check()?;
+        }
+
+        self.limits.check()?;
+
+        if !self.protocol.key_exchange.is_supported() {
+            anyhow::bail!("{} {:?} is not supported by this build, it requires aws_lc_rs crypto",
+                "key_exchange".bold(), self.protocol.key_exchange);
+        }
+
+        if let Some(ban) = &self.ban {
+            ban.check()?;
+        }
+
         if let Some(out_addr) = self.out_address {
             if self.address.ip().is_unspecified() {
                 anyhow::bail!("{} listen to any available ip. \
@@ -83,4 +214,71 @@ impl AppConfig {
 
         Ok(self)
     }
+
+    fn check_users(&self) -> Result<()> {
+        if self.protocol.key.is_empty() && !self.users.iter().any(|u| u.enabled) {
+            anyhow::bail!("{} is empty and there are no enabled users", "key".bold());
+        }
+
+        for (i, user) in self.users.iter().enumerate() {
+            if user.name.is_empty() {
+                anyhow::bail!("user name is empty");
+            }
+
+            if user.key.is_empty() {
+                anyhow::bail!("user {} key is empty", user.name.bold());
+            }
+
+            if user.name == DEFAULT_USER && !self.protocol.key.is_empty() {
+                anyhow::bail!("user name {} is reserved for the main key", DEFAULT_USER.bold());
+            }
+
+            if user.key == self.protocol.key {
+                anyhow::bail!("user {} key is the same as main key", user.name.bold());
+            }
+
+            for other in &self.users[..i] {
+                if other.name == user.name {
+                    anyhow::bail!("user {} is duplicated", user.name.bold());
+                }
+
+                if other.key == user.key {
+                    anyhow::bail!("users {} and {} have the same key", other.name.bold(), user.name.bold());
+                }
+            }
+        }
+
+        self.check_previous_keys()
+    }
+
+    /// Previous key should identify its user like the current one
+    fn check_previous_keys(&self) -> Result<()> {
+        if self.protocol.key.is_empty() && !self.previous_keys.is_empty() {
+            anyhow::bail!("{} is set, but {} is empty", "previous_keys".bold(), "key".bold());
+        }
+
+        if self.protocol.key.is_empty() && self.temp_keys {
+            anyhow::bail!("{} is enabled, but {} is empty", "temp_keys".bold(), "key".bold());
+        }
+
+        let mut keys = vec![(DEFAULT_USER, self.protocol.key.as_str())];
+        keys.extend(self.users.iter().map(|user| (user.name.as_str(), user.key.as_str())));
+
+        let previous = self.previous_keys.iter().map(|previous| (DEFAULT_USER, previous))
+            .chain(self.users.iter().flat_map(|user| user.previous_keys.iter().map(|previous| (user.name.as_str(), previous))));
+
+        for (name, previous) in previous {
+            if previous.key.is_empty() {
+                anyhow::bail!("user {} previous key is empty", name.bold());
+            }
+
+            if let Some((other, _)) = keys.iter().find(|(_, key)| *key == previous.key) {
+                anyhow::bail!("user {} previous key is already used by {}", name.bold(), other.bold());
+            }
+
+            keys.push((name, &previous.key));
+        }
+
+        Ok(())
+    }
 }
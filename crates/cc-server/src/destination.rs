@@ -0,0 +1,196 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+use ipnet::IpNet;
+use serde::Deserialize;
+
+/// Destinations allowed for authenticated clients
+/// addresses are checked after DNS resolution, thus hostname can't be used to bypass the policy
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DestinationPolicy {
+    /// allowed networks, have priority over deny_cidr
+    #[serde(default)]
+    pub allow_cidr: Vec<IpNet>,
+
+    /// denied networks, loopback, link-local and private ranges by default
+    #[serde(default = "default_deny_cidr")]
+    pub deny_cidr: Vec<IpNet>,
+
+    /// allowed ports, any port if empty
+    #[serde(default)]
+    pub allow_ports: Vec<u16>,
+
+    /// denied ports, can be used to protect admin ports of the server
+    #[serde(default)]
+    pub deny_ports: Vec<u16>,
+
+    /// allowed domains (including subdomains), any domain if empty
+    #[serde(default)]
+    pub allow_domains: Vec<String>,
+
+    /// denied domains (including subdomains)
+    #[serde(default)]
+    pub deny_domains: Vec<String>,
+}
+
+pub enum DenyReason {
+    Port(u16),
+    Domain(String),
+    DomainNotAllowed,
+    Address(IpAddr, IpNet),
+}
+
+impl Default for DestinationPolicy {
+    fn default() -> Self {
+        Self {
+            allow_cidr: Vec::new(),
+            deny_cidr: default_deny_cidr(),
+            allow_ports: Vec::new(),
+            deny_ports: Vec::new(),
+            allow_domains: Vec::new(),
+            deny_domains: Vec::new(),
+        }
+    }
+}
+
+fn default_deny_cidr() -> Vec<IpNet> {
+    [
+        "0.0.0.0/8",        // "this" network
+        "10.0.0.0/8",       // private
+        "100.64.0.0/10",    // carrier-grade NAT
+        "127.0.0.0/8",      // loopback
+        "169.254.0.0/16",   // link-local, cloud metadata
+        "172.16.0.0/12",    // private
+        "192.168.0.0/16",   // private
+        "224.0.0.0/4",      // multicast
+        "240.0.0.0/4",      // reserved, broadcast
+        "::/128",           // unspecified
+        "::1/128",          // loopback
+        "fc00::/7",         // unique local
+        "fe80::/10",        // link-local
+        "ff00::/8",         // multicast
+    ]
+    .iter()
+    .map(|net| net.parse().unwrap())
+    .collect()
+}
+
+impl DestinationPolicy {
+    /// check host:port before DNS resolution
+    pub fn check_host(&self, host: &str) -> Result<(), DenyReason> {
+        let Some((domain, port)) = host.rsplit_once(':') else {
+            return Ok(());
+        };
+
+        if let Ok(port) = port.parse::<u16>() {
+            self.check_port(port)?;
+        }
+
+        let domain = domain.trim_start_matches('[').trim_end_matches(']');
+        if domain.parse::<IpAddr>().is_ok() {
+            // checked after resolution
+            return Ok(());
+        }
+
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(pattern) = self.deny_domains.iter().find(|p| domain_matches(&domain, p)) {
+            return Err(DenyReason::Domain(pattern.clone()));
+        }
+
+        if !self.allow_domains.is_empty() && !self.allow_domains.iter().any(|p| domain_matches(&domain, p)) {
+            return Err(DenyReason::DomainNotAllowed);
+        }
+
+        Ok(())
+    }
+
+    /// check resolved address
+    pub fn check_addr(&self, addr: &SocketAddr) -> Result<(), DenyReason> {
+        self.check_port(addr.port())?;
+
+        // ::ffff:127.0.0.1 should be treated as 127.0.0.1
+        let ip = addr.ip().to_canonical();
+        if self.allow_cidr.iter().any(|net| net.contains(&ip)) {
+            return Ok(());
+        }
+
+        if let Some(net) = self.deny_cidr.iter().find(|net| net.contains(&ip)) {
+            return Err(DenyReason::Address(ip, *net));
+        }
+
+        Ok(())
+    }
+
+    fn check_port(&self, port: u16) -> Result<(), DenyReason> {
+        if self.deny_ports.contains(&port)
+            || (!self.allow_ports.is_empty() && !self.allow_ports.contains(&port))
+        {
+            return Err(DenyReason::Port(port));
+        }
+
+        Ok(())
+    }
+}
+
+fn domain_matches(domain: &str, pattern: &str) -> bool {
+    let pattern = pattern.trim_start_matches("*.").trim_end_matches('.').as_bytes();
+    let domain = domain.as_bytes();
+    domain.len() >= pattern.len()
+        && domain[domain.len() - pattern.len()..].eq_ignore_ascii_case(pattern)
+        && (domain.len() == pattern.len() || domain[domain.len() - pattern.len() - 1] == b'.')
+}
+
+impl fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Port(port) => write!(f, "port {port} is not allowed"),
+            Self::Domain(pattern) => write!(f, "domain matches {pattern}"),
+            Self::DomainNotAllowed => write!(f, "domain is not in allowed list"),
+            Self::Address(ip, net) => write!(f, "address {ip} is in {net}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DestinationPolicy, domain_matches};
+
+    #[test]
+    fn default_policy() {
+        let policy = DestinationPolicy::default();
+
+        for addr in ["127.0.0.1:80", "169.254.169.254:80", "10.1.2.3:443", "192.168.1.1:22",
+            "[::1]:80", "[::ffff:127.0.0.1]:80", "[fe80::1]:80", "[fd00::1]:80"]
+        {
+            assert!(policy.check_addr(&addr.parse().unwrap()).is_err(), "{addr}");
+        }
+
+        for addr in ["8.8.8.8:53", "1.1.1.1:443", "[2606:4700::1111]:443"] {
+            assert!(policy.check_addr(&addr.parse().unwrap()).is_ok(), "{addr}");
+        }
+    }
+
+    #[test]
+    fn ports_and_domains() {
+        let policy = DestinationPolicy {
+            allow_cidr: vec!["10.1.0.0/16".parse().unwrap()],
+            deny_ports: vec![25],
+            deny_domains: vec!["example.com".to_owned()],
+            ..Default::default()
+        };
+
+        assert!(policy.check_addr(&"10.1.2.3:80".parse().unwrap()).is_ok());
+        assert!(policy.check_addr(&"10.2.2.3:80".parse().unwrap()).is_err());
+        assert!(policy.check_addr(&"8.8.8.8:25".parse().unwrap()).is_err());
+
+        assert!(policy.check_host("www.example.com:443").is_err());
+        assert!(policy.check_host("example.com:443").is_err());
+        assert!(policy.check_host("notexample.com:443").is_ok());
+        assert!(policy.check_host("mail.google.com:25").is_err());
+
+        assert!(domain_matches("a.b.example.com", "*.example.com"));
+        assert!(!domain_matches("com", "example.com"));
+    }
+}
@@ -1,2 +1,19 @@
+pub mod admin;
+pub mod bans;
 pub mod config;
-pub mod server;
\ No newline at end of file
+pub mod destination;
+pub mod limits;
+pub mod metrics;
+pub mod outbound;
+pub mod persist;
+pub mod proxy_protocol;
+pub mod reload;
+pub mod replay;
+pub mod resolver;
+pub mod server;
+pub mod stats;
+pub mod traffic;
+pub mod users;
+pub mod tls;
+pub mod udp;
+pub mod unix;
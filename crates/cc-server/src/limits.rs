@@ -0,0 +1,222 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    sync::{Mutex, atomic::{AtomicUsize, Ordering}},
+    time::Duration,
+};
+use anyhow::Result;
+use colored::*;
+use serde::Deserialize;
+
+/// Connection limits, junk connections can't exhaust CPU (key derivation) and memory.
+/// Connections over a limit are terminated slowly like unauthenticated ones.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// max number of concurrent connections
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+
+    /// max number of concurrent connections from one ip (/64 for ipv6)
+    #[serde(default = "default_max_connections_per_ip")]
+    pub max_connections_per_ip: usize,
+
+    /// max number of concurrent handshakes (header key derivation)
+    #[serde(default = "default_max_handshakes")]
+    pub max_handshakes: usize,
+
+    /// time (ms) to receive the header, including TLS handshake and https upgrade request
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout: u64,
+}
+
+fn default_max_connections() -> usize {
+    4096
+}
+
+fn default_max_connections_per_ip() -> usize {
+    64
+}
+
+fn default_max_handshakes() -> usize {
+    64
+}
+
+fn default_handshake_timeout() -> u64 {
+    10000
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: default_max_connections(),
+            max_connections_per_ip: default_max_connections_per_ip(),
+            max_handshakes: default_max_handshakes(),
+            handshake_timeout: default_handshake_timeout(),
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn check(&self) -> Result<()> {
+        for (name, value) in [
+            ("max_connections", self.max_connections),
+            ("max_connections_per_ip", self.max_connections_per_ip),
+            ("max_handshakes", self.max_handshakes),
+            ("handshake_timeout", self.handshake_timeout as usize),
+        ] {
+            if value == 0 {
+                anyhow::bail!("limits {} should be greater than zero", name.bold());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_millis(self.handshake_timeout)
+    }
+}
+
+/// Active connections and handshakes, kept on config reload
+#[derive(Default)]
+pub struct Limits {
+    connections: Mutex<Connections>,
+    handshakes: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Connections {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+/// Releases the connection slot on drop
+pub struct ConnectionGuard<'a> {
+    limits: &'a Limits,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        let mut connections = self.limits.connections.lock().unwrap();
+        connections.total -= 1;
+
+        if let Some(ip) = self.ip
+            && let Some(count) = connections.by_ip.get_mut(&ip)
+        {
+            *count -= 1;
+            if *count == 0 {
+                connections.by_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Releases the handshake slot on drop
+pub struct HandshakeGuard<'a> {
+    limits: &'a Limits,
+}
+
+impl Drop for HandshakeGuard<'_> {
+    fn drop(&mut self) {
+        self.limits.handshakes.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Limits {
+    /// None if the global or per ip limit is reached.
+    /// The per ip limit is not applied if the client address is unknown (the reverse proxy address)
+    pub fn connection(&self, ip: Option<IpAddr>, cfg: &LimitsConfig) -> Option<ConnectionGuard<'_>> {
+        let ip = ip.map(limited_ip);
+
+        let mut connections = self.connections.lock().unwrap();
+        let by_ip = ip.and_then(|ip| connections.by_ip.get(&ip).copied()).unwrap_or_default();
+        if connections.total >= cfg.max_connections || by_ip >= cfg.max_connections_per_ip {
+            return None;
+        }
+
+        connections.total += 1;
+        if let Some(ip) = ip {
+            connections.by_ip.insert(ip, by_ip + 1);
+        }
+
+        Some(ConnectionGuard { limits: self, ip })
+    }
+
+    /// None if the handshake limit is reached
+    pub fn handshake(&self, cfg: &LimitsConfig) -> Option<HandshakeGuard<'_>> {
+        self.handshakes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| (count < cfg.max_handshakes).then_some(count + 1))
+            .ok()
+            .map(|_| HandshakeGuard { limits: self })
+    }
+}
+
+/// ipv6 clients usually have /64 at least
+pub(crate) fn limited_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from_bits(ip.to_bits() & !u128::from(u64::MAX))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limits, LimitsConfig};
+    use std::net::IpAddr;
+
+    #[test]
+    fn connections() {
+        let limits = Limits::default();
+        let cfg = LimitsConfig { max_connections: 3, max_connections_per_ip: 2, ..Default::default() };
+        let first: IpAddr = "192.0.2.1".parse().unwrap();
+        let second: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let a = limits.connection(Some(first), &cfg).unwrap();
+        let _b = limits.connection(Some(first), &cfg).unwrap();
+        assert!(limits.connection(Some(first), &cfg).is_none());
+
+        let _c = limits.connection(Some(second), &cfg).unwrap();
+        assert!(limits.connection(Some(second), &cfg).is_none());
+
+        drop(a);
+        assert!(limits.connection(Some(second), &cfg).is_some());
+        assert!(limits.connection(Some(first), &cfg).is_some());
+
+        // the same /64
+        let cfg = LimitsConfig { max_connections_per_ip: 1, ..Default::default() };
+        let _d = limits.connection(Some("2001:db8::1".parse().unwrap()), &cfg).unwrap();
+        assert!(limits.connection(Some("2001:db8::2".parse().unwrap()), &cfg).is_none());
+        assert!(limits.connection(Some("2001:db8:0:1::1".parse().unwrap()), &cfg).is_some());
+    }
+
+    #[test]
+    fn unknown_client_address() {
+        let limits = Limits::default();
+        let cfg = LimitsConfig { max_connections: 3, max_connections_per_ip: 1, ..Default::default() };
+
+        // the reverse proxy connections are limited by max_connections only
+        let a = limits.connection(None, &cfg).unwrap();
+        let _b = limits.connection(None, &cfg).unwrap();
+        let _c = limits.connection(Some("192.0.2.1".parse().unwrap()), &cfg).unwrap();
+        assert!(limits.connection(None, &cfg).is_none());
+
+        drop(a);
+        assert!(limits.connection(None, &cfg).is_some());
+        assert_eq!(limits.connections.lock().unwrap().by_ip.len(), 1);
+    }
+
+    #[test]
+    fn handshakes() {
+        let limits = Limits::default();
+        let cfg = LimitsConfig { max_handshakes: 2, ..Default::default() };
+
+        let a = limits.handshake(&cfg).unwrap();
+        let _b = limits.handshake(&cfg).unwrap();
+        assert!(limits.handshake(&cfg).is_none());
+
+        drop(a);
+        assert!(limits.handshake(&cfg).is_some());
+    }
+}
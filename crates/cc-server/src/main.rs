@@ -1,19 +1,17 @@
-use std::{
-    net::SocketAddr,
-    path::Path,
-};
+use std::path::Path;
 use anyhow::Result;
 use is_terminal::IsTerminal;
 use tracing_subscriber::EnvFilter;
+use chrono::{SecondsFormat, Utc};
 use clap::Parser;
 use colored::*;
-use crypto::kdf::Kdf;
-
-mod config;
-mod server;
-
-use config::AppConfig;
-use server::LOCAL_HOST;
+use crypto::kdf::{Kdf, TempKey, TempKeyInfo};
+use cc_server::{
+    config::AppConfig,
+    reload::ConfigHandle,
+    server::{self, LOCAL_HOST, ShutdownHandle},
+    users::{DEFAULT_USER, PreviousKey},
+};
 
 /// Covert-Connect server
 #[derive(Parser)]
@@ -27,6 +25,26 @@ struct Cli {
 
     #[arg(short, long, help = "Generate new key, update config and exit. New key should be used in client after that.")]
     new_key: bool,
+
+    #[arg(long, value_name = "DAYS", default_value_t = 7, requires = "new_key",
+        help = "The replaced key is accepted for this number of days after --new-key, so clients can be updated. 0 - replaced immediately.")]
+    grace_days: u32,
+
+    #[arg(long, value_name = "HOURS",
+        help = "Generate temporary key that expires after this number of hours and exit. temp_keys should be enabled in config.")]
+    temp_key: Option<u32>,
+
+    #[arg(long, value_name = "NAME", requires = "temp_key", help = "Temporary key holder name, shown in logs (16 bytes at most).")]
+    identity: Option<String>,
+
+    #[arg(long, value_name = "NAME", help = "Add new user with generated key, update config and exit.")]
+    add_user: Option<String>,
+
+    #[arg(long, value_name = "NAME", help = "Disable user, update config and exit.")]
+    disable_user: Option<String>,
+
+    #[arg(long, help = "Show users and exit.")]
+    list_users: bool,
 }
 
 #[tokio::main]
@@ -51,35 +69,275 @@ async fn main() -> Result<()> {
     let url_path = Kdf::derive_url_path(&cfg.protocol.key)?;
 
     if args.url {
-        show_proxy_path(cfg.address, &url_path);
-        Ok(())
+        show_proxy_path(&cfg, &url_path)
     } else if args.new_key {
-        generate_new_key(&args.config, &cfg.protocol.key)
+        generate_new_key(&args.config, &cfg, args.grace_days)
+    } else if let Some(hours) = args.temp_key {
+        generate_temp_key(&cfg, hours, args.identity.as_deref().unwrap_or_default())
+    } else if let Some(name) = args.add_user {
+        add_user(&args.config, &name)
+    } else if let Some(name) = args.disable_user {
+        disable_user(&args.config, &name)
+    } else if args.list_users {
+        list_users(&cfg);
+        Ok(())
     } else {
-        server::serve(cfg, url_path, true).await
+        let handle = ConfigHandle::new(cfg, url_path, Some(args.config)).await?;
+        let shutdown = ShutdownHandle::default();
+        tokio::spawn(shutdown_on_signal(shutdown.clone()));
+        server::serve_with(handle, shutdown, true).await
     }
 }
 
-fn generate_new_key(cfg_path: &Path, old_key: &str) -> Result<()> {
+/// SIGTERM (systemd stop) or SIGINT (Ctrl+C)
+async fn shutdown_on_signal(shutdown: ShutdownHandle) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = terminate.recv() => {},
+            _ = tokio::signal::ctrl_c() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    shutdown.shutdown();
+    Ok(())
+}
+
+/// The old key is moved to previous_keys and accepted for grace_days,
+/// expired previous keys are removed
+fn generate_new_key(cfg_path: &Path, cfg: &AppConfig, grace_days: u32) -> Result<()> {
+    let old_key = cfg.protocol.key.as_str();
+    if old_key.is_empty() {
+        anyhow::bail!("{} is empty, use {} to add a key", "key".bold(), "--add-user".bold());
+    }
+
     // generate new key and update config
     let new_key = Kdf::generate_new_key();
-    print!("\n{}\n{}\n\n", "new key:".green(), new_key.bold());
 
     // probably we can use yaml_rust to preserve comments and format
     // but it's actually easier just find old key and replace with new one
     let config = std::fs::read_to_string(cfg_path)?;
     let config = config.replace(old_key, &new_key);
+
+    let now = Utc::now();
+    let expires = now + chrono::Duration::days(grace_days as i64);
+    let expires = expires.to_rfc3339_opts(SecondsFormat::Secs, true);
+
+    let expired: Vec<&str> = cfg.previous_keys.iter()
+        .filter(|previous| previous.expires.is_some_and(|expires| expires <= now))
+        .map(|previous| previous.key.as_str())
+        .collect();
+
+    let config = match grace_days {
+        0 => config,
+        _ => {
+            let mut lines: Vec<String> = config.lines().map(str::to_owned).collect();
+            remove_list_items(&mut lines, "previous_keys", |item| {
+                expired.iter().any(|key| item.iter().any(|line| line.contains(key)))
+            });
+            insert_list_item(&mut lines, "previous_keys", |indent| vec![
+                format!("{indent}- key: \"{old_key}\""),
+                format!("{indent}  expires: \"{expires}\""),
+            ])?;
+            lines.join("\n") + "\n"
+        },
+    };
+
+    let new_cfg = AppConfig::build(&shellexpand::full(&config)?)?;
+    let old_key_kept = new_cfg.previous_keys.iter().any(|previous| previous.key == old_key);
+    let expired_kept = grace_days > 0 && new_cfg.previous_keys.iter().any(|previous| expired.contains(&previous.key.as_str()));
+    if new_cfg.protocol.key != new_key || old_key_kept != (grace_days > 0) || expired_kept {
+        anyhow::bail!("unable to update the key, please update it manually");
+    }
+
     std::fs::write(cfg_path, config)?;
 
+    print!("\n{}\n{}\n\n", "new key:".green(), new_key.bold());
     print!("Config file {} updated.\nUse new key in the client.\n",
         cfg_path.display().to_string().italic()
     );
 
+    if grace_days > 0 {
+        println!("The old key is accepted until {}.", expires.bold());
+        if !expired.is_empty() {
+            println!("Expired previous keys removed: {}.", expired.len().to_string().bold());
+        }
+    }
+
+    Ok(())
+}
+
+/// Temporary key is derived from the main key, config is not changed
+fn generate_temp_key(cfg: &AppConfig, hours: u32, identity: &str) -> Result<()> {
+    if cfg.protocol.key.is_empty() {
+        anyhow::bail!("{} is empty, temporary keys are derived from it", "key".bold());
+    }
+
+    let expires = Utc::now() + chrono::Duration::hours(hours as i64);
+    let info = TempKeyInfo::new(&cfg.protocol, expires.timestamp(), identity);
+    let temp_key = Kdf::generate_temp_key(&cfg.protocol.key, &info)?;
+
+    print!("\n{}\n{}\n\n", "temporary key:".green(), temp_key.bold());
+    println!("The key is accepted until {}.", expires.to_rfc3339_opts(SecondsFormat::Secs, true).bold());
+
+    if !cfg.temp_keys {
+        tracing::warn!("{} {}", "temp_keys".yellow().bold(), "is not enabled in config, the key is not accepted.".yellow());
+    }
+
     Ok(())
 }
 
-fn show_proxy_path(address: SocketAddr, url_path: &str) {
-    if address.ip() != LOCAL_HOST {
+/// Appends the item to the top level block style list, the list is created if it's not found
+fn insert_list_item(lines: &mut Vec<String>, list: &str, item: impl Fn(&str) -> Vec<String>) -> Result<()> {
+    let header = format!("{list}:");
+
+    match lines.iter().position(|l| l.starts_with(&header)) {
+        Some(pos) => {
+            if lines[pos].trim_end() != header {
+                anyhow::bail!("only block style {} list is supported, please update config manually", header.bold());
+            }
+
+            // use the same indent as other items
+            let indent = lines.get(pos + 1)
+                .filter(|l| l.trim_start().starts_with('-'))
+                .map(|l| l[..l.len() - l.trim_start().len()].to_owned())
+                .unwrap_or_else(|| "  ".to_owned());
+
+            for (i, line) in item(&indent).into_iter().enumerate() {
+                lines.insert(pos + 1 + i, line);
+            }
+        },
+        None => {
+            lines.push(String::new());
+            lines.push(header);
+            lines.extend(item("  "));
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes items of the top level block style list, an item is its `-` line and the deeper indented lines after it
+fn remove_list_items(lines: &mut Vec<String>, list: &str, remove: impl Fn(&[String]) -> bool) {
+    let header = format!("{list}:");
+    let Some(pos) = lines.iter().position(|l| l.trim_end() == header) else {
+        return;
+    };
+
+    let indent = |l: &str| l.len() - l.trim_start().len();
+    let Some(dash) = lines.get(pos + 1).filter(|l| l.trim_start().starts_with('-')).map(|l| indent(l)) else {
+        return;
+    };
+
+    let mut i = pos + 1;
+    while i < lines.len() && indent(&lines[i]) == dash && lines[i].trim_start().starts_with('-') {
+        let end = lines[i + 1..].iter()
+            .position(|l| l.trim().is_empty() || indent(l) <= dash)
+            .map_or(lines.len(), |n| i + 1 + n);
+
+        if remove(&lines[i..end]) {
+            lines.drain(i..end);
+        } else {
+            i = end;
+        }
+    }
+}
+
+fn add_user(cfg_path: &Path, name: &str) -> Result<()> {
+    let new_key = Kdf::generate_new_key();
+
+    // append the user as text to preserve comments and format
+    let config = std::fs::read_to_string(cfg_path)?;
+    let mut lines: Vec<String> = config.lines().map(str::to_owned).collect();
+    insert_list_item(&mut lines, "users", |indent| vec![
+        format!("{indent}- name: \"{name}\""),
+        format!("{indent}  key: \"{new_key}\""),
+    ])?;
+
+    let config = lines.join("\n") + "\n";
+    let new_cfg = AppConfig::build(&shellexpand::full(&config)?)?;
+    if !new_cfg.users.iter().any(|u| u.name == name && u.key == new_key) {
+        anyhow::bail!("unable to add user, please add it manually");
+    }
+
+    std::fs::write(cfg_path, config)?;
+
+    print!("\n{} {}\n{}\n\n", "new user:".green(), name.bold(), new_key.bold());
+    print!("Config file {} updated.\nUse the key above in the user's client.\n",
+        cfg_path.display().to_string().italic()
+    );
+
+    Ok(())
+}
+
+fn disable_user(cfg_path: &Path, name: &str) -> Result<()> {
+    let config = std::fs::read_to_string(cfg_path)?;
+    let mut lines: Vec<String> = config.lines().map(str::to_owned).collect();
+
+    let is_name_line = |line: &str| {
+        let line = line.trim_start().trim_start_matches('-').trim_start();
+        line.strip_prefix("name:")
+            .map(|val| val.trim().trim_matches(|c| c == '"' || c == '\'') == name)
+            .unwrap_or_default()
+    };
+
+    let pos = lines.iter()
+        .position(|l| is_name_line(l))
+        .ok_or_else(|| anyhow::anyhow!("user {} not found", name.bold()))?;
+
+    // the item fields are aligned with "name:"
+    let field_indent = lines[pos].find("name:").unwrap();
+    let item_lines = lines[pos + 1..].iter()
+        .take_while(|l| l.trim().is_empty() || l.len() - l.trim_start().len() == field_indent)
+        .count();
+
+    let enabled_line = " ".repeat(field_indent) + "enabled: false";
+    match lines[pos + 1..pos + 1 + item_lines].iter().position(|l| l.trim_start().starts_with("enabled:")) {
+        Some(enabled_pos) => lines[pos + 1 + enabled_pos] = enabled_line,
+        None => lines.insert(pos + 1, enabled_line),
+    }
+
+    let config = lines.join("\n") + "\n";
+    let new_cfg = AppConfig::build(&shellexpand::full(&config)?)?;
+    if !new_cfg.users.iter().any(|u| u.name == name && !u.enabled) {
+        anyhow::bail!("unable to disable user, please disable it manually");
+    }
+
+    std::fs::write(cfg_path, config)?;
+
+    print!("\nUser {} disabled.\nConfig file {} updated.\n",
+        name.bold(),
+        cfg_path.display().to_string().italic()
+    );
+
+    Ok(())
+}
+
+fn list_users(cfg: &AppConfig) {
+    println!();
+    if !cfg.protocol.key.is_empty() {
+        println!("{} {}", DEFAULT_USER.bold(), "(main key)".italic());
+    }
+
+    for user in &cfg.users {
+        if user.enabled {
+            println!("{}", user.name.bold());
+        } else {
+            println!("{} {}", user.name.bold(), "(disabled)".red());
+        }
+    }
+    println!();
+}
+
+fn show_proxy_path(cfg: &AppConfig, url_path: &str) -> Result<()> {
+    let address = cfg.address;
+    if address.ip() != LOCAL_HOST && cfg.tls.is_none() && cfg.unix_socket.is_none() {
         tracing::warn!("\n{} {} {} {}", address.ip().to_string().yellow().bold(),
                         "can be visible from outside.\naddress".yellow(), 
                         LOCAL_HOST.to_string().yellow().bold(),
@@ -87,14 +345,60 @@ fn show_proxy_path(address: SocketAddr, url_path: &str) {
         );
     }
 
-    print!("\n{}\n{}\n\n", "client path:".green(), url_path.bold());
-    print!("{}\n\
-            location /{url_path} {{\n\
-            \tproxy_pass http://{};\n\
-            \tproxy_set_header Upgrade $http_upgrade;\n\
-            \tproxy_set_header Connection \"Upgrade\";\n\
-            }}\n\n",
-            "nginx config example:".green(),
-            &address
-    );
-}
\ No newline at end of file
+    // every user has its own path derived from the key
+    let mut url_paths = Vec::with_capacity(cfg.users.len() + 1);
+    if !cfg.protocol.key.is_empty() {
+        url_paths.push((DEFAULT_USER.to_owned(), url_path.to_owned()));
+        url_paths.extend(previous_url_paths(DEFAULT_USER, &cfg.previous_keys)?);
+
+        // all temporary keys have the same path
+        if cfg.temp_keys {
+            let header_key = TempKey::header_key(&cfg.protocol.key)?;
+            url_paths.push(("temporary keys".to_owned(), Kdf::derive_url_path(&header_key)?));
+        }
+    }
+    for user in cfg.users.iter().filter(|u| u.enabled) {
+        url_paths.push((user.name.clone(), Kdf::derive_url_path(&user.key)?));
+        url_paths.extend(previous_url_paths(&user.name, &user.previous_keys)?);
+    }
+
+    for (name, url_path) in &url_paths {
+        print!("\n{} {}\n{}\n", "client path:".green(), name.bold(), url_path.bold());
+    }
+
+    if cfg.tls.is_some() {
+        print!("\n{}\n", "TLS is terminated by the server, nginx is not needed.".green());
+        return Ok(());
+    }
+
+    // unix socket can't be reached from the network, it's preferred
+    let upstream = match &cfg.unix_socket {
+        Some(unix_socket) => format!("unix:{}", unix_socket.path.display()),
+        None => address.to_string(),
+    };
+
+    print!("\n{}\n", "nginx config example:".green());
+    for (_, url_path) in &url_paths {
+        print!("location /{url_path} {{\n\
+                \tproxy_pass http://{};\n\
+                \tproxy_http_version 1.1;\n\
+                \tproxy_set_header Upgrade $http_upgrade;\n\
+                \tproxy_set_header Connection \"Upgrade\";\n\
+                }}\n",
+                &upstream
+        );
+    }
+    println!();
+
+    Ok(())
+}
+
+/// nginx location is needed for previous keys until they expire
+fn previous_url_paths(name: &str, previous_keys: &[PreviousKey]) -> Result<Vec<(String, String)>> {
+    let now = Utc::now();
+    previous_keys.iter()
+        .enumerate()
+        .filter(|(_, previous)| previous.expires.is_none_or(|expires| now < expires))
+        .map(|(i, previous)| Ok((format!("{name} (previous key #{})", i + 1), Kdf::derive_url_path(&previous.key)?)))
+        .collect()
+}
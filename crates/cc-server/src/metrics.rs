@@ -0,0 +1,220 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use crate::server::ServerState;
+
+const PREFIX: &str = "cc_server";
+
+// seconds
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const DURATION_BUCKETS: &[f64] = &[1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0, 14400.0, 86400.0];
+
+/// Result of the connection handshake
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Handshake {
+    Success,
+    /// get protocol request
+    SpecialRequest,
+    /// header is not written in one packet
+    BadSize,
+    /// no user key fits, or host can't be decrypted
+    DecryptFailed,
+    Replayed,
+    /// replay cache is full
+    ReplayRejected,
+    /// wrong https upgrade request
+    BadUpgrade,
+    /// header is not received in handshake_timeout
+    Timeout,
+    /// connection or handshake limit is reached
+    OverLimit,
+    /// address is banned for authentication failures
+    Banned,
+    /// temporary key is expired or issued for other protocol parameters
+    Expired,
+    /// client protocol version is not supported
+    UnsupportedVersion,
+}
+
+impl Handshake {
+    const ALL: [Self; 12] = [
+        Self::Success, Self::SpecialRequest, Self::BadSize, Self::DecryptFailed,
+        Self::Replayed, Self::ReplayRejected, Self::BadUpgrade, Self::Timeout, Self::OverLimit, Self::Banned,
+        Self::Expired, Self::UnsupportedVersion,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::SpecialRequest => "special_request",
+            Self::BadSize => "bad_size",
+            Self::DecryptFailed => "decrypt_failed",
+            Self::Replayed => "replayed",
+            Self::ReplayRejected => "replay_rejected",
+            Self::BadUpgrade => "bad_upgrade",
+            Self::Timeout => "timeout",
+            Self::OverLimit => "over_limit",
+            Self::Banned => "banned",
+            Self::Expired => "expired",
+            Self::UnsupportedVersion => "unsupported_version",
+        }
+    }
+}
+
+pub struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: buckets.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        if let Some(pos) = self.buckets.iter().position(|bucket| secs <= *bucket) {
+            self.counts[pos].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros.fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// buckets are cumulative in Prometheus format
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+
+        let mut cumulative = 0;
+        for (bucket, count) in self.buckets.iter().zip(&self.counts) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{PREFIX}_{name}_bucket{{le=\"{bucket}\"}} {cumulative}");
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{PREFIX}_{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{PREFIX}_{name}_sum {sum}");
+        let _ = writeln!(out, "{PREFIX}_{name}_count {count}");
+    }
+}
+
+pub struct Metrics {
+    connections: AtomicU64,
+    handshakes: [AtomicU64; Handshake::ALL.len()],
+    pub dns_latency: Histogram,
+    pub connect_latency: Histogram,
+    connect_failures: AtomicU64,
+    pub tunnel_duration: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            connections: AtomicU64::new(0),
+            handshakes: Default::default(),
+            dns_latency: Histogram::new(LATENCY_BUCKETS),
+            connect_latency: Histogram::new(LATENCY_BUCKETS),
+            connect_failures: AtomicU64::new(0),
+            tunnel_duration: Histogram::new(DURATION_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn connection_accepted(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handshake(&self, result: Handshake) {
+        self.handshakes[result as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connect_failed(&self) {
+        self.connect_failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Prometheus text format
+pub fn render(state: &ServerState) -> String {
+    let metrics = &state.metrics;
+    let mut out = String::with_capacity(4096);
+
+    header(&mut out, "connections_total", "Accepted connections", "counter");
+    let _ = writeln!(out, "{PREFIX}_connections_total {}", metrics.connections.load(Ordering::Relaxed));
+
+    header(&mut out, "handshakes_total", "Handshakes by result", "counter");
+    for result in Handshake::ALL {
+        let _ = writeln!(out, "{PREFIX}_handshakes_total{{result=\"{}\"}} {}",
+            result.label(), metrics.handshakes[result as usize].load(Ordering::Relaxed));
+    }
+
+    // finished tunnels are in user totals, active ones are counted on the fly
+    let tunnels = state.stats.tunnels();
+    header(&mut out, "active_tunnels", "Active tunnels", "gauge");
+    let _ = writeln!(out, "{PREFIX}_active_tunnels {}", tunnels.len());
+
+    header(&mut out, "bytes_total", "Tunnel payload bytes, in - from clients, out - to clients", "counter");
+    for user in state.users.iter() {
+        let (mut rx, mut tx) = (user.state.rx_total.load(Ordering::Relaxed), user.state.tx_total.load(Ordering::Relaxed));
+        for tunnel in tunnels.iter().filter(|tunnel| tunnel.user == user.name) {
+            let (tunnel_rx, tunnel_tx) = tunnel.bytes();
+            rx += tunnel_rx;
+            tx += tunnel_tx;
+        }
+
+        let _ = writeln!(out, "{PREFIX}_bytes_total{{user=\"{}\",direction=\"in\"}} {rx}", user.name);
+        let _ = writeln!(out, "{PREFIX}_bytes_total{{user=\"{}\",direction=\"out\"}} {tx}", user.name);
+    }
+
+    metrics.dns_latency.render(&mut out, "dns_resolution_seconds", "Destination resolution latency");
+    metrics.connect_latency.render(&mut out, "outbound_connect_seconds", "Outbound connect latency");
+
+    header(&mut out, "outbound_connect_failures_total", "Failed outbound connects", "counter");
+    let _ = writeln!(out, "{PREFIX}_outbound_connect_failures_total {}", metrics.connect_failures.load(Ordering::Relaxed));
+
+    metrics.tunnel_duration.render(&mut out, "tunnel_duration_seconds", "Duration of finished tunnels");
+
+    out
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {PREFIX}_{name} {help}");
+    let _ = writeln!(out, "# TYPE {PREFIX}_{name} {kind}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Histogram, LATENCY_BUCKETS};
+    use std::time::Duration;
+
+    #[test]
+    fn histogram() {
+        let histogram = Histogram::new(LATENCY_BUCKETS);
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(30));
+        histogram.observe(Duration::from_secs(60));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "test_seconds", "Test");
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines[0], "# HELP cc_server_test_seconds Test");
+        assert_eq!(lines[1], "# TYPE cc_server_test_seconds histogram");
+        assert_eq!(lines[2], "cc_server_test_seconds_bucket{le=\"0.005\"} 1");
+        assert_eq!(lines[4], "cc_server_test_seconds_bucket{le=\"0.025\"} 1");
+        assert_eq!(lines[5], "cc_server_test_seconds_bucket{le=\"0.05\"} 2");
+        assert_eq!(lines[12], "cc_server_test_seconds_bucket{le=\"10\"} 2");
+        assert_eq!(lines[13], "cc_server_test_seconds_bucket{le=\"+Inf\"} 3");
+        assert_eq!(lines[14], "cc_server_test_seconds_sum 60.033");
+        assert_eq!(lines[15], "cc_server_test_seconds_count 3");
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use anyhow::Result;
+use colored::*;
+use ipnet::Ipv6Net;
+use serde::Deserialize;
+use crate::config::AppConfig;
+
+/// Pool of outbound addresses, every family is used only for destinations of the same family
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutboundConfig {
+    /// IPv4 and IPv6 addresses of the server
+    #[serde(default)]
+    pub addresses: Vec<IpAddr>,
+
+    /// IPv6 addresses are picked from the prefix instead of addresses,
+    /// the prefix should be routed to the server (e.g. ip -6 route add local 2001:db8::/64 dev lo)
+    pub ipv6_prefix: Option<Ipv6Net>,
+
+    /// address selection, random by default
+    #[serde(default)]
+    pub strategy: Strategy,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    #[default]
+    Random,
+    RoundRobin,
+    /// the same address for all connections of the user
+    PerUser,
+    /// the same address for all connections to the destination host
+    PerDestination,
+}
+
+impl OutboundConfig {
+    pub fn check(&self, address: &SocketAddr) -> Result<()> {
+        if self.addresses.is_empty() && self.ipv6_prefix.is_none() {
+            anyhow::bail!("{} has neither addresses nor ipv6_prefix", "outbound".bold());
+        }
+
+        if address.ip().is_unspecified() {
+            anyhow::bail!("{} listen to any available ip. \
+                Please select specific ip in address option or 127.0.0.1 if https mode \
+                or remove outbound option.",
+                address.ip().to_string().bold())
+        }
+
+        for (i, addr) in self.addresses.iter().enumerate() {
+            if addr.is_unspecified() || addr.is_multicast() {
+                anyhow::bail!("outbound address {} is not a unicast address", addr.to_string().bold());
+            }
+
+            if *addr == address.ip() {
+                anyhow::bail!("outbound address {} should not be equal to address", addr.to_string().bold());
+            }
+
+            if self.addresses[..i].contains(addr) {
+                anyhow::bail!("outbound address {} is duplicated", addr.to_string().bold());
+            }
+
+            if addr.is_ipv6() && self.ipv6_prefix.is_some() {
+                anyhow::bail!("outbound address {} can't be used together with ipv6_prefix", addr.to_string().bold());
+            }
+        }
+
+        if let (Some(prefix), IpAddr::V6(ip)) = (&self.ipv6_prefix, address.ip())
+            && prefix.contains(&ip)
+        {
+            anyhow::bail!("outbound ipv6_prefix {} should not contain address", prefix.to_string().bold());
+        }
+
+        Ok(())
+    }
+}
+
+/// Selects outbound address for new connections
+pub struct OutboundPool {
+    v4: Vec<Ipv4Addr>,
+    v6: Vec<Ipv6Addr>,
+    v6_prefix: Option<Ipv6Net>,
+    strategy: Strategy,
+    counter: AtomicU64,
+}
+
+impl OutboundPool {
+    /// out_address is a pool with the single address
+    pub fn new(cfg: &AppConfig) -> Self {
+        match &cfg.outbound {
+            Some(outbound) => Self::with(&outbound.addresses, outbound.ipv6_prefix, outbound.strategy),
+            None => Self::with(&cfg.out_address.into_iter().collect::<Vec<_>>(), None, Strategy::default()),
+        }
+    }
+
+    fn with(addresses: &[IpAddr], v6_prefix: Option<Ipv6Net>, strategy: Strategy) -> Self {
+        Self {
+            v4: addresses.iter().filter_map(|ip| match ip { IpAddr::V4(ip) => Some(*ip), _ => None }).collect(),
+            v6: addresses.iter().filter_map(|ip| match ip { IpAddr::V6(ip) => Some(*ip), _ => None }).collect(),
+            v6_prefix,
+            strategy,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Address of the destination family, None if the system should choose it
+    pub fn select(&self, dest: &SocketAddr, user: &str, host: &str) -> Option<IpAddr> {
+        let has_addresses = match dest {
+            SocketAddr::V4(_) => !self.v4.is_empty(),
+            SocketAddr::V6(_) => !self.v6.is_empty() || self.v6_prefix.is_some(),
+        };
+        if !has_addresses {
+            return None;
+        }
+
+        let key = match self.strategy {
+            Strategy::Random => rand::random::<u128>(),
+            Strategy::RoundRobin => self.counter.fetch_add(1, Ordering::Relaxed) as u128,
+            Strategy::PerUser => hash(user),
+            Strategy::PerDestination => hash(host),
+        };
+
+        Some(match dest {
+            SocketAddr::V4(_) => IpAddr::V4(self.v4[(key % self.v4.len() as u128) as usize]),
+            SocketAddr::V6(_) => match &self.v6_prefix {
+                Some(prefix) => IpAddr::V6(from_prefix(prefix, key)),
+                None => IpAddr::V6(self.v6[(key % self.v6.len() as u128) as usize]),
+            },
+        })
+    }
+}
+
+/// Stable between restarts, thus sticky addresses are kept
+fn hash(value: &str) -> u128 {
+    let hash = blake3::hash(value.as_bytes());
+    u128::from_le_bytes(hash.as_bytes()[..16].try_into().unwrap())
+}
+
+/// Host part of the address is taken from the key, subnet-router anycast (zero host part) is skipped
+fn from_prefix(prefix: &Ipv6Net, key: u128) -> Ipv6Addr {
+    let host_mask = u128::from(prefix.hostmask());
+    let mut host = key & host_mask;
+    if host == 0 && host_mask != 0 {
+        host = 1;
+    }
+
+    Ipv6Addr::from(u128::from(prefix.network()) | host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OutboundConfig, OutboundPool, Strategy};
+    use std::{collections::HashSet, net::{IpAddr, SocketAddr}};
+
+    const DEST_V4: &str = "1.1.1.1:443";
+    const DEST_V6: &str = "[2606:4700::1111]:443";
+
+    fn new_pool(addresses: &[&str], ipv6_prefix: Option<&str>, strategy: Strategy) -> (OutboundConfig, OutboundPool) {
+        let cfg = OutboundConfig {
+            addresses: addresses.iter().map(|ip| ip.parse().unwrap()).collect(),
+            ipv6_prefix: ipv6_prefix.map(|prefix| prefix.parse().unwrap()),
+            strategy,
+        };
+
+        let pool = OutboundPool::with(&cfg.addresses, cfg.ipv6_prefix, strategy);
+        (cfg, pool)
+    }
+
+    fn select(pool: &OutboundPool, dest: &str, user: &str, host: &str) -> Option<IpAddr> {
+        pool.select(&dest.parse::<SocketAddr>().unwrap(), user, host)
+    }
+
+    #[test]
+    fn strategies() {
+        let addresses = ["192.0.2.1", "192.0.2.2", "192.0.2.3", "2001:db8::1"];
+
+        let (_, pool) = new_pool(&addresses, None, Strategy::RoundRobin);
+        let selected: Vec<_> = (0..4).map(|_| select(&pool, DEST_V4, "user", "host").unwrap()).collect();
+        assert_eq!(selected, ["192.0.2.1", "192.0.2.2", "192.0.2.3", "192.0.2.1"].map(|ip| ip.parse::<IpAddr>().unwrap()));
+
+        // family of the destination only
+        assert_eq!(select(&pool, DEST_V6, "user", "host"), Some("2001:db8::1".parse().unwrap()));
+
+        let (_, pool) = new_pool(&addresses, None, Strategy::PerUser);
+        let user1 = select(&pool, DEST_V4, "user1", "host1");
+        assert!((0..10).all(|i| select(&pool, DEST_V4, "user1", &format!("host{i}")) == user1));
+
+        let (_, pool) = new_pool(&addresses, None, Strategy::PerDestination);
+        let host1 = select(&pool, DEST_V4, "user1", "host1");
+        assert!((0..10).all(|i| select(&pool, DEST_V4, &format!("user{i}"), "host1") == host1));
+
+        let (_, pool) = new_pool(&addresses, None, Strategy::Random);
+        let selected: HashSet<_> = (0..100).map(|_| select(&pool, DEST_V4, "user", "host").unwrap()).collect();
+        assert_eq!(selected.len(), 3);
+
+        // no addresses of the family
+        let (_, pool) = new_pool(&["192.0.2.1"], None, Strategy::Random);
+        assert_eq!(select(&pool, DEST_V6, "user", "host"), None);
+    }
+
+    #[test]
+    fn ipv6_prefix() {
+        let (_, pool) = new_pool(&["192.0.2.1"], Some("2001:db8:1:2::/64"), Strategy::Random);
+        let prefix: ipnet::IpNet = "2001:db8:1:2::/64".parse().unwrap();
+
+        let selected: HashSet<_> = (0..100)
+            .map(|_| select(&pool, DEST_V6, "user", "host").unwrap())
+            .inspect(|ip| assert!(prefix.contains(ip) && *ip != prefix.network()))
+            .collect();
+        assert_eq!(selected.len(), 100);
+
+        let (_, pool) = new_pool(&[], Some("2001:db8:1:2::/64"), Strategy::RoundRobin);
+        assert_eq!(select(&pool, DEST_V6, "user", "host"), Some("2001:db8:1:2::1".parse().unwrap()));
+        assert_eq!(select(&pool, DEST_V6, "user", "host"), Some("2001:db8:1:2::1".parse().unwrap()));
+        assert_eq!(select(&pool, DEST_V6, "user", "host"), Some("2001:db8:1:2::2".parse().unwrap()));
+        assert_eq!(select(&pool, DEST_V4, "user", "host"), None);
+
+        let (_, pool) = new_pool(&[], Some("2001:db8::5/128"), Strategy::Random);
+        assert_eq!(select(&pool, DEST_V6, "user", "host"), Some("2001:db8::5".parse().unwrap()));
+    }
+
+    #[test]
+    fn check() {
+        let address: SocketAddr = "192.0.2.10:443".parse().unwrap();
+        let check = |addresses: &[&str], ipv6_prefix: Option<&str>| new_pool(addresses, ipv6_prefix, Strategy::Random).0.check(&address);
+
+        assert!(check(&["192.0.2.1", "2001:db8::1"], None).is_ok());
+        assert!(check(&["192.0.2.1"], Some("2001:db8::/64")).is_ok());
+
+        assert!(check(&[], None).is_err());
+        assert!(check(&["192.0.2.1", "192.0.2.1"], None).is_err());
+        assert!(check(&["192.0.2.10"], None).is_err());
+        assert!(check(&["0.0.0.0"], None).is_err());
+        assert!(check(&["2001:db8::1"], Some("2001:db8::/64")).is_err());
+        assert!(new_pool(&["192.0.2.1"], None, Strategy::Random).0.check(&"0.0.0.0:443".parse().unwrap()).is_err());
+    }
+}
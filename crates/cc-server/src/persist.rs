@@ -0,0 +1,46 @@
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+use anyhow::Result;
+
+/// Replaces the file atomically, so it's never seen half-written.
+/// The content is written to `<name>.tmp` next to the file first
+pub fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    let tmp = tmp_path(path);
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_atomic;
+    use std::fs;
+
+    #[test]
+    fn write_atomic_keeps_other_files() {
+        let dir = std::env::temp_dir().join(format!("cc-server-persist-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.txt");
+        let other = dir.join("state.tmp");
+        fs::write(&other, "other").unwrap();
+
+        write_atomic(&path, "first").unwrap();
+        write_atomic(&path, "second").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(fs::read_to_string(&other).unwrap(), "other");
+        assert!(!dir.join("state.txt.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
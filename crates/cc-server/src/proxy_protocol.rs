@@ -0,0 +1,184 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use anyhow::Result;
+use ipnet::IpNet;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// including CRLF
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+const V2_VERSION: u8 = 0x20;
+const V2_CMD_LOCAL: u8 = 0x00;
+const V2_CMD_PROXY: u8 = 0x01;
+const V2_AF_INET: u8 = 0x10;
+const V2_AF_INET6: u8 = 0x20;
+
+/// HAProxy PROXY protocol (v1 and v2) for the server behind a reverse proxy,
+/// so limits, bans and logs see the client address instead of the proxy one
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyProtocolConfig {
+    /// proxies that should send the header, it's not accepted from other addresses.
+    /// https upgrade is accepted from these addresses only
+    #[serde(default = "default_trusted")]
+    pub trusted: Vec<IpNet>,
+}
+
+fn default_trusted() -> Vec<IpNet> {
+    ["127.0.0.0/8", "::1/128"].iter().map(|net| net.parse().unwrap()).collect()
+}
+
+impl ProxyProtocolConfig {
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// Reads the header, nothing after it is consumed.
+/// Returns the client address, None if the proxy doesn't know it (v1 UNKNOWN, v2 LOCAL, e.g. health checks)
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    // the shortest v1 header is "PROXY UNKNOWN\r\n", so the prefix of any version fits
+    let mut prefix = [0u8; V2_SIGNATURE.len()];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if prefix.starts_with(V1_PREFIX) {
+        read_v1(stream, &prefix).await
+    } else {
+        anyhow::bail!("no PROXY protocol header");
+    }
+}
+
+/// PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S, prefix: &[u8]) -> Result<Option<SocketAddr>> {
+    let mut line = prefix.to_vec();
+
+    // byte by byte, the payload after CRLF shouldn't be read
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            anyhow::bail!("PROXY protocol v1 header is too long");
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[V1_PREFIX.len()..line.len() - 2])?;
+    let mut fields = line.split(' ');
+
+    match fields.next() {
+        Some("TCP4") | Some("TCP6") => {},
+        Some("UNKNOWN") => return Ok(None),
+        _ => anyhow::bail!("wrong PROXY protocol v1 header: {line}"),
+    }
+
+    let parsed = (|| {
+        let ip: IpAddr = fields.next()?.parse().ok()?;
+        let _destination: IpAddr = fields.next()?.parse().ok()?;
+        let port: u16 = fields.next()?.parse().ok()?;
+        Some(SocketAddr::new(ip, port))
+    })();
+
+    match parsed {
+        Some(addr) => Ok(Some(addr)),
+        None => anyhow::bail!("wrong PROXY protocol v1 header: {line}"),
+    }
+}
+
+/// signature, version and command, family and protocol, length, addresses and TLVs
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+
+    let [version_command, family, len @ ..] = header;
+    if version_command & 0xF0 != V2_VERSION {
+        anyhow::bail!("wrong PROXY protocol v2 version {version_command:#x}");
+    }
+
+    // TLVs are skipped, they are in the same block
+    let mut data = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut data).await?;
+
+    match version_command & 0x0F {
+        V2_CMD_LOCAL => return Ok(None),
+        V2_CMD_PROXY => {},
+        command => anyhow::bail!("wrong PROXY protocol v2 command {command:#x}"),
+    }
+
+    // source address, destination address, source port, destination port
+    let addr = match family & 0xF0 {
+        V2_AF_INET if data.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&data[..4]).unwrap());
+            SocketAddr::new(ip.into(), u16::from_be_bytes([data[8], data[9]]))
+        },
+        V2_AF_INET6 if data.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&data[..16]).unwrap());
+            SocketAddr::new(ip.into(), u16::from_be_bytes([data[32], data[33]]))
+        },
+        // unix sockets and unspecified family
+        _ => return Ok(None),
+    };
+
+    Ok(Some(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{V2_SIGNATURE, read_header};
+    use std::net::SocketAddr;
+
+    async fn read(data: &[u8]) -> (anyhow::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = data;
+        let result = read_header(&mut stream).await;
+        (result, stream.to_vec())
+    }
+
+    #[tokio::test]
+    async fn v1() {
+        let (addr, rest) = read(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\npayload").await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"payload");
+
+        let (addr, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        let (addr, rest) = read(b"PROXY UNKNOWN\r\npayload").await;
+        assert_eq!(addr.unwrap(), None);
+        assert_eq!(rest, b"payload");
+
+        assert!(read(b"PROXY TCP4 192.0.2.1 192.0.2.2 port 443\r\n").await.0.is_err());
+        assert!(read(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await.0.is_err());
+        assert!(read(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 200]].concat()).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        // PROXY, TCP over IPv4, addresses and a TLV
+        data.extend_from_slice(&[0x21, 0x11, 0, 16]);
+        data.extend_from_slice(&[192, 0, 2, 1, 192, 0, 2, 2, 0xDC, 0x04, 0x01, 0xBB]);
+        data.extend_from_slice(&[0x04, 0, 1, 0]);
+        data.extend_from_slice(b"payload");
+
+        let (addr, rest) = read(&data).await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"payload");
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        data.extend_from_slice(&"2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&[0xDC, 0x04, 0x01, 0xBB]);
+        assert_eq!(read(&data).await.0.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        // LOCAL, health check
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read(&data).await.0.unwrap(), None);
+
+        // wrong version
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x11, 0x11, 0, 0]);
+        assert!(read(&data).await.0.is_err());
+    }
+}
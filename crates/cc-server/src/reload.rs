@@ -0,0 +1,229 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use anyhow::Result;
+use tokio::{net::TcpListener, select, sync::Notify, task::AbortHandle, time::sleep};
+use tokio_rustls::TlsAcceptor;
+use crypto::kdf::Kdf;
+use crate::{
+    bans::Bans,
+    config::AppConfig,
+    limits::Limits,
+    metrics::Metrics,
+    outbound::OutboundPool,
+    replay::ReplayCache,
+    resolver::Resolver,
+    server::ServerState,
+    stats::Stats,
+    tls,
+    traffic::Traffic,
+    users::Users,
+};
+
+// how often the config file is checked for changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Config and the state derived from it.
+/// Connection takes a snapshot on accept, so tunnels in flight keep the config they were started with.
+pub struct Running {
+    pub cfg: AppConfig,
+    pub state: Arc<ServerState>,
+    pub tls_acceptor: Option<TlsAcceptor>,
+    url_path: String,
+    // certificate watch, stopped with the config
+    cert_watch: Option<AbortHandle>,
+}
+
+impl Running {
+    /// replay cache, limits, bans, traffic, stats and metrics are taken from the previous config
+    async fn new(cfg: AppConfig, url_path: String, previous: Option<&Running>) -> Result<Self> {
+        let state = match previous.map(|running| &running.state) {
+            Some(previous) => ServerState {
+                users: Users::reload(&cfg, &url_path, &previous.users)?,
+                replay_cache: previous.replay_cache.clone(),
+                limits: previous.limits.clone(),
+                resolver: Resolver::new(&cfg.resolver).await?,
+                outbound: OutboundPool::new(&cfg),
+                stats: previous.stats.clone(),
+                metrics: previous.metrics.clone(),
+                bans: previous.bans.clone(),
+                traffic: previous.traffic.clone(),
+            },
+            None => ServerState {
+                users: Users::new(&cfg, &url_path)?,
+                replay_cache: Arc::new(ReplayCache::new(cfg.replay_cache_size)),
+                limits: Arc::new(Limits::default()),
+                resolver: Resolver::new(&cfg.resolver).await?,
+                outbound: OutboundPool::new(&cfg),
+                stats: Arc::new(Stats::default()),
+                metrics: Arc::new(Metrics::default()),
+                bans: Arc::new(Bans::load(cfg.ban.as_ref())?),
+                traffic: Arc::new(Traffic::load(cfg.traffic.as_ref())?),
+            },
+        };
+
+        let (tls_acceptor, cert_watch) = match &cfg.tls {
+            Some(tls_cfg) => {
+                let (acceptor, resolver) = tls::new_acceptor(tls_cfg)?;
+                (Some(acceptor), Some(tokio::spawn(resolver.watch()).abort_handle()))
+            },
+            None => (None, None),
+        };
+
+        Ok(Self { cfg, state: Arc::new(state), tls_acceptor, url_path, cert_watch })
+    }
+
+    /// Stops background tasks of the config, they are stopped on drop too
+    /// (the replaced config is dropped when its tunnels are finished)
+    pub fn stop(&self) {
+        if let Some(cert_watch) = &self.cert_watch {
+            cert_watch.abort();
+        }
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Config that can be replaced without restart: on SIGHUP, config file change or `reload` call.
+/// The new config is applied to new connections only.
+pub struct ConfigHandle {
+    path: Option<PathBuf>,
+    modified: Mutex<Option<SystemTime>>,
+    current: RwLock<Arc<Running>>,
+    // reloads are not concurrent
+    reloading: tokio::sync::Mutex<()>,
+    // listener for the changed address, taken by the accept loop
+    listener: Mutex<Option<TcpListener>>,
+    rebind: Notify,
+}
+
+impl ConfigHandle {
+    /// url_path is already derived for the default key
+    /// path - config file, the config can't be reloaded if not set
+    pub async fn new(cfg: AppConfig, url_path: String, path: Option<PathBuf>) -> Result<Arc<Self>> {
+        let modified = path.as_deref().and_then(modified_time);
+        let running = Running::new(cfg, url_path, None).await?;
+
+        Ok(Arc::new(Self {
+            path,
+            modified: Mutex::new(modified),
+            current: RwLock::new(Arc::new(running)),
+            reloading: Default::default(),
+            listener: Default::default(),
+            rebind: Notify::new(),
+        }))
+    }
+
+    pub fn current(&self) -> Arc<Running> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Re-reads the config file, the running config is kept if the new one is invalid.
+    /// The listener is rebound if the address is changed.
+    pub async fn reload(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            anyhow::bail!("config is not loaded from a file");
+        };
+
+        let _reloading = self.reloading.lock().await;
+
+        // the broken file is not reloaded again until it's changed
+        *self.modified.lock().unwrap() = modified_time(path);
+
+        let cfg = AppConfig::new(path)?;
+        let current = self.current();
+
+        let admin = |cfg: &AppConfig| cfg.admin.as_ref().map(|admin| (admin.listen.clone(), admin.token.clone()));
+        if admin(&cfg) != admin(&current.cfg) {
+            tracing::warn!("admin API changes are applied after restart");
+        }
+
+        if cfg.replay_cache_size != current.cfg.replay_cache_size {
+            tracing::warn!("replay_cache_size change is applied after restart");
+        }
+
+        let unix_socket = |cfg: &AppConfig| cfg.unix_socket.as_ref().map(|unix| (unix.path.clone(), unix.mode.clone()));
+        if unix_socket(&cfg) != unix_socket(&current.cfg) {
+            tracing::warn!("unix_socket changes are applied after restart");
+        }
+
+        let url_path = if cfg.protocol.key == current.cfg.protocol.key {
+            current.url_path.clone()
+        } else {
+            Kdf::derive_url_path(&cfg.protocol.key)?
+        };
+
+        let listener = match cfg.address != current.cfg.address {
+            true => Some(TcpListener::bind(cfg.address).await?),
+            false => None,
+        };
+
+        let running = Running::new(cfg, url_path, Some(&current)).await?;
+        *self.current.write().unwrap() = Arc::new(running);
+
+        if let Some(listener) = listener {
+            *self.listener.lock().unwrap() = Some(listener);
+            self.rebind.notify_one();
+        }
+
+        Ok(())
+    }
+
+    /// Resolves when the listen address is changed, the new listener is returned
+    pub(crate) async fn rebound(&self) -> TcpListener {
+        loop {
+            self.rebind.notified().await;
+            if let Some(listener) = self.listener.lock().unwrap().take() {
+                return listener;
+            }
+        }
+    }
+
+    /// Reloads the config on SIGHUP and when the config file is changed
+    pub(crate) async fn watch(self: Arc<Self>) -> Result<()> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+
+        #[cfg(unix)]
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+
+        loop {
+            #[cfg(unix)]
+            let signal = hangup.recv();
+            #[cfg(not(unix))]
+            let signal = std::future::pending::<Option<()>>();
+
+            select! {
+                _ = signal => tracing::info!("SIGHUP received, reloading config"),
+                _ = self.file_changed(&path) => tracing::info!("config file changed, reloading config"),
+            }
+
+            match self.reload().await {
+                Ok(()) => tracing::info!("config reloaded: {}", path.display()),
+                Err(err) => tracing::error!("config reload failed, running config is kept: {:?}", err),
+            }
+        }
+    }
+
+    async fn file_changed(&self, path: &Path) {
+        loop {
+            sleep(CHECK_INTERVAL).await;
+
+            if modified_time(path) != *self.modified.lock().unwrap() {
+                return;
+            }
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
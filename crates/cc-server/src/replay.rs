@@ -0,0 +1,122 @@
+use std::{
+    collections::HashSet,
+    sync::Mutex,
+};
+
+// 128 bits are more than enough to distinguish random salts
+const FINGERPRINT_LEN: usize = 16;
+
+type Fingerprint = [u8; FINGERPRINT_LEN];
+
+/// Remembers nonce and salt of accepted headers for two timestamp intervals.
+/// Header is valid for the current and the previous interval (see `derive_key_from_timestamp`)
+/// thus older entries can be dropped.
+pub struct ReplayCache {
+    buckets: Mutex<[Bucket; 2]>,
+    max_entries: usize,
+}
+
+#[derive(Default)]
+struct Bucket {
+    timestamp: i64,
+    entries: HashSet<Fingerprint>,
+}
+
+pub enum ReplayCheck {
+    Accepted,
+    Replayed,
+    /// cache is full or timestamp is too old
+    Rejected,
+}
+
+impl ReplayCache {
+    /// max_entries is a limit for one timestamp interval
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            buckets: Default::default(),
+            max_entries,
+        }
+    }
+
+    /// timestamp is a timestamp interval of the connection (connect time / max_connect_delay)
+    pub fn check_and_insert(&self, timestamp: i64, nonce: &[u8], salt: &[u8]) -> ReplayCheck {
+        let fingerprint = fingerprint(nonce, salt);
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let [current, prev] = &mut *buckets;
+
+        if timestamp > current.timestamp {
+            // move to the next interval, forget everything older than previous one
+            let prev_entries = if current.timestamp == timestamp - 1 {
+                std::mem::take(&mut current.entries)
+            } else {
+                HashSet::new()
+            };
+
+            *prev = Bucket { timestamp: timestamp - 1, entries: prev_entries };
+            current.timestamp = timestamp;
+            current.entries.clear();
+        }
+
+        if current.entries.contains(&fingerprint) || prev.entries.contains(&fingerprint) {
+            return ReplayCheck::Replayed;
+        }
+
+        // connection can be processed a bit later than accepted
+        let bucket = if timestamp == current.timestamp {
+            current
+        } else if timestamp == prev.timestamp {
+            prev
+        } else {
+            // too old, we can't say it's not replayed
+            return ReplayCheck::Rejected;
+        };
+
+        if bucket.entries.len() >= self.max_entries {
+            return ReplayCheck::Rejected;
+        }
+
+        bucket.entries.insert(fingerprint);
+        ReplayCheck::Accepted
+    }
+}
+
+fn fingerprint(nonce: &[u8], salt: &[u8]) -> Fingerprint {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(nonce);
+    hasher.update(salt);
+
+    let mut fingerprint = [0u8; FINGERPRINT_LEN];
+    fingerprint.copy_from_slice(&hasher.finalize().as_bytes()[..FINGERPRINT_LEN]);
+    fingerprint
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReplayCache, ReplayCheck};
+
+    #[test]
+    fn replay() {
+        let cache = ReplayCache::new(2);
+        let nonce = [1u8; 12];
+
+        assert!(matches!(cache.check_and_insert(10, &nonce, &[1u8; 32]), ReplayCheck::Accepted));
+        assert!(matches!(cache.check_and_insert(10, &nonce, &[1u8; 32]), ReplayCheck::Replayed));
+        assert!(matches!(cache.check_and_insert(10, &nonce, &[2u8; 32]), ReplayCheck::Accepted));
+        assert!(matches!(cache.check_and_insert(10, &nonce, &[3u8; 32]), ReplayCheck::Rejected));
+
+        // still remembered in the next interval
+        assert!(matches!(cache.check_and_insert(11, &nonce, &[1u8; 32]), ReplayCheck::Replayed));
+        assert!(matches!(cache.check_and_insert(11, &nonce, &[3u8; 32]), ReplayCheck::Accepted));
+
+        // forgotten after two intervals
+        assert!(matches!(cache.check_and_insert(12, &nonce, &[1u8; 32]), ReplayCheck::Accepted));
+        assert!(matches!(cache.check_and_insert(12, &nonce, &[3u8; 32]), ReplayCheck::Replayed));
+        assert!(matches!(cache.check_and_insert(14, &nonce, &[3u8; 32]), ReplayCheck::Accepted));
+
+        // late connection
+        assert!(matches!(cache.check_and_insert(13, &nonce, &[4u8; 32]), ReplayCheck::Accepted));
+        assert!(matches!(cache.check_and_insert(14, &nonce, &[4u8; 32]), ReplayCheck::Replayed));
+        assert!(matches!(cache.check_and_insert(12, &nonce, &[5u8; 32]), ReplayCheck::Rejected));
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use anyhow::{Result, anyhow};
+use hickory_resolver::{
+    config::{LookupIpStrategy, NameServerConfig, ResolveHosts, ResolverConfig as UpstreamConfig, ResolverOpts},
+    name_server::TokioConnectionProvider,
+    proto::xfer::Protocol,
+    TokioResolver,
+};
+use serde::Deserialize;
+
+const DNS_PORT: u16 = 53;
+const HTTPS_PORT: u16 = 443;
+const DOH_PATH: &str = "/dns-query";
+
+/// Destination resolver
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResolverConfig {
+    /// DNS servers: udp://1.1.1.1, tcp://1.1.1.1:53, https://cloudflare-dns.com/dns-query
+    /// system resolver configuration is used if empty
+    #[serde(default)]
+    pub upstreams: Vec<Upstream>,
+
+    /// address family of destinations, prefer-v4 by default
+    #[serde(default)]
+    pub ip_policy: IpPolicy,
+
+    /// max number of cached records, positive records are cached for their TTL
+    #[serde(default = "default_cache_size")]
+    pub cache_size: usize,
+
+    /// not found hosts are cached for negative_ttl (sec), 30 sec by default
+    #[serde(default = "default_negative_ttl")]
+    pub negative_ttl: u64,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            upstreams: Vec::new(),
+            ip_policy: IpPolicy::default(),
+            cache_size: default_cache_size(),
+            negative_ttl: default_negative_ttl(),
+        }
+    }
+}
+
+fn default_cache_size() -> usize {
+    4096
+}
+
+fn default_negative_ttl() -> u64 {
+    30
+}
+
+/// DNS server, port is optional
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Upstream {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    /// DNS over HTTPS, host name is resolved by the system resolver on start
+    Https { host: String, port: u16, path: String },
+}
+
+impl TryFrom<String> for Upstream {
+    type Error = anyhow::Error;
+
+    fn try_from(url: String) -> Result<Self> {
+        let (scheme, rest) = url.split_once("://")
+            .ok_or_else(|| anyhow!("upstream {url}: scheme (udp, tcp, https) is missing"))?;
+
+        match scheme {
+            "udp" => Ok(Self::Udp(parse_addr(rest, DNS_PORT)?)),
+            "tcp" => Ok(Self::Tcp(parse_addr(rest, DNS_PORT)?)),
+            "https" => {
+                let (authority, path) = match rest.find('/') {
+                    Some(pos) => rest.split_at(pos),
+                    None => (rest, DOH_PATH),
+                };
+
+                let (host, port) = match parse_addr(authority, HTTPS_PORT) {
+                    Ok(addr) => (addr.ip().to_string(), addr.port()),
+                    Err(_) => match authority.rsplit_once(':') {
+                        Some((host, port)) => (host.to_owned(), port.parse()?),
+                        None => (authority.to_owned(), HTTPS_PORT),
+                    },
+                };
+
+                if host.is_empty() {
+                    anyhow::bail!("upstream {url}: host is empty");
+                }
+
+                Ok(Self::Https { host, port, path: path.to_owned() })
+            },
+            _ => anyhow::bail!("upstream {url}: unknown scheme {scheme}, expected udp, tcp or https"),
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Udp(addr) => write!(f, "udp://{addr}"),
+            Self::Tcp(addr) => write!(f, "tcp://{addr}"),
+            Self::Https { host, port, path } => write!(f, "https://{host}:{port}{path}"),
+        }
+    }
+}
+
+/// ip or [ipv6] with optional port
+fn parse_addr(addr: &str, default_port: u16) -> Result<SocketAddr> {
+    if let Ok(addr) = addr.parse() {
+        return Ok(addr);
+    }
+
+    let ip = addr.trim_start_matches('[').trim_end_matches(']');
+    let ip: IpAddr = ip.parse().map_err(|_| anyhow!("wrong address {addr}"))?;
+    Ok(SocketAddr::new(ip, default_port))
+}
+
+/// Address family policy
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpPolicy {
+    #[default]
+    PreferV4,
+    PreferV6,
+    V4Only,
+    V6Only,
+}
+
+impl IpPolicy {
+    /// Filters out denied family and puts preferred family first, order of the same family is kept
+    pub fn apply(&self, ips: impl IntoIterator<Item = IpAddr>) -> Vec<IpAddr> {
+        let mut ips: Vec<IpAddr> = ips.into_iter()
+            .filter(|ip| match self {
+                Self::V4Only => ip.is_ipv4(),
+                Self::V6Only => ip.is_ipv6(),
+                _ => true,
+            })
+            .collect();
+
+        match self {
+            Self::PreferV4 => ips.sort_by_key(|ip| ip.is_ipv6()),
+            Self::PreferV6 => ips.sort_by_key(|ip| ip.is_ipv4()),
+            _ => {},
+        }
+
+        ips
+    }
+
+    fn lookup_strategy(&self) -> LookupIpStrategy {
+        match self {
+            Self::V4Only => LookupIpStrategy::Ipv4Only,
+            Self::V6Only => LookupIpStrategy::Ipv6Only,
+            // both families are needed to fallback to the other one
+            _ => LookupIpStrategy::Ipv4AndIpv6,
+        }
+    }
+}
+
+/// Caching resolver shared between connections
+pub struct Resolver {
+    inner: TokioResolver,
+    policy: IpPolicy,
+}
+
+impl Resolver {
+    pub async fn new(cfg: &ResolverConfig) -> Result<Self> {
+        let mut builder = if cfg.upstreams.is_empty() {
+            TokioResolver::builder_tokio()?
+        } else {
+            let mut upstreams = UpstreamConfig::new();
+            for upstream in &cfg.upstreams {
+                for name_server in name_servers(upstream).await? {
+                    upstreams.add_name_server(name_server);
+                }
+            }
+
+            // hosts file is a part of system configuration too
+            let mut builder = TokioResolver::builder_with_config(upstreams, TokioConnectionProvider::default());
+            builder.options_mut().use_hosts_file = ResolveHosts::Never;
+            builder
+        };
+
+        let opts: &mut ResolverOpts = builder.options_mut();
+        opts.ip_strategy = cfg.ip_policy.lookup_strategy();
+        opts.cache_size = cfg.cache_size;
+        opts.negative_min_ttl = Some(Duration::from_secs(cfg.negative_ttl));
+        opts.negative_max_ttl = Some(Duration::from_secs(cfg.negative_ttl));
+
+        Ok(Self {
+            inner: builder.build(),
+            policy: cfg.ip_policy,
+        })
+    }
+
+    /// Resolves host:port, addresses are ordered by the ip policy
+    pub async fn lookup(&self, host: &str) -> Result<Vec<SocketAddr>> {
+        let (name, port) = match host.parse::<SocketAddr>() {
+            Ok(addr) => return self.with_policy(host, [addr.ip()], addr.port()),
+            Err(_) => host.rsplit_once(':')
+                .and_then(|(name, port)| Some((name, port.parse::<u16>().ok()?)))
+                .ok_or_else(|| anyhow!("wrong host {host}"))?,
+        };
+
+        let ips = self.inner.lookup_ip(name)
+            .await
+            .map_err(|err| anyhow!("host {host} notfound: {err}"))?;
+
+        self.with_policy(host, ips.iter(), port)
+    }
+
+    fn with_policy(&self, host: &str, ips: impl IntoIterator<Item = IpAddr>, port: u16) -> Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = self.policy.apply(ips)
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect();
+
+        if addrs.is_empty() {
+            anyhow::bail!("host {host} notfound ({:?})", self.policy);
+        }
+
+        Ok(addrs)
+    }
+}
+
+async fn name_servers(upstream: &Upstream) -> Result<Vec<NameServerConfig>> {
+    match upstream {
+        Upstream::Udp(addr) => Ok(vec![NameServerConfig::new(*addr, Protocol::Udp)]),
+        Upstream::Tcp(addr) => Ok(vec![NameServerConfig::new(*addr, Protocol::Tcp)]),
+        #[cfg(any(feature = "rustls_ring", feature = "aws_lc_rs"))]
+        Upstream::Https { host, port, path } => {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), *port))
+                .await
+                .map_err(|err| anyhow!("upstream {upstream}: {err}"))?
+                .collect();
+
+            Ok(addrs.into_iter()
+                .map(|addr| {
+                    let mut name_server = NameServerConfig::new(addr, Protocol::Https);
+                    name_server.tls_dns_name = Some(host.clone());
+                    name_server.http_endpoint = Some(path.clone());
+                    name_server
+                })
+                .collect())
+        },
+        #[cfg(not(any(feature = "rustls_ring", feature = "aws_lc_rs")))]
+        Upstream::Https { .. } => anyhow::bail!("upstream {upstream}: DNS over HTTPS requires TLS support"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IpPolicy, Resolver, ResolverConfig, Upstream};
+    use std::net::IpAddr;
+
+    #[test]
+    fn upstreams() {
+        let parse = |url: &str| Upstream::try_from(url.to_owned());
+
+        assert_eq!(parse("udp://1.1.1.1").unwrap(), Upstream::Udp("1.1.1.1:53".parse().unwrap()));
+        assert_eq!(parse("tcp://[2606:4700::1111]:5353").unwrap(), Upstream::Tcp("[2606:4700::1111]:5353".parse().unwrap()));
+        assert_eq!(parse("udp://[::1]").unwrap(), Upstream::Udp("[::1]:53".parse().unwrap()));
+        assert_eq!(parse("https://cloudflare-dns.com").unwrap(), Upstream::Https {
+            host: "cloudflare-dns.com".to_owned(), port: 443, path: "/dns-query".to_owned()
+        });
+        assert_eq!(parse("https://1.1.1.1:8443/resolve").unwrap(), Upstream::Https {
+            host: "1.1.1.1".to_owned(), port: 8443, path: "/resolve".to_owned()
+        });
+
+        assert!(parse("1.1.1.1").is_err());
+        assert!(parse("udp://dns.google").is_err());
+        assert!(parse("tls://1.1.1.1").is_err());
+        assert!(parse("https:///dns-query").is_err());
+    }
+
+    #[test]
+    fn ip_policy() {
+        let ips: Vec<IpAddr> = ["2001:db8::1", "1.2.3.4", "2001:db8::2", "5.6.7.8"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        let ip = |i: usize| ips[i];
+
+        assert_eq!(IpPolicy::PreferV4.apply(ips.clone()), [ip(1), ip(3), ip(0), ip(2)]);
+        assert_eq!(IpPolicy::PreferV6.apply(ips.clone()), [ip(0), ip(2), ip(1), ip(3)]);
+        assert_eq!(IpPolicy::V4Only.apply(ips.clone()), [ip(1), ip(3)]);
+        assert_eq!(IpPolicy::V6Only.apply(ips.clone()), [ip(0), ip(2)]);
+    }
+
+    #[tokio::test]
+    async fn ip_hosts() {
+        // ip addresses are not sent to upstream
+        let cfg = ResolverConfig {
+            upstreams: vec![Upstream::try_from("udp://127.0.0.1:1".to_owned()).unwrap()],
+            ip_policy: IpPolicy::V4Only,
+            ..Default::default()
+        };
+        let resolver = Resolver::new(&cfg).await.unwrap();
+
+        assert_eq!(resolver.lookup("1.2.3.4:80").await.unwrap(), ["1.2.3.4:80".parse().unwrap()]);
+        assert!(resolver.lookup("[2001:db8::1]:443").await.is_err());
+        assert!(resolver.lookup("example.com").await.is_err());
+    }
+}
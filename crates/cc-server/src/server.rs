@@ -3,59 +3,144 @@ use std::{
     str,
     ops::Range,
     net::{SocketAddr, IpAddr, Ipv4Addr},
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{lookup_host, TcpListener, TcpStream, TcpSocket},
-    time::timeout
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, TcpSocket},
+    select,
+    sync::watch,
+    task::JoinSet,
+    time::{self, interval_at, timeout, timeout_at}
 };
+use futures::FutureExt;
 use chrono::Utc;
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use bytes::{BufMut, BytesMut};
 use rand::prelude::*;
 use rand_chacha::ChaCha20Rng;
-use crate::config::AppConfig;
+use crate::{
+    admin,
+    bans::Bans,
+    config::AppConfig,
+    limits::{ConnectionGuard, Limits},
+    metrics::{Handshake, Metrics},
+    outbound::OutboundPool,
+    proxy_protocol,
+    reload::{ConfigHandle, Running},
+    replay::{ReplayCache, ReplayCheck},
+    stats::{CountingStream, Stats},
+    resolver::Resolver,
+    tls,
+    traffic::{ThrottledStream, Traffic, SAVE_INTERVAL},
+    udp,
+    unix::{self, UnixListener, UnixStream},
+    users::{User, UserKey, Users},
+};
 use crypto::{
-    cipher::{Cipher, CipherType}, config::ProtocolConfig, kdf::Kdf, stream::EncryptedStream,
-    GET_PROTOCOL_MAX_CONNECT_DELAY, MIN_HOST_LEN
+    cipher::{Cipher, CipherType}, config::ProtocolConfig, datagram::CMD_UDP_ASSOCIATE,
+    kdf::{Kdf, TempKey, TEMP_KEY_TOKEN_LEN}, key_exchange::{server_key_exchange, KeyExchange}, stream::EncryptedStream,
+    version::{Capabilities, PeerVersion}, GET_PROTOCOL_MAX_CONNECT_DELAY, MIN_HOST_LEN
 };
+use happy_eyeballs::Timing;
+use websocket::{Role, WsStream};
 
 pub const LOCAL_HOST: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 pub const MAX_PACKET_SIZE: usize = 0xFFFF; // max TCP packet size
+// CDN can add a lot of headers
+const MAX_UPGRADE_REQUEST: usize = 4096;
+
+/// State shared between connections, replay cache, limits, stats and metrics are kept on config reload
+pub struct ServerState {
+    pub users: Users,
+    pub replay_cache: Arc<ReplayCache>,
+    pub limits: Arc<Limits>,
+    pub resolver: Resolver,
+    pub outbound: OutboundPool,
+    pub stats: Arc<Stats>,
+    pub metrics: Arc<Metrics>,
+    pub bans: Arc<Bans>,
+    pub traffic: Arc<Traffic>,
+}
 
-async fn start_tunnel(
-    stream: &mut TcpStream,
+/// http_upgrade - connection is from https proxy (nginx or built-in TLS)
+async fn start_tunnel<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
     socket_addr: SocketAddr,
     connect_time: i64,
     cfg: &AppConfig,
-    url_path: &str,
-    upgrade_support: bool,
+    state: &ServerState,
+    http_upgrade: bool,
 ) -> Result<()> {
-    let unauth_cooldown = cfg.unauth_cooldown.clone();
-    if upgrade_support && socket_addr.ip() == LOCAL_HOST {
-        if let Err(err) = process_http_upgrade(stream, url_path).await {
-            terminate_slowly(stream, unauth_cooldown).await;
-            return Err(err);
-        }
+    let users = &state.users;
+
+    // everything read before authentication, forwarded to fallback if authentication failed
+    let mut received = BytesMut::new();
+
+    // no key derivation for banned addresses
+    if cfg.ban.is_some() && state.bans.is_banned(socket_addr.ip()) {
+        state.metrics.handshake(Handshake::Banned);
+        tracing::debug!("banned {socket_addr} rejected");
+        refuse(stream, &received, cfg).await;
+        return Ok(());
+    }
+
+    if !http_upgrade {
+        let candidates: Vec<&Arc<User>> = users.iter().collect();
+        return process_tunnel(stream, socket_addr, connect_time, cfg, state, &candidates, received).await;
+    }
+
+    // url path is derived from the user key, so only this user can be used after upgrade
+    let upgrade = timeout(cfg.limits.handshake_timeout(), process_http_upgrade(stream, users, &mut received)).await;
+    let Ok(upgrade) = upgrade else {
+        reject(stream, &received, socket_addr, cfg, state, Handshake::Timeout).await;
+        anyhow::bail!("https upgrade request timeout from {socket_addr}");
+    };
+
+    match upgrade {
+        Ok((user, true)) => {
+            let mut stream = WsStream::from_stream(stream, Role::Server);
+            process_tunnel(&mut stream, socket_addr, connect_time, cfg, state, &[user], received).await
+        },
+        // legacy client without WebSocket framing
+        Ok((user, false)) => process_tunnel(stream, socket_addr, connect_time, cfg, state, &[user], received).await,
+        Err(err) => {
+            reject(stream, &received, socket_addr, cfg, state, Handshake::BadUpgrade).await;
+            Err(err)
+        },
     }
+}
 
+/// received - data already read from the stream
+async fn process_tunnel<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    socket_addr: SocketAddr,
+    connect_time: i64,
+    cfg: &AppConfig,
+    state: &ServerState,
+    candidates: &[&Arc<User>],
+    mut received: BytesMut,
+) -> Result<()> {
     let ProtocolConfig {
-        key, kdf, cipher: cipher_type, ..
+        kdf, cipher: cipher_type, key_exchange, ..
     } = &cfg.protocol;
 
     let key_size = cipher_type.key_size();
     let tag_size = cipher_type.tag_size();
     let nonce_size = cipher_type.nonce_size();
+    let client_share_size = key_exchange.client_share_size();
 
     let main_header_len = nonce_size
         + key_size              // salt
         + mem::size_of::<u16>() // padding
         + mem::size_of::<u8>()  // host len
+        + PeerVersion::SIZE
         + tag_size;
 
     let max_header_len = main_header_len
         + u8::MAX as usize // max host len (saved as u8)
+        + client_share_size
         + tag_size
         + cfg.protocol.header_padding.end as usize;
 
@@ -68,100 +153,202 @@ async fn start_tunnel(
     let mut data = BytesMut::with_capacity(max_header_len);
     data.resize(min_header_len, 0);
 
-    let readed = stream.read(data.as_mut()).await?;
+    let Ok(readed) = timeout(cfg.limits.handshake_timeout(), stream.read(data.as_mut())).await else {
+        reject(stream, &received, socket_addr, cfg, state, Handshake::Timeout).await;
+        anyhow::bail!("header timeout from {socket_addr}");
+    };
+    let readed = readed?;
+    received.extend_from_slice(&data[..readed]);
+
+    // key derivation is expensive, the number of concurrent ones is limited
+    let Some(handshake) = state.limits.handshake(&cfg.limits) else {
+        reject_over_limit(stream, cfg, state).await;
+        anyhow::bail!("handshake limit reached, {socket_addr} rejected");
+    };
+
     if readed < min_header_len {
-        if try_special_request(data, readed, connect_time, stream, cfg).await.is_ok() {
+        if try_special_request(data, readed, connect_time, stream, candidates, cfg).await.is_ok() {
+            state.metrics.handshake(Handshake::SpecialRequest);
             return Ok(());
         }
 
         // header should be written in one call
-        terminate_slowly(stream, unauth_cooldown).await;
+        drop(handshake);
+        reject(stream, &received, socket_addr, cfg, state, Handshake::BadSize).await;
         anyhow::bail!("wrong header packet size");
     }
 
     let mut header = data.split_to(main_header_len);
     
     let nonce = header.split_to(nonce_size);
-    let mut header_key = BytesMut::zeroed(cipher_type.key_size());
     let timestamp_for_key = connect_time / (cfg.protocol.max_connect_delay as i64);
 
-    kdf.derive_key_from_timestamp(key.as_bytes(), timestamp_for_key, &mut header_key)?;
-    let mut header_cipher = Cipher::new_with_nonce(*cipher_type, &header_key, nonce.as_ref());
+    let Some((user, user_key, header_key)) = decrypt_header(
+        candidates, *kdf, *cipher_type, timestamp_for_key, &nonce, &mut header
+    )? else {
+        // restore splited data
+        let mut restored_data = nonce;
+        restored_data.extend_from_slice(header.as_ref());
+        restored_data.extend_from_slice(data.as_ref());
+        if try_special_request(restored_data, readed, connect_time, stream, candidates, cfg).await.is_ok() {
+            state.metrics.handshake(Handshake::SpecialRequest);
+            return Ok(());
+        }
 
-    let header_copy = header.clone();
-    if !header_cipher.decrypt(&mut header) {
-        // try one more time with timestamp_for_key - 1
-        // it's possible that client sent data in a prev interval
-        kdf.derive_key_from_timestamp(key.as_bytes(), timestamp_for_key - 1, &mut header_key)?;
-        header_cipher = Cipher::new_with_nonce(*cipher_type, &header_key, nonce.as_ref());
-
-        // restore header
-        header = header_copy.clone();
-        if !header_cipher.decrypt(&mut header) {
-            // restore splited data
-            let mut restored_data = nonce;
-            restored_data.extend_from_slice(header_copy.as_ref());
-            restored_data.extend_from_slice(data.as_ref());
-            if try_special_request(restored_data, readed, connect_time, stream, cfg).await.is_ok() {
-                return Ok(());
-            }
+        drop(handshake);
+        reject(stream, &received, socket_addr, cfg, state, Handshake::DecryptFailed).await;
+        anyhow::bail!("decrypt header failed");
+    };
+    drop(handshake);
 
-            terminate_slowly(stream, unauth_cooldown).await;
-            anyhow::bail!("decrypt header failed");
-        }
+    // clients that are not migrated after key rotation
+    if let Some(previous) = user_key.previous {
+        let expires = user_key.expires.map(|expires| expires.to_rfc3339()).unwrap_or_else(|| "never".to_owned());
+        tracing::info!("{} from {socket_addr} uses previous key #{previous}, expires: {expires}", user.name);
     }
 
+    let mut header_cipher = Cipher::new_with_nonce(*cipher_type, &header_key, nonce.as_ref());
+
     let salt = header.split_to(key_size);
 
+    // the same header can't be used twice
+    match state.replay_cache.check_and_insert(timestamp_for_key, &nonce, &salt) {
+        ReplayCheck::Accepted => {},
+        ReplayCheck::Replayed => {
+            reject(stream, &received, socket_addr, cfg, state, Handshake::Replayed).await;
+            anyhow::bail!("replayed header");
+        },
+        ReplayCheck::Rejected => {
+            reject(stream, &received, socket_addr, cfg, state, Handshake::ReplayRejected).await;
+            anyhow::bail!("replay cache rejected header");
+        },
+    }
+
     let padding_bytes = header.split_to(mem::size_of::<u16>());
     let padding = u16::from_be_bytes(padding_bytes.as_ref().try_into().unwrap());
     
     let host_len_bytes = header.split_to(mem::size_of::<u8>());
     let host_len = u8::from_be_bytes(host_len_bytes.as_ref().try_into().unwrap()) as usize + MIN_HOST_LEN;
 
+    let client_version = PeerVersion::get(&mut header);
+    if let Err(err) = client_version.check("client") {
+        reject(stream, &received, socket_addr, cfg, state, Handshake::UnsupportedVersion).await;
+        anyhow::bail!("{} from {socket_addr}: {err}", user.name);
+    }
+
     // read the rest
     let rest_header_size = host_len
+        + client_share_size
         + cipher_type.tag_size()
         + padding as usize;
 
     let readed = data.len();
     data.resize(rest_header_size, 0);
 
-    let rest_readed = stream.try_read(&mut data[readed..])?;
+    let mut rest_readed = try_read(stream, &mut data[readed..])?;
+    // the key share may not fit in one packet, the main header is authenticated already
+    if key_exchange.enabled() && readed + rest_readed < rest_header_size {
+        let rest = &mut data[readed + rest_readed..];
+        if let Ok(Ok(size)) = timeout(cfg.limits.handshake_timeout(), stream.read_exact(rest)).await {
+            rest_readed += size;
+        }
+    }
+    received.extend_from_slice(&data[readed..readed + rest_readed]);
     if readed + rest_readed < rest_header_size {
         // header should be written in one call
-        terminate_slowly(stream, unauth_cooldown).await;
+        reject(stream, &received, socket_addr, cfg, state, Handshake::BadSize).await;
         anyhow::bail!("wrong header packet size");
     }
 
-    // decrypt host
-    let mut host_data = data.split_to(host_len + tag_size);
+    // decrypt host and the client key share
+    let mut host_data = data.split_to(host_len + client_share_size + tag_size);
 
     header_cipher.inc_nonce(padding);
     if !header_cipher.decrypt(&mut host_data) {
-        terminate_slowly(stream, unauth_cooldown).await;
+        reject(stream, &received, socket_addr, cfg, state, Handshake::DecryptFailed).await;
         anyhow::bail!("decrypt host failed");
     }
 
-    //
-    let host = match str::from_utf8(&host_data[..host_len]) {
+    // temporary key token goes before the host
+    let (host, temp_key) = if user_key.temp {
+        let Some((token, host)) = host_data[..host_len].split_at_checked(TEMP_KEY_TOKEN_LEN)
+            .filter(|(_, host)| host.len() >= MIN_HOST_LEN)
+        else {
+            reject(stream, &received, socket_addr, cfg, state, Handshake::BadSize).await;
+            anyhow::bail!("temp key token not found");
+        };
+
+        let temp_key = match TempKey::open(&cfg.protocol.key, token) {
+            Ok(temp_key) => temp_key,
+            Err(err) => {
+                reject(stream, &received, socket_addr, cfg, state, Handshake::DecryptFailed).await;
+                return Err(err);
+            }
+        };
+
+        let info = &temp_key.info;
+        if info.is_expired(Utc::now().timestamp()) || !info.matches(&cfg.protocol) {
+            reject(stream, &received, socket_addr, cfg, state, Handshake::Expired).await;
+            anyhow::bail!("temp key {:?} from {socket_addr} is expired or issued for other protocol parameters", info.identity);
+        }
+
+        let expires = chrono::DateTime::from_timestamp(info.expires, 0).unwrap_or_default();
+        tracing::info!("{} from {socket_addr} uses temp key {:?}, expires: {}", user.name, info.identity, expires.to_rfc3339());
+
+        (host, Some(temp_key))
+    } else {
+        (&host_data[..host_len], None)
+    };
+
+    let host = match str::from_utf8(host) {
         Ok(host) => host,
         Err(err) => {
-            terminate_slowly(stream, unauth_cooldown).await;
+            reject(stream, &received, socket_addr, cfg, state, Handshake::DecryptFailed).await;
             anyhow::bail!(err.to_owned());
         }
     };
+    state.metrics.handshake(Handshake::Success);
+
+    // rate limits and quota of the user
+    let traffic = match state.traffic.start(&user.name, cfg) {
+        Ok(traffic) => traffic,
+        Err(err) => anyhow::bail!("DENY {} from {socket_addr}: {err}", user.name),
+    };
+
+    // destinations are sent with every datagram in UDP associate mode
+    let udp_associate = host.as_bytes()[0] == CMD_UDP_ASSOCIATE;
+    let addrs = if udp_associate {
+        if !cfg.udp.enabled {
+            anyhow::bail!("DENY {} from {socket_addr}: UDP is disabled", user.name);
+        }
+        if !client_version.capabilities.contains(Capabilities::UDP_ASSOCIATE) {
+            anyhow::bail!("{} from {socket_addr}: client doesn't support UDP associate", user.name);
+        }
+        None
+    } else {
+        Some(resolve_destination(host, cfg, state, user, socket_addr).await?)
+    };
+
+    let data_pass = temp_key.as_ref().map_or(&user_key.key, |temp_key| &temp_key.key);
+
+    // ephemeral key exchange, the server share goes before the data
+    let secret = if key_exchange.enabled() {
+        let client_share = &host_data[host_len..host_len + client_share_size];
+        let (share, secret) = server_key_exchange(*key_exchange, client_share)?;
 
-    // prefer ipv4
-    let addr = lookup_host(host)
-        .await?
-        .reduce(|acc, val| if acc.is_ipv6() && val.is_ipv4() { val } else { acc })
-        .ok_or_else(|| anyhow!("host {host} notfound"))?;
+        let mut response = BytesMut::from(share.as_slice());
+        Cipher::new_key_exchange(*cipher_type, *kdf, data_pass, &salt)?.encrypt(&mut response, 0);
+        stream.write_all(&response).await?;
+        stream.flush().await?;
+        secret
+    } else {
+        Vec::new()
+    };
 
     let (client_cipher, server_cipher) =
-        Cipher::new_client_server(*cipher_type, *kdf, key, &salt)?;
+        Cipher::new_client_server_with_secret(*cipher_type, *kdf, data_pass, &salt, &secret)?;
 
-    let mut client = EncryptedStream::from_stream(
+    let client = EncryptedStream::from_stream(
         stream,
         client_cipher,
         server_cipher,
@@ -169,33 +356,165 @@ async fn start_tunnel(
         cfg.protocol.encryption_limit,
         ChaCha20Rng::from_entropy()
     );
+    let client = ThrottledStream::new(client, traffic);
+
+    let Some(addrs) = addrs else {
+        tracing::info!("UDP {} from {socket_addr}", user.name);
+
+        let guard = state.stats.register(&user.name, socket_addr, None);
+        let mut client = CountingStream::new(client, &guard.tunnel);
+
+        user.tunnel_started();
+        let started = Instant::now();
+        let result = select! {
+            result = udp::relay(&mut client, socket_addr, cfg, state, user) => result,
+            _ = guard.tunnel.closed() => {
+                tracing::info!("UDP {} from {socket_addr} closed by admin", user.name);
+                Ok(())
+            },
+        };
+        let (rx, tx) = guard.tunnel.bytes();
+        user.tunnel_finished(rx, tx);
+            state.metrics.tunnel_duration.observe(started.elapsed());
+
+        tracing::debug!("CLOSE UDP {} from {socket_addr}, rx: {rx}, tx: {tx}", user.name);
+
+        result?;
+        return Ok(());
+    };
 
-    let mut out_stream = match cfg.out_address {
-        Some(out_addr) if out_addr.is_ipv4() == addr.is_ipv4() => {
-            let socket = match out_addr {
-                IpAddr::V4(_) => TcpSocket::new_v4()?,
-                IpAddr::V6(_) => TcpSocket::new_v6()?,
-            };
-
-            socket.bind(SocketAddr::new(out_addr, 0))?;
-            socket.connect(addr).await?
+    // every attempt has connect_timeout, fallback attempts are not cut off by the first one
+    let connect_started = Instant::now();
+    let timing = Timing { attempt_timeout: cfg.tcp.connect_timeout(), ..Default::default() };
+    let connected = happy_eyeballs::connect_with(addrs, timing, |addr| async move {
+        let stream = match state.outbound.select(&addr, &user.name, host) {
+            Some(out_addr) => {
+                let socket = match out_addr {
+                    IpAddr::V4(_) => TcpSocket::new_v4()?,
+                    IpAddr::V6(_) => TcpSocket::new_v6()?,
+                };
+
+                socket.bind(SocketAddr::new(out_addr, 0))?;
+                socket.connect(addr).await
+            },
+            _ => TcpStream::connect(addr).await
+        }?;
+
+        cfg.tcp.apply(&stream)?;
+        Ok(stream)
+    }).await;
+
+    let (mut out_stream, addr) = match connected {
+        Ok(connected) => {
+            state.metrics.connect_latency.observe(connect_started.elapsed());
+            connected
+        },
+        Err(err) => {
+            state.metrics.connect_failed();
+            return Err(err.into());
         },
-        _ => TcpStream::connect(addr).await?
     };
 
-    tracing::info!("CONNECT from {socket_addr} to {addr}");
+    tracing::info!("CONNECT {} from {socket_addr} to {addr}", user.name);
+
+    let guard = state.stats.register(&user.name, socket_addr, Some(host));
+    let mut client = CountingStream::new(client, &guard.tunnel);
+
+    user.tunnel_started();
+    let started = Instant::now();
+    let result = select! {
+        result = tcp_options::copy_bidirectional(&mut client, &mut out_stream, cfg.tcp.idle_timeout()) => result.map(|_| ()),
+        _ = guard.tunnel.closed() => {
+            tracing::info!("CONNECT {} from {socket_addr} to {addr} closed by admin", user.name);
+            Ok(())
+        },
+    };
+    let (rx, tx) = guard.tunnel.bytes();
+    user.tunnel_finished(rx, tx);
+    state.metrics.tunnel_duration.observe(started.elapsed());
 
-    tokio::io::copy_bidirectional(&mut client, &mut out_stream).await?;
+    tracing::debug!("CLOSE {} from {socket_addr} to {addr}, rx: {rx}, tx: {tx}", user.name);
 
+    result?;
     Ok(())
 }
 
-pub async fn try_special_request(
+/// Resolves host:port and checks the destination policy, returns allowed addresses in the ip policy order.
+/// Resolved addresses are checked, so hostname can't be used to bypass the policy
+pub(crate) async fn resolve_destination(
+    host: &str,
+    cfg: &AppConfig,
+    state: &ServerState,
+    user: &User,
+    socket_addr: SocketAddr,
+) -> Result<Vec<SocketAddr>> {
+    let policy = &cfg.destination;
+    if let Err(reason) = policy.check_host(host) {
+        anyhow::bail!("DENY {} from {socket_addr} to {host}: {reason}", user.name);
+    }
+
+    let mut deny_reason = None;
+    let resolve_started = Instant::now();
+    let resolved = state.resolver.lookup(host).await?;
+    state.metrics.dns_latency.observe(resolve_started.elapsed());
+
+    let addrs: Vec<SocketAddr> = resolved
+        .into_iter()
+        .filter(|addr| match policy.check_addr(addr) {
+            Ok(()) => true,
+            Err(reason) => {
+                deny_reason.get_or_insert(reason);
+                false
+            }
+        })
+        .collect();
+
+    match (addrs.is_empty(), deny_reason) {
+        (false, _) => Ok(addrs),
+        (true, Some(reason)) => anyhow::bail!("DENY {} from {socket_addr} to {host}: {reason}", user.name),
+        (true, None) => anyhow::bail!("host {host} notfound"),
+    }
+}
+
+/// Tries every valid user key (previous keys included) for the current and the previous timestamp interval.
+/// Returns the user, the key and the header key, header is left untouched if no key is found.
+fn decrypt_header<'a>(
+    users: &[&'a Arc<User>],
+    kdf: Kdf,
+    cipher_type: CipherType,
+    timestamp_for_key: i64,
+    nonce: &[u8],
+    header: &mut BytesMut,
+) -> Result<Option<(&'a Arc<User>, &'a UserKey, BytesMut)>> {
+    let mut header_key = BytesMut::zeroed(cipher_type.key_size());
+    let header_copy = header.clone();
+
+    for user in users {
+        for user_key in user.valid_keys() {
+            // it's possible that client sent data in a prev interval
+            for timestamp in [timestamp_for_key, timestamp_for_key - 1] {
+                user_key.header_key(kdf, timestamp, &mut header_key)?;
+                let mut header_cipher = Cipher::new_with_nonce(cipher_type, &header_key, nonce);
+                if header_cipher.decrypt(header) {
+                    return Ok(Some((user, user_key, header_key)));
+                }
+
+                // restore header
+                header.clone_from(&header_copy);
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+pub async fn try_special_request<S: AsyncRead + AsyncWrite + Unpin>(
     mut data: BytesMut,
     readed: usize,
     connect_time: i64,
-    stream: &mut TcpStream,
-    cfg: &AppConfig
+    stream: &mut S,
+    users: &[&Arc<User>],
+    cfg: &AppConfig,
 ) -> Result<()> {
     // protocol description in /doc/protocol.md
 
@@ -203,7 +522,6 @@ pub async fn try_special_request(
     let kdf = Kdf::Argon2;
     let cipher_type = CipherType::Aes256Gcm;
     let timestamp_for_key = connect_time / (GET_PROTOCOL_MAX_CONNECT_DELAY as i64);
-    let key = &cfg.protocol.key;
 
     let key_size = cipher_type.key_size();
     let tag_size = cipher_type.tag_size();
@@ -222,31 +540,21 @@ pub async fn try_special_request(
     let mut header = data.split_to(header_len);
 
     let nonce = header.split_to(nonce_size);
-    let mut header_key = BytesMut::zeroed(cipher_type.key_size());
 
-    kdf.derive_key_from_timestamp(key.as_bytes(), timestamp_for_key, &mut header_key)?;
-    let mut header_cipher = Cipher::new_with_nonce(cipher_type, &header_key, nonce.as_ref());
+    let Some((_, user_key, header_key)) = decrypt_header(users, kdf, cipher_type, timestamp_for_key, &nonce, &mut header)? else {
+        anyhow::bail!("decrypt error");
+    };
 
-    let header_copy = header.clone();
-    if !header_cipher.decrypt(&mut header) {
-        // try one more time with timestamp_for_key - 1
-        // it's possible that client sent data in a prev interval
-        kdf.derive_key_from_timestamp(key.as_bytes(), timestamp_for_key - 1, &mut header_key)?;
-        header_cipher = Cipher::new_with_nonce(cipher_type, &header_key, nonce.as_ref());
-
-        // restore header
-        header = header_copy.clone();
-        if !header_cipher.decrypt(&mut header) {
-            anyhow::bail!("decrypt error");
-        }
+    // the protocol is included in temporary keys
+    if user_key.temp {
+        anyhow::bail!("get protocol with temp key");
     }
 
     header.truncate(header.len() - tag_size);
-    header_cipher = Cipher::new_with_nonce(CipherType::ChaCha20Poly1305, &header_key, nonce.as_ref());
+    let mut header_cipher = Cipher::new_with_nonce(CipherType::ChaCha20Poly1305, &header_key, nonce.as_ref());
     if !header_cipher.decrypt(&mut header) {
         anyhow::bail!("decrypt error");
     }
-
     let salt = header.split_to(key_size);
 
     let padding_bytes = header.split_to(mem::size_of::<u16>());
@@ -254,7 +562,7 @@ pub async fn try_special_request(
 
     // read the rest
     data.resize(header_len + padding, 0);
-    let readed_rest = stream.try_read(&mut data[readed..])?;
+    let readed_rest = try_read(stream, &mut data[readed..])?;
     if readed + readed_rest != header_len + padding {
         anyhow::bail!("not enough readed (padding)");
     }
@@ -265,7 +573,7 @@ pub async fn try_special_request(
     let padding_end: u16 = rng.gen_range(range);
 
     let mut response_key = BytesMut::zeroed(key_size);
-    kdf.derive_protocol_response_key(cfg.protocol.key.as_bytes(), &salt, &mut response_key)?;
+    kdf.derive_protocol_response_key(user_key.key.as_bytes(), &salt, &mut response_key)?;
 
     let mut cipher_aes = Cipher::new_with_nonce(CipherType::Aes256Gcm, &response_key, &salt[0..nonce_size]);
     let mut cipher_cha = Cipher::new_with_nonce(CipherType::ChaCha20Poly1305, &response_key, &salt[key_size - nonce_size..key_size]);
@@ -276,6 +584,7 @@ pub async fn try_special_request(
     // header
     response.put_u16(padding_begin);
     response.put_u16(padding_end);
+    PeerVersion::current(capabilities(cfg)).put(&mut response);
 
     cipher_aes.encrypt(&mut response, 0);
     cipher_cha.encrypt(&mut response, 0);
@@ -300,6 +609,7 @@ pub async fn try_special_request(
     response.put_u16(cfg.protocol.data_padding.max);
     response.put_u8(cfg.protocol.data_padding.rate);
     response.put_u64(cfg.protocol.encryption_limit as u64);
+    response.put_u8(cfg.protocol.key_exchange.into());
 
     // padding end
     let padding_end_start = response.len();
@@ -318,45 +628,346 @@ pub async fn try_special_request(
     Ok(())
 }
 
+/// Optional features advertised in get protocol response
+fn capabilities(cfg: &AppConfig) -> Capabilities {
+    Capabilities::empty()
+        .with(Capabilities::UDP_ASSOCIATE, cfg.udp.enabled)
+        .with(Capabilities::TEMP_KEYS, cfg.temp_keys)
+        .with(Capabilities::ML_KEM, KeyExchange::X25519MlKem768.is_supported())
+}
+
+/// Stops the server: new connections are not accepted,
+/// active ones are drained up to drain_timeout and cancelled after that
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self { sender: Arc::new(watch::Sender::new(false)) }
+    }
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.sender.send_replace(true);
+    }
+
+    async fn requested(&self) {
+        let _ = self.sender.subscribe().wait_for(|requested| *requested).await;
+    }
+}
+
+/// Config can't be reloaded and the server can't be stopped, see `serve_with` for that
 pub async fn serve(cfg: AppConfig, url_path: String, upgrade_support: bool) -> Result<()> {
-    let listener = TcpListener::bind(&cfg.address).await?;
+    serve_with(ConfigHandle::new(cfg, url_path, None).await?, ShutdownHandle::default(), upgrade_support).await
+}
 
-    tracing::info!("server started: {:?}", cfg.address);
+/// Config is reloaded on SIGHUP or file change if the handle is created with the config path.
+/// Returns when the server is stopped with the shutdown handle and connections are drained.
+pub async fn serve_with(handle: Arc<ConfigHandle>, shutdown: ShutdownHandle, upgrade_support: bool) -> Result<()> {
+    let running = handle.current();
+    let mut listener = TcpListener::bind(&running.cfg.address).await?;
 
-    loop {
-        let (mut stream, socket_addr) = listener.accept().await?;
+    // admin API, config watch and quota saving, stopped with the server
+    let mut background = JoinSet::new();
 
-        // replay protection
-        let timestamp = Utc::now().timestamp_millis();
+    if let Some(admin_cfg) = &running.cfg.admin {
+        let admin_listener = admin::bind(admin_cfg).await?;
+        tracing::info!("admin API started: {}", admin_cfg.listen);
 
-        let cfg = cfg.clone();
-        let url_path = url_path.clone();
-        tokio::spawn(async move {
-            if let Err(err) = start_tunnel(&mut stream, socket_addr, timestamp, &cfg, &url_path, upgrade_support).await {
-                tracing::error!("{:?}", err);
+        let (admin_cfg, handle) = (admin_cfg.clone(), handle.clone());
+        background.spawn(async move {
+            if let Err(err) = admin::serve(admin_listener, admin_cfg, handle).await {
+                tracing::error!("admin API: {:?}", err);
             }
         });
     }
+
+    let watcher = handle.clone();
+    background.spawn(async move {
+        if let Err(err) = watcher.watch().await {
+            tracing::error!("config watch: {:?}", err);
+        }
+    });
+
+    // quota usage is saved with the current config, open tunnels are counted too
+    let saver = handle.clone();
+    background.spawn(async move {
+        let mut ticks = interval_at(time::Instant::now() + SAVE_INTERVAL, SAVE_INTERVAL);
+        loop {
+            ticks.tick().await;
+            let running = saver.current();
+            running.state.traffic.save_now(&running.cfg);
+        }
+    });
+
+    // nginx connects to the unix socket, it's not changed on reload
+    let unix_listener = match &running.cfg.unix_socket {
+        Some(unix_cfg) => {
+            let unix_listener = UnixListener::bind(unix_cfg)?;
+            tracing::info!("unix socket: {}", unix_listener.path().display());
+            Some(unix_listener)
+        },
+        None => None,
+    };
+
+    tracing::info!("server started: {:?}", running.cfg.address);
+    drop(running);
+
+    let mut connections = JoinSet::new();
+    loop {
+        select! {
+            accepted = listener.accept() => {
+                let (stream, socket_addr) = accepted?;
+                let (running, timestamp) = accepted_now(&handle);
+                connections.spawn(serve_connection(stream, socket_addr, timestamp, running, upgrade_support));
+            },
+            accepted = unix::accept(unix_listener.as_ref()) => {
+                let stream = accepted?;
+                let (running, timestamp) = accepted_now(&handle);
+                connections.spawn(serve_unix_connection(stream, timestamp, running));
+            },
+            new_listener = handle.rebound() => {
+                listener = new_listener;
+                tracing::info!("server address changed: {:?}", listener.local_addr()?);
+            },
+            // finished connections are removed from the set
+            Some(_) = connections.join_next() => {},
+            _ = shutdown.requested() => break,
+        }
+    }
+
+    drop(listener);
+    drop(unix_listener);
+
+    let drain_timeout = Duration::from_secs(handle.current().cfg.drain_timeout);
+    tracing::info!("shutting down, draining {} connections up to {drain_timeout:?}", connections.len());
+
+    let drained = timeout(drain_timeout, async {
+        while connections.join_next().await.is_some() {}
+    }).await;
+
+    if drained.is_err() {
+        tracing::warn!("{} connections cancelled after drain timeout", connections.len());
+        connections.shutdown().await;
+    }
+
+    background.shutdown().await;
+
+    // the handle can outlive the server, replaced configs are already dropped with their connections
+    let running = handle.current();
+    running.stop();
+
+    // quota usage since the last save
+    running.state.traffic.save_now(&running.cfg);
+
+    tracing::info!("server stopped");
+
+    Ok(())
+}
+
+/// New connections use the current config, tunnels in flight keep their own.
+/// The timestamp is used for replay protection
+fn accepted_now(handle: &ConfigHandle) -> (Arc<Running>, i64) {
+    let running = handle.current();
+    running.state.metrics.connection_accepted();
+
+    (running, Utc::now().timestamp_millis())
+}
+
+async fn serve_connection(mut stream: TcpStream, socket_addr: SocketAddr, timestamp: i64, running: Arc<Running>, upgrade_support: bool) {
+    let (cfg, state) = (&running.cfg, &running.state);
+
+    // https upgrade is accepted only from the reverse proxy
+    let trusted = cfg.is_trusted_proxy(socket_addr.ip());
+
+    let Some((socket_addr, _connection)) = client_connection(&mut stream, socket_addr, trusted, cfg, state).await else {
+        return;
+    };
+
+    if let Err(err) = cfg.tcp.apply(&stream) {
+        tracing::debug!("socket options for {socket_addr}: {:?}", err);
+    }
+
+    // waiting for the client hello is a part of the TLS handshake
+    let deadline = tokio::time::Instant::now() + cfg.limits.handshake_timeout();
+    let acceptor = match running.tls_acceptor.clone() {
+        Some(acceptor) => match timeout_at(deadline, tls::is_client_hello(&stream)).await {
+            Ok(client_hello) => client_hello.then_some(acceptor),
+            Err(_) => {
+                state.metrics.handshake(Handshake::Timeout);
+                tracing::debug!("TLS handshake timeout from {socket_addr}");
+                return;
+            },
+        },
+        None => None,
+    };
+
+    let result = match acceptor {
+        Some(acceptor) => {
+            match timeout_at(deadline, acceptor.accept(stream)).await {
+                Ok(Ok(mut stream)) => start_tunnel(&mut stream, socket_addr, timestamp, cfg, state, true).await,
+                Ok(Err(err)) => {
+                    tracing::debug!("TLS handshake from {socket_addr}: {:?}", err);
+                    return;
+                },
+                Err(_) => {
+                    state.metrics.handshake(Handshake::Timeout);
+                    tracing::debug!("TLS handshake timeout from {socket_addr}");
+                    return;
+                },
+            }
+        },
+        None => {
+            let http_upgrade = upgrade_support && trusted;
+            start_tunnel(&mut stream, socket_addr, timestamp, cfg, state, http_upgrade).await
+        },
+    };
+
+    if let Err(err) = result {
+        tracing::error!("{:?}", err);
+    }
+}
+
+/// Unix socket peer is the reverse proxy, so the connection is upgrade-capable
+/// and the client address is known only from PROXY protocol header.
+/// Without the header only max_connections is applied, all clients have the same address
+async fn serve_unix_connection(mut stream: UnixStream, timestamp: i64, running: Arc<Running>) {
+    let (cfg, state) = (&running.cfg, &running.state);
+
+    let socket_addr = SocketAddr::new(LOCAL_HOST, 0);
+    let Some((socket_addr, _connection)) = client_connection(&mut stream, socket_addr, true, cfg, state).await else {
+        return;
+    };
+
+    if let Err(err) = start_tunnel(&mut stream, socket_addr, timestamp, cfg, state, true).await {
+        tracing::error!("{:?}", err);
+    }
+}
+
+/// The client address from the trusted reverse proxy replaces the proxy one, then connection limits are checked.
+/// The per ip limit is not applied to the reverse proxy itself (no PROXY protocol header).
+/// None if the connection is rejected
+async fn client_connection<'a, S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    socket_addr: SocketAddr,
+    trusted: bool,
+    cfg: &AppConfig,
+    state: &'a ServerState,
+) -> Option<(SocketAddr, ConnectionGuard<'a>)> {
+    let (socket_addr, client_known) = match &cfg.proxy_protocol {
+        Some(_) if trusted => {
+            match timeout(cfg.limits.handshake_timeout(), proxy_protocol::read_header(stream)).await {
+                Ok(Ok(client_addr)) => (client_addr.unwrap_or(socket_addr), client_addr.is_some()),
+                Ok(Err(err)) => {
+                    tracing::debug!("PROXY protocol header from {socket_addr}: {:?}", err);
+                    return None;
+                },
+                Err(_) => {
+                    state.metrics.handshake(Handshake::Timeout);
+                    tracing::debug!("PROXY protocol header timeout from {socket_addr}");
+                    return None;
+                },
+            }
+        },
+        _ => (socket_addr, !trusted),
+    };
+
+    let limited_ip = client_known.then_some(socket_addr.ip());
+    let Some(connection) = state.limits.connection(limited_ip, &cfg.limits) else {
+        tracing::debug!("connection limit reached, {socket_addr} rejected");
+        reject_over_limit(stream, cfg, state).await;
+        return None;
+    };
+
+    Some((socket_addr, connection))
+}
+
+/// Unauthenticated connection is forwarded to the fallback upstream if set
+/// so prober sees an ordinary website, otherwise it's terminated slowly
+async fn reject<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    received: &[u8],
+    socket_addr: SocketAddr,
+    cfg: &AppConfig,
+    state: &ServerState,
+    reason: Handshake,
+) {
+    state.stats.auth_failed(socket_addr.ip());
+    state.metrics.handshake(reason);
+
+    // timeouts and a full replay cache are not the client's fault, outdated clients are not probing
+    let probing = !matches!(reason, Handshake::Timeout | Handshake::ReplayRejected | Handshake::UnsupportedVersion);
+    if let Some(ban) = &cfg.ban
+        && probing
+        && state.bans.failed(socket_addr.ip(), ban)
+    {
+        tracing::warn!("{} banned for {} sec", socket_addr.ip(), ban.ban_time);
+    }
+
+    refuse(stream, received, cfg).await;
+}
+
+/// Forwards to the fallback or terminates slowly
+async fn refuse<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, received: &[u8], cfg: &AppConfig) {
+    match &cfg.fallback {
+        Some(fallback) => {
+            if let Err(err) = forward_to_fallback(stream, received, fallback).await {
+                tracing::debug!("fallback {fallback}: {:?}", err);
+            }
+        },
+        None => terminate_slowly(stream, cfg.unauth_cooldown.clone()).await,
+    }
+}
+
+/// Connections over a limit look like unauthenticated ones, but they are never forwarded to the fallback
+async fn reject_over_limit<S: AsyncRead + Unpin>(stream: &mut S, cfg: &AppConfig, state: &ServerState) {
+    state.metrics.handshake(Handshake::OverLimit);
+    terminate_slowly(stream, cfg.unauth_cooldown.clone()).await;
+}
+
+async fn forward_to_fallback<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, received: &[u8], fallback: &str) -> Result<()> {
+    let mut upstream = TcpStream::connect(fallback).await?;
+
+    // replay what we already read
+    upstream.write_all(received).await?;
+    tokio::io::copy_bidirectional(stream, &mut upstream).await?;
+
+    Ok(())
+}
+
+/// Reads data that is already available without waiting (like TcpStream::try_read)
+fn try_read<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut [u8]) -> io::Result<usize> {
+    stream.read(buf).now_or_never().unwrap_or(Ok(0))
 }
 
-async fn terminate_slowly(stream: &mut TcpStream, cooldown: Range<u16>) {
+async fn terminate_slowly<S: AsyncRead + Unpin>(stream: &mut S, cooldown: Range<u16>) {
     // avoid testing for required header size
     let mut rng = ChaCha20Rng::from_entropy();
 
     let max_read : u16 = rng.r#gen();
     let max_time_ms = rng.gen_range(cooldown) as u64;
 
-    let mut data = BytesMut::with_capacity(max_read as usize);
-    timeout(Duration::from_millis(max_time_ms), stream.read_exact(&mut data)).await.ok();
+    // read and drop, memory is not allocated for every junk connection
+    let mut limited = stream.take(max_read as u64);
+    timeout(Duration::from_millis(max_time_ms), io::copy(&mut limited, &mut io::sink())).await.ok();
 }
 
-async fn process_http_upgrade(stream: &mut TcpStream, url_path: &str) -> Result<()> {
+/// Returns the user and true if WebSocket framing is used (Sec-WebSocket-Key is set)
+async fn process_http_upgrade<'a, S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    users: &'a Users,
+    received: &mut BytesMut,
+) -> Result<(&'a Arc<User>, bool)> {
     // should be from proxy check for Uprage header and skip it
-    let mut req_bytes = [0u8;u8::MAX as usize];
+    let mut req_bytes = [0u8; MAX_UPGRADE_REQUEST];
     let mut readed = 0;
     let mut lf_in_row = 0;
     while lf_in_row < 2 {
         let byte = stream.read_u8().await?;
+        received.put_u8(byte);
         if byte == b'\n' {
             lf_in_row += 1;
         } else if byte != b'\r' {
@@ -366,28 +977,38 @@ async fn process_http_upgrade(stream: &mut TcpStream, url_path: &str) -> Result<
         req_bytes[readed] = byte;
         readed += 1;
 
-        if readed >= u8::MAX as usize {
+        if readed >= MAX_UPGRADE_REQUEST {
             anyhow::bail!("response header with upgrade is too big");    
         }
     }
 
     let req = String::from_utf8_lossy(&req_bytes[..readed]);
-    if req.find(url_path).is_none() || !req.ends_with('\n') {
+    let user = match users.find_by_url_path(&req) {
+        Some(user) if req.ends_with('\n') => user,
         // wrong request
-        anyhow::bail!("unexpected header from localhost");
-    }
+        _ => anyhow::bail!("unexpected header from localhost"),
+    };
 
     // reply with upgrade
-    stream.write_all(
-        "\
+    let ws_key = websocket::find_header(&req, "Sec-WebSocket-Key");
+    let response = match ws_key {
+        Some(key) => format!("\
+            HTTP/1.1 101 Switching Protocols\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Accept: {}\r\n\
+            \r\n\
+        ", websocket::accept_key(key)),
+        None => "\
             HTTP/1.1 101 Switching Protocols\r\n\
             Upgrade: cconnect\r\n\
             Connection: Upgrade\r\n\
             \r\n\
-        "
-        .as_bytes(),
-    ).await?;
+        ".to_owned(),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
     stream.flush().await?;
 
-    Ok(())
-}
\ No newline at end of file
+    Ok((user, ws_key.is_some()))
+}
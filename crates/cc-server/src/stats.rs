@@ -0,0 +1,195 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}},
+    task::{Context, Poll},
+};
+use chrono::{DateTime, Utc};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    sync::Notify,
+};
+
+// failed authentications are counted for this number of addresses at most
+const MAX_FAILED_AUTH_ADDRESSES: usize = 1024;
+
+/// Active tunnel
+pub struct Tunnel {
+    pub id: u64,
+    pub user: String,
+    pub peer: SocketAddr,
+    /// host:port or None for UDP associate
+    pub destination: Option<String>,
+    pub started: DateTime<Utc>,
+    /// from the client
+    pub rx: AtomicU64,
+    /// to the client
+    pub tx: AtomicU64,
+    close: Notify,
+}
+
+impl Tunnel {
+    /// Resolves when the tunnel is closed by admin
+    pub async fn closed(&self) {
+        self.close.notified().await
+    }
+
+    pub fn bytes(&self) -> (u64, u64) {
+        (self.rx.load(Ordering::Relaxed), self.tx.load(Ordering::Relaxed))
+    }
+}
+
+/// Active tunnels and authentication failures
+#[derive(Default)]
+pub struct Stats {
+    next_id: AtomicU64,
+    tunnels: Mutex<HashMap<u64, Arc<Tunnel>>>,
+    failed_auth: AtomicU64,
+    failed_auth_by_ip: Mutex<HashMap<IpAddr, u64>>,
+}
+
+/// Removes the tunnel from active ones on drop
+pub struct TunnelGuard<'a> {
+    stats: &'a Stats,
+    pub tunnel: Arc<Tunnel>,
+}
+
+impl Drop for TunnelGuard<'_> {
+    fn drop(&mut self) {
+        self.stats.tunnels.lock().unwrap().remove(&self.tunnel.id);
+    }
+}
+
+impl Stats {
+    pub fn register(&self, user: &str, peer: SocketAddr, destination: Option<&str>) -> TunnelGuard<'_> {
+        let tunnel = Arc::new(Tunnel {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            user: user.to_owned(),
+            peer,
+            destination: destination.map(str::to_owned),
+            started: Utc::now(),
+            rx: AtomicU64::new(0),
+            tx: AtomicU64::new(0),
+            close: Notify::new(),
+        });
+
+        self.tunnels.lock().unwrap().insert(tunnel.id, tunnel.clone());
+        TunnelGuard { stats: self, tunnel }
+    }
+
+    /// Active tunnels ordered by id
+    pub fn tunnels(&self) -> Vec<Arc<Tunnel>> {
+        let mut tunnels: Vec<_> = self.tunnels.lock().unwrap().values().cloned().collect();
+        tunnels.sort_by_key(|tunnel| tunnel.id);
+        tunnels
+    }
+
+    /// Returns false if there is no such tunnel
+    pub fn close(&self, id: u64) -> bool {
+        match self.tunnels.lock().unwrap().get(&id) {
+            Some(tunnel) => {
+                // the permit is stored if the tunnel is not waiting yet
+                tunnel.close.notify_one();
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn auth_failed(&self, ip: IpAddr) {
+        self.failed_auth.fetch_add(1, Ordering::Relaxed);
+
+        let mut by_ip = self.failed_auth_by_ip.lock().unwrap();
+        if let Some(count) = by_ip.get_mut(&ip) {
+            *count += 1;
+        } else if by_ip.len() < MAX_FAILED_AUTH_ADDRESSES {
+            by_ip.insert(ip, 1);
+        }
+    }
+
+    /// Total number and the number per address
+    pub fn failed_auth(&self) -> (u64, HashMap<IpAddr, u64>) {
+        (self.failed_auth.load(Ordering::Relaxed), self.failed_auth_by_ip.lock().unwrap().clone())
+    }
+}
+
+/// Counts bytes of the tunnel while they are transferred
+pub struct CountingStream<'a, S> {
+    inner: S,
+    tunnel: &'a Tunnel,
+}
+
+impl<'a, S> CountingStream<'a, S> {
+    pub fn new(inner: S, tunnel: &'a Tunnel) -> Self {
+        Self { inner, tunnel }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<'_, S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.tunnel.rx.fetch_add((buf.filled().len() - filled) as u64, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<'_, S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(size)) = result {
+            self.tunnel.tx.fetch_add(size as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CountingStream, Stats};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn tunnels() {
+        let stats = Stats::default();
+        let peer = "192.0.2.1:1234".parse().unwrap();
+
+        let first = stats.register("alice", peer, Some("example.com:443"));
+        let second = stats.register("bob", peer, None);
+        assert_eq!(stats.tunnels().iter().map(|t| t.id).collect::<Vec<_>>(), [first.tunnel.id, second.tunnel.id]);
+
+        // bytes are counted on the fly
+        let (client, mut remote) = tokio::io::duplex(64);
+        let mut stream = CountingStream::new(client, &first.tunnel);
+        stream.write_all(b"hello").await.unwrap();
+        remote.write_all(b"hi").await.unwrap();
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(first.tunnel.bytes(), (2, 5));
+
+        // closed before waiting
+        assert!(stats.close(second.tunnel.id));
+        second.tunnel.closed().await;
+
+        drop(second);
+        assert_eq!(stats.tunnels().len(), 1);
+        assert!(!stats.close(100));
+
+        stats.auth_failed(peer.ip());
+        stats.auth_failed(peer.ip());
+        let (total, by_ip) = stats.failed_auth();
+        assert_eq!(total, 2);
+        assert_eq!(by_ip[&peer.ip()], 2);
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::BufReader,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use tokio::{net::TcpStream, time::sleep};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        self,
+        crypto::CryptoProvider,
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+    },
+};
+
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+const TLS_MAJOR_VERSION: u8 = 0x03;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+// peek returns the same bytes until more data arrives, so it's repeated with the delay
+const PEEK_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Built-in TLS, nginx is not needed in this case
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// certificate chain (PEM), e.g. fullchain.pem from certbot
+    pub cert: PathBuf,
+
+    /// private key (PEM), e.g. privkey.pem from certbot
+    pub key: PathBuf,
+
+    /// how often (sec) certificate files are checked for changes, 1 hour by default
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: u64,
+}
+
+fn default_reload_interval() -> u64 {
+    3600
+}
+
+/// Certificate that can be replaced without restart
+pub struct CertResolver {
+    cfg: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    key: RwLock<Arc<CertifiedKey>>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl CertResolver {
+    fn new(cfg: &TlsConfig, provider: Arc<CryptoProvider>) -> Result<Self> {
+        let modified = modified_time(cfg);
+        let key = load_certified_key(cfg, &provider)?;
+
+        Ok(Self {
+            cfg: cfg.clone(),
+            provider,
+            key: RwLock::new(Arc::new(key)),
+            modified: RwLock::new(modified),
+        })
+    }
+
+    /// returns true if certificate was reloaded
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let modified = modified_time(&self.cfg);
+        if modified == *self.modified.read().unwrap() {
+            return Ok(false);
+        }
+
+        let key = load_certified_key(&self.cfg, &self.provider)?;
+        *self.key.write().unwrap() = Arc::new(key);
+        *self.modified.write().unwrap() = modified;
+
+        Ok(true)
+    }
+
+    /// checks certificate files for changes periodically, the task is aborted with its config
+    pub async fn watch(self: Arc<Self>) {
+        loop {
+            sleep(Duration::from_secs(self.cfg.reload_interval)).await;
+
+            match self.reload_if_changed() {
+                Ok(true) => tracing::info!("certificate reloaded: {}", self.cfg.cert.display()),
+                Ok(false) => {},
+                Err(err) => tracing::error!("certificate reload: {:?}", err),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertResolver").field("cert", &self.cfg.cert).finish()
+    }
+}
+
+pub fn new_acceptor(cfg: &TlsConfig) -> Result<(TlsAcceptor, Arc<CertResolver>)> {
+    // builder installs the default provider (ring or aws-lc-rs), it's used for the key as well
+    let builder = rustls::ServerConfig::builder();
+    let provider = CryptoProvider::get_default()
+        .ok_or_else(|| anyhow!("no default TLS crypto provider"))?;
+    let resolver = Arc::new(CertResolver::new(cfg, provider.clone())?);
+
+    let mut tls_cfg = builder
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    tls_cfg.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok((TlsAcceptor::from(Arc::new(tls_cfg)), resolver))
+}
+
+/// TLS and the raw protocol can be used on the same port.
+/// The raw protocol starts with random nonce, thus probability to get TLS record header
+/// with client hello by accident is negligible.
+/// Waits until the record header is received, the caller limits the time.
+pub async fn is_client_hello(stream: &TcpStream) -> bool {
+    // record type, version (2), length (2), handshake type
+    let mut data = [0u8; 6];
+    loop {
+        let readed = match stream.peek(&mut data).await {
+            Ok(0) | Err(_) => return false,
+            Ok(readed) => readed,
+        };
+
+        // the raw protocol is detected by the first bytes without waiting for the rest
+        if data[0] != TLS_HANDSHAKE_RECORD || (readed > 1 && data[1] != TLS_MAJOR_VERSION) {
+            return false;
+        }
+        if readed == data.len() {
+            return data[5] == TLS_CLIENT_HELLO;
+        }
+
+        sleep(PEEK_RETRY_DELAY).await;
+    }
+}
+
+fn load_certified_key(cfg: &TlsConfig, provider: &CryptoProvider) -> Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&cfg.cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {}", cfg.cert.display());
+    }
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&cfg.key)?))?
+        .ok_or_else(|| anyhow!("no private key found in {}", cfg.key.display()))?;
+    let key = provider.key_provider.load_private_key(key)?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+fn modified_time(cfg: &TlsConfig) -> Option<SystemTime> {
+    // certbot replaces both files, cert is enough to check
+    fs::metadata(&cfg.cert).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_client_hello;
+    use std::time::Duration;
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+        time::{sleep, timeout},
+    };
+
+    async fn check(chunks: &[&[u8]]) -> bool {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let chunks: Vec<Vec<u8>> = chunks.iter().map(|chunk| chunk.to_vec()).collect();
+        tokio::spawn(async move {
+            for chunk in chunks {
+                client.write_all(&chunk).await.unwrap();
+                sleep(Duration::from_millis(50)).await;
+            }
+            sleep(Duration::from_millis(500)).await;
+        });
+
+        timeout(Duration::from_millis(300), is_client_hello(&server)).await.unwrap_or(false)
+    }
+
+    #[tokio::test]
+    async fn client_hello() {
+        assert!(check(&[&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01]]).await);
+        // the record header is split
+        assert!(check(&[&[0x16, 0x03], &[0x01, 0x02, 0x00, 0x01]]).await);
+
+        assert!(!check(&[&[0x16, 0x03, 0x01, 0x02, 0x00, 0x02]]).await);
+        assert!(!check(&[&[0x17]]).await);
+        assert!(!check(&[&[0x16, 0x01]]).await);
+    }
+
+    #[tokio::test]
+    async fn client_hello_waits() {
+        // the caller's timeout is reached, the rest of the header is not received
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        client.write_all(&[0x16, 0x03, 0x01]).await.unwrap();
+
+        assert!(timeout(Duration::from_millis(100), is_client_hello(&server)).await.is_err());
+    }
+}
@@ -0,0 +1,502 @@
+use std::{
+    collections::HashMap,
+    fs,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering}},
+    task::{Context, Poll, ready},
+    time::{Duration, Instant},
+};
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Months, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    time::{Sleep, sleep},
+};
+use crate::{config::AppConfig, persist::write_atomic};
+
+/// Usage is saved by the server on this interval (and on shutdown)
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Bandwidth limits and traffic quotas, applied to every user (including the main key).
+/// Users can override them with their own `traffic`
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrafficConfig {
+    /// from the client, bytes per second for all tunnels of the user, 0 - unlimited
+    #[serde(default)]
+    pub upload: u64,
+
+    /// to the client, bytes per second for all tunnels of the user, 0 - unlimited
+    #[serde(default)]
+    pub download: u64,
+
+    /// bytes (both directions) per period, tunnels are closed and new ones refused when it's reached, 0 - unlimited
+    #[serde(default)]
+    pub quota: u64,
+
+    #[serde(default)]
+    pub period: QuotaPeriod,
+
+    /// quota usage is saved to the file and loaded on start
+    pub file: Option<PathBuf>,
+}
+
+/// Overrides of the global limits for the user, 0 - unlimited
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserTrafficConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
+}
+
+/// Quotas are reset at the start of the period (UTC)
+#[derive(Clone, Copy, Default, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuotaPeriod {
+    Daily,
+    #[default]
+    Monthly,
+}
+
+impl QuotaPeriod {
+    fn id(&self, now: DateTime<Utc>) -> String {
+        match self {
+            Self::Daily => now.format("%Y-%m-%d").to_string(),
+            Self::Monthly => now.format("%Y-%m").to_string(),
+        }
+    }
+
+    /// Start of the next period
+    fn end(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now.date_naive();
+        let next = match self {
+            Self::Daily => today.succ_opt(),
+            Self::Monthly => NaiveDate::from_ymd_opt(today.year(), today.month(), 1)
+                .and_then(|first| first.checked_add_months(Months::new(1))),
+        };
+        next.and_then(|date| date.and_hms_opt(0, 0, 0))
+            .map_or(DateTime::<Utc>::MAX_UTC, |time| time.and_utc())
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Daily => "daily",
+            Self::Monthly => "monthly",
+        }
+    }
+}
+
+/// Limits of the user, global ones overridden by the user ones
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct TrafficLimits {
+    pub upload: u64,
+    pub download: u64,
+    pub quota: u64,
+    pub period: QuotaPeriod,
+}
+
+impl AppConfig {
+    pub fn traffic_limits(&self, user: &str) -> TrafficLimits {
+        let mut limits = match &self.traffic {
+            Some(traffic) => TrafficLimits {
+                upload: traffic.upload,
+                download: traffic.download,
+                quota: traffic.quota,
+                period: traffic.period,
+            },
+            None => TrafficLimits::default(),
+        };
+
+        let user_cfg = self.users.iter().find(|u| u.name == user).and_then(|u| u.traffic.as_ref());
+        if let Some(user_cfg) = user_cfg {
+            limits.upload = user_cfg.upload.unwrap_or(limits.upload);
+            limits.download = user_cfg.download.unwrap_or(limits.download);
+            limits.quota = user_cfg.quota.unwrap_or(limits.quota);
+        }
+
+        limits
+    }
+}
+
+/// Token bucket shared by all tunnels of the user, one second of traffic can be sent at once
+#[derive(Default)]
+struct TokenBucket {
+    /// bytes per second, 0 - unlimited
+    rate: AtomicU64,
+    state: Mutex<Option<(f64, Instant)>>,
+}
+
+impl TokenBucket {
+    fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Takes up to `wanted` bytes, returns how long to wait if there are not enough tokens
+    fn take(&self, wanted: usize) -> Result<usize, Duration> {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 || wanted == 0 {
+            return Ok(wanted);
+        }
+
+        let capacity = rate as f64;
+        let chunk = wanted.min(rate as usize);
+        let now = Instant::now();
+
+        let mut state = self.state.lock().unwrap();
+        let (tokens, updated) = state.get_or_insert((capacity, now));
+        *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * capacity).min(capacity);
+        *updated = now;
+
+        if *tokens >= chunk as f64 {
+            *tokens -= chunk as f64;
+            Ok(chunk)
+        } else {
+            Err(Duration::from_secs_f64((chunk as f64 - *tokens) / capacity))
+        }
+    }
+
+    /// Returns tokens that are taken but not used
+    fn refund(&self, unused: usize) {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if let Some((tokens, _)) = self.state.lock().unwrap().as_mut() {
+            *tokens = (*tokens + unused as f64).min(rate as f64);
+        }
+    }
+}
+
+/// Rate limits and quota usage of the user, kept on config reload
+#[derive(Default)]
+pub struct UserTraffic {
+    upload: TokenBucket,
+    download: TokenBucket,
+    /// bytes in the current period
+    used: AtomicU64,
+    /// 0 - unlimited
+    quota: AtomicU64,
+    period: Mutex<CurrentPeriod>,
+    /// unix time of the next period start, checked by tunnels in flight
+    period_end: AtomicI64,
+    /// the event is logged once per period
+    exceeded: AtomicBool,
+}
+
+#[derive(Default)]
+struct CurrentPeriod {
+    kind: QuotaPeriod,
+    id: String,
+}
+
+impl UserTraffic {
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Resets the usage when a new period is started
+    fn update_period(&self, kind: QuotaPeriod, now: DateTime<Utc>) {
+        let id = kind.id(now);
+        let mut current = self.period.lock().unwrap();
+        if current.id != id {
+            current.id = id;
+            self.used.store(0, Ordering::Relaxed);
+            self.exceeded.store(false, Ordering::Relaxed);
+        }
+        current.kind = kind;
+        self.period_end.store(kind.end(now).timestamp(), Ordering::Relaxed);
+    }
+
+    /// Checked by tunnels on every read and write
+    fn quota_exceeded(&self) -> bool {
+        let now = Utc::now();
+        if now.timestamp() >= self.period_end.load(Ordering::Relaxed) {
+            let kind = self.period.lock().unwrap().kind;
+            self.update_period(kind, now);
+        }
+
+        let quota = self.quota.load(Ordering::Relaxed);
+        quota > 0 && self.used() >= quota
+    }
+
+    fn transferred(&self, bytes: usize) {
+        self.used.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Traffic of all users
+pub struct Traffic {
+    users: Mutex<HashMap<String, Arc<UserTraffic>>>,
+}
+
+impl Traffic {
+    /// Loads saved usage if the file is set, missing file is not an error
+    pub fn load(cfg: Option<&TrafficConfig>) -> Result<Self> {
+        let traffic = Self { users: Default::default() };
+        let Some(path) = cfg.and_then(|cfg| cfg.file.as_ref()) else {
+            return Ok(traffic);
+        };
+
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(traffic),
+            Err(err) => anyhow::bail!("traffic file {}: {err}", path.display()),
+        };
+
+        let mut users = traffic.users.lock().unwrap();
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            // user name can have spaces
+            let mut fields = line.rsplitn(3, ' ');
+            let (Some(used), Some(period), Some(name)) = (fields.next(), fields.next(), fields.next()) else {
+                anyhow::bail!("traffic file {}: wrong line {line}", path.display());
+            };
+            let Ok(used) = used.parse::<u64>() else {
+                anyhow::bail!("traffic file {}: wrong line {line}", path.display());
+            };
+
+            let user = UserTraffic {
+                used: AtomicU64::new(used),
+                period: Mutex::new(CurrentPeriod { id: period.to_owned(), ..Default::default() }),
+                ..Default::default()
+            };
+            users.insert(name.to_owned(), Arc::new(user));
+        }
+        drop(users);
+
+        Ok(traffic)
+    }
+
+    /// Applies the current limits of the user, fails if the quota is exceeded
+    pub fn start(&self, user: &str, cfg: &AppConfig) -> Result<Arc<UserTraffic>> {
+        let limits = cfg.traffic_limits(user);
+        let traffic = self.users.lock().unwrap().entry(user.to_owned()).or_default().clone();
+
+        traffic.upload.set_rate(limits.upload);
+        traffic.download.set_rate(limits.download);
+        traffic.quota.store(limits.quota, Ordering::Relaxed);
+        traffic.update_period(limits.period, Utc::now());
+
+        if traffic.quota_exceeded() {
+            if !traffic.exceeded.swap(true, Ordering::Relaxed) {
+                tracing::warn!("{user} exceeded {} quota of {} bytes, tunnels are closed", limits.period.name(), limits.quota);
+                self.save_now(cfg);
+            }
+            anyhow::bail!("{} quota of {} bytes is exceeded", limits.period.name(), limits.quota);
+        }
+
+        Ok(traffic)
+    }
+
+    /// Saves usage if the file is set (every SAVE_INTERVAL and on shutdown)
+    pub fn save_now(&self, cfg: &AppConfig) {
+        if let Some(path) = cfg.traffic.as_ref().and_then(|traffic| traffic.file.as_ref())
+            && let Err(err) = self.save(path)
+        {
+            tracing::error!("traffic file {}: {:?}", path.display(), err);
+        }
+    }
+
+    /// One "user period bytes" per line
+    fn save(&self, path: &Path) -> Result<()> {
+        let content: String = self.users.lock().unwrap().iter()
+            .map(|(name, traffic)| format!("{name} {} {}\n", traffic.period.lock().unwrap().id, traffic.used()))
+            .collect();
+
+        write_atomic(path, &content)
+    }
+}
+
+/// Throttles the client stream of a tunnel and counts the user traffic,
+/// the stream fails when the quota is exceeded.
+/// Reading is upload (from the client), writing is download (to the client)
+pub struct ThrottledStream<S> {
+    inner: S,
+    traffic: Arc<UserTraffic>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> ThrottledStream<S> {
+    pub fn new(inner: S, traffic: Arc<UserTraffic>) -> Self {
+        Self { inner, traffic, read_delay: None, write_delay: None }
+    }
+}
+
+fn quota_error() -> io::Error {
+    io::Error::other("traffic quota is exceeded")
+}
+
+/// Waits for the delay if it's set, the delay is set when the bucket is empty
+fn poll_tokens(
+    bucket: &TokenBucket,
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut Context<'_>,
+    wanted: usize,
+) -> Poll<usize> {
+    loop {
+        if let Some(sleep) = delay {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+
+        match bucket.take(wanted) {
+            Ok(granted) => return Poll::Ready(granted),
+            Err(wait) => *delay = Some(Box::pin(sleep(wait))),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ThrottledStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.traffic.quota_exceeded() {
+            return Poll::Ready(Err(quota_error()));
+        }
+        let granted = ready!(poll_tokens(&this.traffic.upload, &mut this.read_delay, cx, buf.remaining()));
+
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(granted));
+        let result = Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+        let read = limited.filled().len();
+
+        this.traffic.upload.refund(granted - read);
+        if let Poll::Ready(Ok(())) = result {
+            buf.advance(read);
+            this.traffic.transferred(read);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ThrottledStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.traffic.quota_exceeded() {
+            return Poll::Ready(Err(quota_error()));
+        }
+        let granted = ready!(poll_tokens(&this.traffic.download, &mut this.write_delay, cx, buf.len()));
+
+        let result = Pin::new(&mut this.inner).poll_write(cx, &buf[..granted]);
+        let written = match &result {
+            Poll::Ready(Ok(size)) => *size,
+            _ => 0,
+        };
+
+        this.traffic.download.refund(granted - written);
+        this.traffic.transferred(written);
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QuotaPeriod, ThrottledStream, TokenBucket, Traffic, UserTraffic};
+    use crate::config::AppConfig;
+    use chrono::{DateTime, Utc};
+    use std::{sync::{Arc, atomic::Ordering}, time::{Duration, Instant}};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn token_bucket() {
+        let bucket = TokenBucket::default();
+        assert_eq!(bucket.take(1 << 20), Ok(1 << 20));
+
+        bucket.set_rate(1000);
+        // one second of traffic at most
+        assert_eq!(bucket.take(4096), Ok(1000));
+        let wait = bucket.take(500).unwrap_err();
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+
+        bucket.refund(300);
+        assert_eq!(bucket.take(300), Ok(300));
+    }
+
+    #[tokio::test]
+    async fn throttled_stream() {
+        let traffic = Arc::new(UserTraffic::default());
+        traffic.download.set_rate(10_000);
+
+        let (client, mut server) = tokio::io::duplex(1 << 16);
+        let mut stream = ThrottledStream::new(client, traffic.clone());
+
+        // the first second is in the bucket already
+        let started = Instant::now();
+        stream.write_all(&[0u8; 15_000]).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(450));
+
+        server.write_all(&[0u8; 100]).await.unwrap();
+        let mut buf = [0u8; 100];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(traffic.used(), 15_100);
+    }
+
+    #[test]
+    fn quota() {
+        let path = std::env::temp_dir().join(format!("cc-server-traffic-{}.txt", std::process::id()));
+        let cfg_str = format!("address: \"127.0.0.1:8387\"\nkey: \"key\"\n\
+            traffic:\n  quota: 100\n  period: daily\n  file: {}\n", path.display());
+        let cfg = AppConfig::build(&cfg_str).unwrap();
+
+        let traffic = Traffic::load(cfg.traffic.as_ref()).unwrap();
+        let user = traffic.start("alice smith", &cfg).unwrap();
+        user.transferred(100);
+
+        // refused and saved
+        assert!(traffic.start("alice smith", &cfg).is_err());
+        assert!(traffic.start("bob", &cfg).is_ok());
+
+        let loaded = Traffic::load(cfg.traffic.as_ref()).unwrap();
+        assert!(loaded.start("alice smith", &cfg).is_err());
+
+        // the user limit has priority
+        let cfg_str = cfg_str + "users:\n  - name: alice smith\n    key: user_key\n    traffic:\n      quota: 0\n";
+        let cfg = AppConfig::build(&cfg_str).unwrap();
+        assert_eq!(cfg.traffic_limits("alice smith").quota, 0);
+        assert_eq!(cfg.traffic_limits("bob").quota, 100);
+        assert!(loaded.start("alice smith", &cfg).is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn quota_in_flight() {
+        let cfg = AppConfig::build("address: \"127.0.0.1:8387\"\nkey: \"key\"\ntraffic:\n  quota: 1000\n").unwrap();
+        let traffic = Traffic::load(cfg.traffic.as_ref()).unwrap();
+        let user = traffic.start("alice", &cfg).unwrap();
+
+        let (client, _server) = tokio::io::duplex(1 << 16);
+        let mut stream = ThrottledStream::new(client, user.clone());
+        stream.write_all(&[0u8; 1000]).await.unwrap();
+        assert!(stream.write_all(&[0u8; 1]).await.is_err());
+
+        // the open tunnel continues in the next period
+        user.period.lock().unwrap().id = "previous".to_owned();
+        user.period_end.store(0, Ordering::Relaxed);
+        stream.write_all(&[0u8; 1]).await.unwrap();
+        assert_eq!(user.used(), 1);
+    }
+
+    #[test]
+    fn period_end() {
+        let now: DateTime<Utc> = "2026-12-31T18:30:00Z".parse().unwrap();
+        assert_eq!(QuotaPeriod::Daily.end(now), "2027-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap());
+        assert_eq!(QuotaPeriod::Monthly.end(now), "2027-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap());
+
+        let now: DateTime<Utc> = "2026-01-31T00:00:00Z".parse().unwrap();
+        assert_eq!(QuotaPeriod::Daily.end(now), "2026-02-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap());
+        assert_eq!(QuotaPeriod::Monthly.end(now), "2026-02-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap());
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use anyhow::Result;
+use bytes::BytesMut;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::UdpSocket,
+    select,
+    time::{sleep_until, Instant},
+};
+use crypto::datagram::{put_datagram, DatagramAddr, DatagramReader, MAX_DATAGRAM_SIZE};
+use crate::{config::AppConfig, outbound::OutboundPool, server::{self, ServerState}, users::User};
+
+// resolved destinations are cached for this number of hosts per session
+const MAX_RESOLVED_HOSTS: usize = 256;
+// the resolver has its own cache, it's checked again after this time
+const RESOLVED_TTL: Duration = Duration::from_secs(60);
+
+/// UDP relay (UDP associate command)
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UdpConfig {
+    /// enabled by default
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+
+    /// session is closed if there are no datagrams (sec), 60 sec by default
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            idle_timeout: default_idle_timeout(),
+        }
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_idle_timeout() -> u64 {
+    60
+}
+
+/// UDP sockets of one session, created on demand for every address family
+#[derive(Default)]
+struct Sockets {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+}
+
+impl Sockets {
+    /// Outbound address is selected once per session and family, the first destination is used for the selection
+    async fn get(&mut self, addr: &SocketAddr, host: &str, outbound: &OutboundPool, user: &User) -> io::Result<&UdpSocket> {
+        let (socket, unspecified) = match addr {
+            SocketAddr::V4(_) => (&mut self.v4, IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            SocketAddr::V6(_) => (&mut self.v6, IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        };
+
+        if socket.is_none() {
+            let bind_ip = outbound.select(addr, &user.name, host).unwrap_or(unspecified);
+            *socket = Some(UdpSocket::bind(SocketAddr::new(bind_ip, 0)).await?);
+        }
+
+        Ok(socket.as_ref().unwrap())
+    }
+}
+
+/// Resolved destinations of the session, limited so many hostnames don't grow it
+#[derive(Default)]
+struct ResolvedHosts(HashMap<String, (SocketAddr, Instant)>);
+
+impl ResolvedHosts {
+    fn get(&self, host: &str, now: Instant) -> Option<SocketAddr> {
+        self.0.get(host)
+            .filter(|(_, resolved)| now < *resolved + RESOLVED_TTL)
+            .map(|(addr, _)| *addr)
+    }
+
+    /// The host is not cached if all entries are still valid
+    fn insert(&mut self, host: String, addr: SocketAddr, now: Instant) {
+        if !self.0.contains_key(&host) && self.0.len() >= MAX_RESOLVED_HOSTS {
+            self.0.retain(|_, (_, resolved)| now < *resolved + RESOLVED_TTL);
+            if self.0.len() >= MAX_RESOLVED_HOSTS {
+                return;
+            }
+        }
+        self.0.insert(host, (addr, now));
+    }
+}
+
+/// Relays datagrams between the client stream and UDP sockets until the stream is closed or session is idle
+pub async fn relay<S: AsyncRead + AsyncWrite + Unpin>(
+    client: &mut S,
+    socket_addr: SocketAddr,
+    cfg: &AppConfig,
+    state: &ServerState,
+    user: &User,
+) -> Result<()> {
+    let idle_timeout = Duration::from_secs(cfg.udp.idle_timeout);
+    let mut deadline = Instant::now() + idle_timeout;
+
+    let mut sockets = Sockets::default();
+    let mut reader = DatagramReader::default();
+
+    let mut resolved = ResolvedHosts::default();
+
+    let mut buf_v4 = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut buf_v6 = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut response = BytesMut::with_capacity(MAX_DATAGRAM_SIZE);
+
+    loop {
+        let (payload, from) = select! {
+            datagram = reader.read(client) => {
+                let Some((addr, payload)) = datagram? else {
+                    break;
+                };
+                deadline = Instant::now() + idle_timeout;
+
+                let dest = match &addr {
+                    DatagramAddr::Ip(addr) => cfg.destination.check_addr(addr)
+                        .map(|_| *addr)
+                        .map_err(|reason| anyhow::anyhow!("DENY {} from {socket_addr} to {addr}: {reason}", user.name)),
+                    DatagramAddr::Domain(..) => {
+                        let host = addr.to_string();
+                        let now = Instant::now();
+                        match resolved.get(&host, now) {
+                            Some(dest) => Ok(dest),
+                            // datagrams are not raced, the most preferred address is used
+                            None => server::resolve_destination(&host, cfg, state, user, socket_addr).await
+                                .map(|addrs| addrs[0])
+                                .inspect(|dest| resolved.insert(host, *dest, now)),
+                        }
+                    },
+                };
+
+                match dest {
+                    Ok(dest) => {
+                        let sent = async {
+                            sockets.get(&dest, &addr.to_string(), &state.outbound, user).await?.send_to(&payload, dest).await
+                        }.await;
+                        // unreachable destination drops the datagram only
+                        if let Err(err) = sent {
+                            tracing::debug!("UDP {} from {socket_addr} to {dest}: {err}", user.name);
+                        }
+                    },
+                    // drop the datagram, the session is still valid
+                    Err(err) => tracing::debug!("UDP {:?}", err),
+                }
+                continue;
+            },
+            res = recv_from(&sockets.v4, &mut buf_v4) => {
+                let (size, from) = res?;
+                (&buf_v4[..size], from)
+            },
+            res = recv_from(&sockets.v6, &mut buf_v6) => {
+                let (size, from) = res?;
+                (&buf_v6[..size], from)
+            },
+            _ = sleep_until(deadline) => {
+                tracing::debug!("UDP {} from {socket_addr}: idle timeout", user.name);
+                break;
+            },
+        };
+
+        deadline = Instant::now() + idle_timeout;
+
+        response.clear();
+        put_datagram(&mut response, &DatagramAddr::Ip(from), payload)?;
+        client.write_all(&response).await?;
+        client.flush().await?;
+    }
+
+    Ok(())
+}
+
+/// Pending forever if there is no socket yet
+async fn recv_from(socket: &Option<UdpSocket>, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ResolvedHosts, MAX_RESOLVED_HOSTS, RESOLVED_TTL};
+    use std::net::SocketAddr;
+    use tokio::time::Instant;
+
+    #[test]
+    fn resolved_hosts() {
+        let addr: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let now = Instant::now();
+        let mut resolved = ResolvedHosts::default();
+
+        for i in 0..MAX_RESOLVED_HOSTS + 10 {
+            resolved.insert(format!("host{i}:53"), addr, now);
+        }
+        assert_eq!(resolved.0.len(), MAX_RESOLVED_HOSTS);
+        assert_eq!(resolved.get("host0:53", now), Some(addr));
+        assert_eq!(resolved.get(&format!("host{MAX_RESOLVED_HOSTS}:53"), now), None);
+
+        // expired entries are replaced
+        let later = now + RESOLVED_TTL;
+        assert_eq!(resolved.get("host0:53", later), None);
+        resolved.insert("new:53".to_owned(), addr, later);
+        assert_eq!(resolved.0.len(), 1);
+        assert_eq!(resolved.get("new:53", later), Some(addr));
+    }
+}
@@ -0,0 +1,116 @@
+use std::{io, path::{Path, PathBuf}};
+use anyhow::Result;
+use colored::*;
+use serde::Deserialize;
+
+/// Unix socket for the reverse proxy (nginx), it can't be reached from the network.
+/// Connections on it are upgrade-capable like ones from 127.0.0.1
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UnixSocketConfig {
+    pub path: PathBuf,
+
+    /// socket file permissions (octal), the reverse proxy user should be able to write to it
+    #[serde(default = "default_mode")]
+    pub mode: String,
+}
+
+fn default_mode() -> String {
+    "660".to_owned()
+}
+
+impl UnixSocketConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.path.as_os_str().is_empty() {
+            anyhow::bail!("unix_socket {} is empty", "path".bold());
+        }
+
+        self.mode()?;
+        Ok(())
+    }
+
+    fn mode(&self) -> Result<u32> {
+        match u32::from_str_radix(&self.mode, 8) {
+            Ok(mode) if mode <= 0o777 => Ok(mode),
+            _ => anyhow::bail!("unix_socket {} {} is not octal permissions like 660", "mode".bold(), self.mode.bold()),
+        }
+    }
+}
+
+#[cfg(unix)]
+pub type UnixStream = tokio::net::UnixStream;
+// never accepted, the listener can't be bound
+#[cfg(not(unix))]
+pub type UnixStream = tokio::io::DuplexStream;
+
+/// The socket file is removed on drop
+pub struct UnixListener {
+    #[cfg(unix)]
+    listener: tokio::net::UnixListener,
+    path: PathBuf,
+}
+
+impl UnixListener {
+    pub fn bind(cfg: &UnixSocketConfig) -> Result<Self> {
+        #[cfg(not(unix))]
+        anyhow::bail!("unix sockets are not supported on this platform: {}", cfg.path.display());
+
+        #[cfg(unix)]
+        Ok(Self { listener: bind(&cfg.path, cfg.mode()?)?, path: cfg.path.clone() })
+    }
+
+    pub async fn accept(&self) -> io::Result<UnixStream> {
+        #[cfg(unix)]
+        return self.listener.accept().await.map(|(stream, _)| stream);
+        #[cfg(not(unix))]
+        std::future::pending().await
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Pending forever if the listener is not set
+pub async fn accept(listener: Option<&UnixListener>) -> io::Result<UnixStream> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Stale socket from the previous run is removed
+#[cfg(unix)]
+pub fn bind(path: &Path, mode: u32) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UnixSocketConfig;
+
+    #[test]
+    fn mode() {
+        let cfg = |mode: &str| UnixSocketConfig { path: "/run/cc-server.sock".into(), mode: mode.to_owned() };
+
+        assert_eq!(cfg("660").mode().unwrap(), 0o660);
+        assert_eq!(cfg("0600").mode().unwrap(), 0o600);
+        assert!(cfg("680").mode().is_err());
+        assert!(cfg("1777").mode().is_err());
+        assert!(cfg("rw").check().is_err());
+    }
+}
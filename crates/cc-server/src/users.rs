@@ -0,0 +1,229 @@
+use std::{
+    sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}},
+};
+use anyhow::Result;
+use bytes::BytesMut;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crypto::kdf::{Kdf, TempKey};
+use crate::{config::AppConfig, traffic::UserTrafficConfig};
+
+/// name of the user that owns `key` from the main protocol config
+pub const DEFAULT_USER: &str = "default";
+
+// header keys for the current and the previous timestamp interval
+// (and the same for get protocol request)
+const MAX_CACHED_HEADER_KEYS: usize = 4;
+
+/// Server user with a separate key
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    /// user name, used in logs and stats
+    pub name: String,
+
+    /// user key, generated by --add-user
+    pub key: String,
+
+    /// keys that are still accepted after rotation
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub previous_keys: Vec<PreviousKey>,
+
+    /// enabled if not set
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+
+    /// overrides of the global bandwidth limits and quota
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traffic: Option<UserTrafficConfig>,
+}
+
+/// Replaced key that is accepted for a grace period, so clients can be updated
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PreviousKey {
+    pub key: String,
+
+    /// the key is not accepted after this time (RFC 3339), accepted until removed if not set
+    pub expires: Option<DateTime<Utc>>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Default)]
+pub struct UserState {
+    pub tunnels: AtomicU64,
+    pub active: AtomicU64,
+    pub rx_total: AtomicU64,
+    pub tx_total: AtomicU64,
+}
+
+pub struct User {
+    pub name: String,
+    /// the current key goes first
+    pub keys: Vec<UserKey>,
+    /// kept on config reload
+    pub state: Arc<UserState>,
+}
+
+pub struct UserKey {
+    pub key: String,
+    pub url_path: String,
+    /// position in previous_keys (from 1), None for the current key
+    pub previous: Option<usize>,
+    pub expires: Option<DateTime<Utc>>,
+    /// shared header key of temporary keys, the data key is restored from the header
+    pub temp: bool,
+
+    // header key depends only on the key and the timestamp interval
+    // thus we can derive it once per interval instead of once per connection
+    header_keys: Mutex<Vec<(HeaderKeyId, BytesMut)>>,
+}
+
+#[derive(PartialEq)]
+struct HeaderKeyId {
+    kdf: Kdf,
+    timestamp: i64,
+    size: usize,
+}
+
+/// All enabled users, the default key (if set) goes first
+pub struct Users {
+    users: Vec<Arc<User>>,
+}
+
+impl UserKey {
+    fn new(key: &str, url_path: String, previous: Option<usize>, expires: Option<DateTime<Utc>>) -> Self {
+        Self {
+            key: key.to_owned(),
+            url_path,
+            previous,
+            expires,
+            temp: false,
+            header_keys: Default::default(),
+        }
+    }
+
+    /// Previous key is not accepted after expiration
+    pub fn is_valid(&self, now: DateTime<Utc>) -> bool {
+        self.expires.is_none_or(|expires| now < expires)
+    }
+
+    pub fn header_key(&self, kdf: Kdf, timestamp: i64, out: &mut BytesMut) -> Result<()> {
+        let id = HeaderKeyId { kdf, timestamp, size: out.len() };
+
+        if let Some((_, key)) = self.header_keys.lock().unwrap().iter().find(|(key_id, _)| key_id == &id) {
+            out.copy_from_slice(key);
+            return Ok(());
+        }
+
+        // derive without lock, it can take a while for argon2
+        kdf.derive_key_from_timestamp(self.key.as_bytes(), timestamp, out)?;
+
+        let mut header_keys = self.header_keys.lock().unwrap();
+        if header_keys.len() >= MAX_CACHED_HEADER_KEYS {
+            // remove the oldest one
+            header_keys.remove(0);
+        }
+        header_keys.push((id, out.clone()));
+
+        Ok(())
+    }
+}
+
+impl User {
+    /// Keys that are accepted now, the current key first
+    pub fn valid_keys(&self) -> impl Iterator<Item = &UserKey> {
+        let now = Utc::now();
+        self.keys.iter().filter(move |key| key.is_valid(now))
+    }
+
+    fn same_keys(&self, key: &str, previous_keys: &[PreviousKey], temp_keys: bool) -> bool {
+        self.keys.len() == previous_keys.len() + 1 + temp_keys as usize
+            && self.keys.iter().any(|user_key| user_key.temp) == temp_keys
+            && self.keys[0].key == key
+            && self.keys[1..].iter().zip(previous_keys).all(|(user_key, previous)| {
+                user_key.key == previous.key && user_key.expires == previous.expires
+            })
+    }
+
+    pub fn tunnel_started(&self) {
+        self.state.tunnels.fetch_add(1, Ordering::Relaxed);
+        self.state.active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tunnel_finished(&self, rx: u64, tx: u64) {
+        self.state.active.fetch_sub(1, Ordering::Relaxed);
+        self.state.rx_total.fetch_add(rx, Ordering::Relaxed);
+        self.state.tx_total.fetch_add(tx, Ordering::Relaxed);
+    }
+}
+
+impl Users {
+    /// url_path is already derived for the default key
+    pub fn new(cfg: &AppConfig, url_path: &str) -> Result<Self> {
+        Self::build(cfg, url_path, None)
+    }
+
+    /// Users with the same name keep their counters after config reload,
+    /// unchanged users are reused as is
+    pub fn reload(cfg: &AppConfig, url_path: &str, previous: &Users) -> Result<Self> {
+        Self::build(cfg, url_path, Some(previous))
+    }
+
+    fn build(cfg: &AppConfig, url_path: &str, previous: Option<&Users>) -> Result<Self> {
+        let mut users = Vec::with_capacity(cfg.users.len() + 1);
+
+        let mut push = |name: &str, key: &str, previous_keys: &[PreviousKey], temp_keys: bool, url_path: Option<&str>| -> Result<()> {
+            let previous = previous.and_then(|p| p.users.iter().find(|u| u.name == name));
+            let user = match previous {
+                Some(user) if user.same_keys(key, previous_keys, temp_keys) => user.clone(),
+                _ => {
+                    let url_path = match url_path {
+                        Some(url_path) => url_path.to_owned(),
+                        None => Kdf::derive_url_path(key)?,
+                    };
+
+                    let mut keys = vec![UserKey::new(key, url_path, None, None)];
+                    for (i, previous_key) in previous_keys.iter().enumerate() {
+                        let url_path = Kdf::derive_url_path(&previous_key.key)?;
+                        keys.push(UserKey::new(&previous_key.key, url_path, Some(i + 1), previous_key.expires));
+                    }
+
+                    if temp_keys {
+                        let header_key = TempKey::header_key(key)?;
+                        let url_path = Kdf::derive_url_path(&header_key)?;
+                        keys.push(UserKey { temp: true, ..UserKey::new(&header_key, url_path, None, None) });
+                    }
+
+                    let state = previous.map(|u| u.state.clone()).unwrap_or_default();
+                    Arc::new(User { name: name.to_owned(), keys, state })
+                },
+            };
+
+            users.push(user);
+            Ok(())
+        };
+
+        if !cfg.protocol.key.is_empty() {
+            push(DEFAULT_USER, &cfg.protocol.key, &cfg.previous_keys, cfg.temp_keys, Some(url_path))?;
+        }
+
+        for user in cfg.users.iter().filter(|u| u.enabled) {
+            push(&user.name, &user.key, &user.previous_keys, false, None)?;
+        }
+
+        Ok(Self { users })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<User>> {
+        self.users.iter()
+    }
+
+    /// Url path of any valid key
+    pub fn find_by_url_path(&self, request: &str) -> Option<&Arc<User>> {
+        self.users.iter().find(|u| u.valid_keys().any(|key| request.contains(&key.url_path)))
+    }
+}
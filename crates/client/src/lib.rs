@@ -5,4 +5,5 @@ mod protocol;
 mod ttfb_stream;
 mod monitor_stream;
 mod pac_file_service;
+mod socks5;
 mod upgrade_stream;
@@ -1,18 +1,25 @@
 use std::{
-    mem, net::SocketAddr, sync::{atomic::{AtomicU64, Ordering}, Arc}
+    mem, net::SocketAddr, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::Duration
 };
 use anyhow::{bail, Result};
-use tokio::io::{AsyncRead, AsyncWriteExt, AsyncReadExt};
+use tokio::{
+    io::{AsyncRead, AsyncWriteExt, AsyncReadExt},
+    net::UdpSocket,
+    select,
+};
 use rand::prelude::*;
 use rand_chacha::ChaCha20Rng;
 use chrono::Utc;
 use bytes::{Buf, BufMut, BytesMut};
 use crypto::{
-    cipher::{Cipher, CipherType}, config::{ProtocolConfig, DataPadding}, stream::EncryptedStream, kdf::Kdf,
+    cipher::{Cipher, CipherType}, config::{ProtocolConfig, DataPadding}, stream::EncryptedStream, kdf::{Kdf, TempKey},
+    datagram::{put_datagram, DatagramReader, MAX_DATAGRAM_SIZE, UDP_ASSOCIATE_HOST},
+    version::{Capabilities, PeerVersion}, key_exchange::{ClientKeyExchange, KeyExchange},
     MIN_HOST_LEN, GET_PROTOCOL_MAX_CONNECT_DELAY
 };
 use crate::config::ServerConfig;
 use crate::monitor_stream::MonitorStream;
+use crate::socks5;
 
 #[derive(Clone)]
 pub struct Server {
@@ -37,6 +44,13 @@ pub struct SelectedServer {
     pub state: Arc<ServerState>,
 }
 
+/// features of this client, sent in every tunnel header
+fn client_capabilities() -> Capabilities {
+    Capabilities::UDP_ASSOCIATE
+        .union(Capabilities::TEMP_KEYS)
+        .with(Capabilities::ML_KEM, KeyExchange::X25519MlKem768.is_supported())
+}
+
 const MAX_GET_PROTOCOL_HEADER_PADDING: u16 = 4096;
 const MIN_GET_PROTOCOL_HEADER_PADDING: u16 = 177;
 
@@ -102,6 +116,7 @@ pub async fn get_server_protocol(
     let header_len = 
         mem::size_of::<u16>()
         + mem::size_of::<u16>()
+        + PeerVersion::SIZE
         + tag_size
         + tag_size;
 
@@ -115,13 +130,22 @@ pub async fn get_server_protocol(
 
     let mut cipher_aes = Cipher::new_with_nonce(CipherType::Aes256Gcm, &response_key, &salt[0..nonce_size]);
     let mut cipher_cha = Cipher::new_with_nonce(CipherType::ChaCha20Poly1305, &response_key, &salt[key_size - nonce_size..key_size]);
-    
-    if !cipher_cha.decrypt(&mut header) {
-        bail!("can't decrypt header");
-    }
 
-    header.truncate(header.len() - tag_size);
-    if !cipher_aes.decrypt(&mut header) {
+    let decrypt_header = |cipher_aes: &mut Cipher, cipher_cha: &mut Cipher, header: &mut BytesMut| {
+        if !cipher_cha.decrypt(header) {
+            return false;
+        }
+
+        header.truncate(header.len() - tag_size);
+        cipher_aes.decrypt(header)
+    };
+
+    // servers before protocol versions send the header without it, the payload follows it
+    let mut legacy_header = BytesMut::from(&header[..header_len - PeerVersion::SIZE]);
+    if !decrypt_header(&mut cipher_aes, &mut cipher_cha, &mut header) {
+        if decrypt_header(&mut cipher_aes, &mut cipher_cha, &mut legacy_header) {
+            PeerVersion { version: 0, min_version: 0, capabilities: Capabilities::empty() }.check("server")?;
+        }
         bail!("can't decrypt header");
     }
 
@@ -134,6 +158,10 @@ pub async fn get_server_protocol(
     let padding_bytes = header.split_to(mem::size_of::<u16>());
     let padding_end = u16::from_be_bytes(padding_bytes.as_ref().try_into().unwrap()) as usize;
 
+    let server_version = PeerVersion::get(&mut header);
+    server_version.check("server")?;
+    tracing::debug!("server protocol version {}, capabilities: {}", server_version.version, server_version.capabilities);
+
     let data_len = padding_start
         + mem::size_of::<u8>()  // kdf
         + mem::size_of::<u8>()  // cipher
@@ -143,6 +171,7 @@ pub async fn get_server_protocol(
         + mem::size_of::<u16>() // data_padding.max
         + mem::size_of::<u8>()  // data_padding.rate
         + mem::size_of::<u64>()  // encryption_limit
+        + mem::size_of::<u8>()  // key_exchange
         + padding_end
         + tag_size
         + tag_size;
@@ -167,6 +196,7 @@ pub async fn get_server_protocol(
     let header_padding = payload.get_u16()..payload.get_u16();
     let data_padding = DataPadding {max: payload.get_u16(), rate: payload.get_u8()};
     let encryption_limit = payload.get_u64() as usize;
+    let key_exchange = KeyExchange::try_from(payload.get_u8())?;
     
     let key = key.to_owned();
     Ok(ProtocolConfig{
@@ -177,20 +207,131 @@ pub async fn get_server_protocol(
         header_padding,
         data_padding,
         encryption_limit,
+        key_exchange,
     })
 }
 
 pub async fn process_tunnel(
-    mut server: impl AsyncWriteExt + Unpin + AsyncRead,
+    server: impl AsyncWriteExt + Unpin + AsyncRead,
     mut client: impl AsyncWriteExt + Unpin + AsyncRead,
     host: String,
-    mut rng: impl CryptoRng + Rng,
+    rng: impl CryptoRng + Rng,
     selected_server: SelectedServer,
+    idle_timeout: Option<Duration>,
 ) -> Result<()> 
+{
+    let server = open_session(server, host.as_bytes(), rng, &selected_server.protocol).await?;
+    let mut server = MonitorStream::from_stream(server, selected_server.state.clone());
+    
+    let result = tcp_options::copy_bidirectional(&mut client, &mut server, idle_timeout).await;
+
+    if !server.is_success() {
+        selected_server.state.err_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    result?;
+    Ok(())
+}
+
+/// Relays datagrams between SOCKS5 UDP socket and the server until control connection is closed.
+/// Datagrams are accepted from the ip of control connection only.
+pub async fn process_udp_tunnel(
+    server: impl AsyncWriteExt + Unpin + AsyncRead,
+    mut control: impl AsyncWriteExt + Unpin + AsyncRead,
+    socket: UdpSocket,
+    client_addr: SocketAddr,
+    rng: impl CryptoRng + Rng,
+    selected_server: SelectedServer,
+) -> Result<()>
+{
+    let server = open_session(server, &UDP_ASSOCIATE_HOST, rng, &selected_server.protocol).await?;
+    let mut server = MonitorStream::from_stream(server, selected_server.state.clone());
+
+    let mut reader = DatagramReader::default();
+    let mut app_addr = None;
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut packet = BytesMut::with_capacity(MAX_DATAGRAM_SIZE);
+    let mut control_buf = [0u8; 1];
+
+    loop {
+        select! {
+            res = control.read(&mut control_buf) => {
+                // association ends with control connection
+                if matches!(res, Ok(0) | Err(_)) {
+                    break;
+                }
+            },
+            res = socket.recv_from(&mut buf) => {
+                let (size, from) = res?;
+                if from.ip() != client_addr.ip() {
+                    continue;
+                }
+                app_addr = Some(from);
+
+                let Some((addr, payload)) = socks5::parse_udp_request(&buf[..size]) else {
+                    continue;
+                };
+
+                packet.clear();
+                if put_datagram(&mut packet, &addr, payload).is_err() {
+                    continue;
+                }
+                server.write_all(&packet).await?;
+                server.flush().await?;
+            },
+            res = reader.read(&mut server) => {
+                let Some((addr, payload)) = res? else {
+                    break;
+                };
+
+                if let Some(app_addr) = app_addr {
+                    packet.clear();
+                    if socks5::put_udp_header(&mut packet, &addr).is_err() {
+                        continue;
+                    }
+                    packet.put_slice(&payload);
+                    socket.send_to(&packet, app_addr).await?;
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends header with host (or command) and returns encrypted stream
+async fn open_session<S, R>(
+    mut server: S,
+    host: &[u8],
+    mut rng: R,
+    protocol: &ProtocolConfig,
+) -> Result<EncryptedStream<S, R>>
+where
+    S: AsyncWriteExt + Unpin + AsyncRead,
+    R: CryptoRng + Rng,
 {
     let ProtocolConfig {
-        key, kdf, cipher: cipher_type, header_padding, ..
-    } = &selected_server.protocol;
+        key, kdf, cipher: cipher_type, header_padding, key_exchange, ..
+    } = protocol;
+
+    // ephemeral key exchange, the share is sent with the host
+    let client_key_exchange = ClientKeyExchange::new(*key_exchange)?;
+
+    // temporary key: the header is encrypted with the key shared by all temporary keys,
+    // the host is prefixed with the token, the server restores the data key from it
+    let temp_key = TempKey::parse(key);
+    let token_host;
+    let (header_pass, data_pass, host) = match &temp_key {
+        Some(temp_key) => {
+            token_host = [temp_key.token.as_slice(), host].concat();
+            (&temp_key.header_key, &temp_key.key, token_host.as_slice())
+        },
+        None => (key, key, host),
+    };
+
+    if host.len() > MIN_HOST_LEN + u8::MAX as usize {
+        bail!("host is too long");
+    }
 
     // prepare header        
     let key_size = cipher_type.key_size();
@@ -206,16 +347,18 @@ pub async fn process_tunnel(
         + salt.len()
         + mem::size_of::<u16>()
         + mem::size_of::<u8>()
+        + PeerVersion::SIZE
         + cipher_type.tag_size()
         + u8::MAX as usize // max host len (saved as u8)
+        + key_exchange.client_share_size()
         + cipher_type.tag_size()
         + header_padding.end as usize
     );
 
     // header cipher
     let mut header_key = BytesMut::zeroed(key_size);
-    let timestamp = Utc::now().timestamp_millis() / (selected_server.protocol.max_connect_delay as i64);
-    kdf.derive_key_from_timestamp(key.as_bytes(), timestamp, &mut header_key)?;
+    let timestamp = Utc::now().timestamp_millis() / (protocol.max_connect_delay as i64);
+    kdf.derive_key_from_timestamp(header_pass.as_bytes(), timestamp, &mut header_key)?;
 
     let mut header_cipher = Cipher::new(*cipher_type, &header_key, &mut rng);
     
@@ -226,14 +369,16 @@ pub async fn process_tunnel(
     packet.put(salt.as_ref());
     packet.put_u16(padding_size);
     packet.put_u8((host.len() - MIN_HOST_LEN) as u8);
+    PeerVersion::current(client_capabilities()).put(&mut packet);
 
     // encrypt main header part
     header_cipher.encrypt(&mut packet, nonce_size);
     header_cipher.inc_nonce(padding_size);
 
-    // add host and encrypt
+    // add host with the key share and encrypt
     let header_main_size = packet.len();
-    packet.put(host.as_bytes());
+    packet.put(host);
+    packet.put(client_key_exchange.share());
     header_cipher.encrypt(&mut packet, header_main_size);
 
     // add unencrypted padding
@@ -246,28 +391,31 @@ pub async fn process_tunnel(
     server.write_all(packet.as_ref()).await?;
     server.flush().await?;
 
+    // the server replies with its share before the data
+    let secret = if key_exchange.enabled() {
+        let mut response = BytesMut::zeroed(key_exchange.server_share_size() + cipher_type.tag_size());
+        server.read_exact(response.as_mut()).await?;
+
+        let mut cipher = Cipher::new_key_exchange(*cipher_type, *kdf, data_pass, &salt)?;
+        if !cipher.decrypt(&mut response) {
+            bail!("can't decrypt server key share");
+        }
+        client_key_exchange.finish(&response[..key_exchange.server_share_size()])?
+    } else {
+        Vec::new()
+    };
+
     let (client_cipher, server_cipher) =
-        Cipher::new_client_server(*cipher_type, *kdf, key, &salt)?;
+        Cipher::new_client_server_with_secret(*cipher_type, *kdf, data_pass, &salt, &secret)?;
 
-    let server = EncryptedStream::from_stream(
+    Ok(EncryptedStream::from_stream(
         server,
         server_cipher,
         client_cipher,
-        selected_server.protocol.data_padding,
-        selected_server.protocol.encryption_limit,
+        protocol.data_padding,
+        protocol.encryption_limit,
         rng
-    );
-    
-    let mut server = MonitorStream::from_stream(server, selected_server.state.clone());
-    
-    let result = tokio::io::copy_bidirectional(&mut client, &mut server).await;
-
-    if !server.is_success() {
-        selected_server.state.err_count.fetch_add(1, Ordering::Relaxed);
-    }
-
-    result?;
-    Ok(())
+    ))
 }
 
 impl From<&Server> for SelectedServer {
@@ -11,7 +11,7 @@ use std::{
 use sys_connections::{Protocol, process_path_by_local_addr};
 use tokio::{
     io::{AsyncRead, AsyncWriteExt},
-    net::{TcpListener, TcpStream, lookup_host},
+    net::{TcpListener, TcpStream, UdpSocket, lookup_host},
     select,
     sync::{Mutex, RwLock, oneshot},
 };
@@ -22,6 +22,8 @@ use axum::{
     http::{Method, StatusCode, uri},
     response::{IntoResponse, Response},
 };
+use happy_eyeballs::Timing;
+use tcp_options::TcpOptions;
 use hyper::{body::Incoming, server::conn::http1, upgrade::Upgraded};
 use hyper_util::rt::TokioIo;
 use rand::prelude::*;
@@ -34,13 +36,14 @@ use tokio_rustls::{
 use tower::util::ServiceExt;
 
 use crate::pac_file_service::PacFileService;
+use crate::socks5;
 use crate::protocol::{self, SelectedServer, Server};
 use crate::upgrade_stream::UgradeStream;
 use crate::{
     config::{ServerConfig, ServerConnectConfig, default_server_address},
     ttfb_stream::TtfbStream,
 };
-use crypto::config::ProtocolConfig;
+use crypto::{config::ProtocolConfig, kdf::TempKey};
 
 #[derive(Debug, Default, Clone, Copy, Deserialize, Serialize, PartialEq)]
 pub enum ProxyState {
@@ -58,11 +61,12 @@ pub struct Proxy {
     tls_cfg: Arc<rustls::ClientConfig>,
     initialized: AtomicBool,
     restart: Mutex<Option<oneshot::Sender<()>>>,
+    tcp_options: RwLock<TcpOptions>,
 }
 
 enum StreamType {
     TcpStream(TcpStream),
-    UgradeStream(UgradeStream<TlsStream<TcpStream>>),
+    UgradeStream(Box<UgradeStream<TlsStream<TcpStream>>>),
 }
 
 impl Proxy {
@@ -88,9 +92,15 @@ impl Proxy {
             tls_cfg: Arc::new(tls_cfg),
             initialized: AtomicBool::new(false),
             restart: Default::default(),
+            tcp_options: Default::default(),
         }))
     }
 
+    /// Applied to new connections: inbound, to the server and direct ones
+    pub async fn set_tcp_options(&self, options: TcpOptions) {
+        *self.tcp_options.write().await = options;
+    }
+
     pub fn get_proxy_address(&self) -> SocketAddr {
         self.pac_service.get_proxy_address()
     }
@@ -155,10 +165,10 @@ impl Proxy {
     async fn remove_domain_from_servers(&self, domain: &str) -> Result<()> {
         let mut servers = self.servers.write().await;
         for srv in servers.iter_mut() {
-            if let Some(domains) = &mut srv.config.domains {
-                if let Some(idx) = domains.iter().position(|d| d == domain) {
-                    domains.remove(idx);
-                }
+            if let Some(domains) = &mut srv.config.domains
+                && let Some(idx) = domains.iter().position(|d| d == domain)
+            {
+                domains.remove(idx);
             }
         }
 
@@ -202,10 +212,10 @@ impl Proxy {
     async fn remove_app_from_servers(&self, app: &str) -> Result<()> {
         let mut servers = self.servers.write().await;
         for srv in servers.iter_mut() {
-            if let Some(apps) = &mut srv.config.apps {
-                if let Some(idx) = apps.iter().position(|d| d == app) {
-                    apps.remove(idx);
-                }
+            if let Some(apps) = &mut srv.config.apps
+                && let Some(idx) = apps.iter().position(|d| d == app)
+            {
+                apps.remove(idx);
             }
         }
 
@@ -270,7 +280,7 @@ impl Proxy {
         let mut wr_servers = self.servers.write().await;
         if let Some(idx) = wr_servers.iter().position(|s| s.config.host == host) {
             wr_servers.remove(idx);
-            if wr_servers.len() == 0 {
+            if wr_servers.is_empty() {
                 drop(wr_servers);
                 // turn off proxy if we have no servers
                 self.set_proxy_state(ProxyState::Off).await
@@ -304,7 +314,12 @@ impl Proxy {
         }
     }
 
+    /// Temporary key includes the protocol, the server is not requested
     pub async fn get_server_protocol(&self, host: &str, key: &str) -> Result<ProtocolConfig> {
+        if let Some(temp_key) = TempKey::parse(key) {
+            return Ok(ProtocolConfig::from_temp_key(key, &temp_key));
+        }
+
         let conn_cfg = ServerConnectConfig::new(host, key).await?;
 
         match self
@@ -424,7 +439,7 @@ impl Proxy {
         }
 
         let servers = self.servers.read().await;
-        if servers.len() == 0 {
+        if servers.is_empty() {
             bail!("no servers found")
         }
 
@@ -536,9 +551,21 @@ impl Proxy {
                 result = listener.accept() => {
                     match result {
                         Ok((stream, client_addr)) => {
-                            let io = TokioIo::new(stream);
+                            if let Err(err) = self.tcp_options.read().await.apply(&stream) {
+                                tracing::debug!("socket options for {client_addr}: {:?}", err);
+                            }
+
                             let handle_request = handle_request.clone();
+                            let proxy = self.clone();
                             tokio::task::spawn(async move {
+                                if socks5::is_socks5(&stream).await {
+                                    if let Err(err) = socks5::serve_connection(stream, proxy, client_addr).await {
+                                        tracing::warn!("SOCKS5 connection: {}", err);
+                                    }
+                                    return;
+                                }
+
+                                let io = TokioIo::new(stream);
                                 let service =
                                     hyper::service::service_fn(move |request: Request<Incoming>| handle_request(request, client_addr));
 
@@ -579,19 +606,68 @@ impl Proxy {
         selected: SelectedServer,
         rng: impl CryptoRng + Rng,
     ) -> Result<()> {
+        let idle_timeout = self.tcp_options.read().await.idle_timeout();
+        match self
+            .connect(selected.address, &selected.host, &selected.url_path)
+            .await?
+        {
+            StreamType::TcpStream(stream) => protocol::process_tunnel(stream, client, target_host, rng, selected, idle_timeout).await,
+            StreamType::UgradeStream(stream) => {
+                protocol::process_tunnel(stream, client, target_host, rng, selected, idle_timeout).await
+            }
+        }
+    }
+
+    /// Tunnel through selected server or direct connection
+    pub(crate) async fn tunnel(
+        &self,
+        client: impl AsyncWriteExt + Unpin + AsyncRead,
+        target_host: String,
+        client_addr: SocketAddr,
+    ) -> Result<()> {
+        let mut rng = ChaCha20Rng::from_entropy();
+        let selected = self.select_server(&target_host, &mut rng, client_addr).await?;
+        if let Some(server) = selected {
+            self.ensure_config_initialized(&server).await;
+            self.start_tunnel_with_server(client, target_host, server, rng).await
+        } else {
+            self.direct_connection(client, target_host).await
+        }
+    }
+
+    /// None if direct connection is selected
+    pub(crate) async fn select_udp_server(&self, target_host: &str, client_addr: SocketAddr) -> Result<Option<SelectedServer>> {
+        let rng = ChaCha20Rng::from_entropy();
+        let selected = self.select_server(target_host, rng, client_addr).await?;
+        if let Some(server) = &selected {
+            self.ensure_config_initialized(server).await;
+        }
+
+        Ok(selected)
+    }
+
+    /// UDP associate through selected server
+    pub(crate) async fn udp_tunnel(
+        &self,
+        control: TcpStream,
+        socket: UdpSocket,
+        client_addr: SocketAddr,
+        selected: SelectedServer,
+    ) -> Result<()> {
+        let rng = ChaCha20Rng::from_entropy();
         match self
             .connect(selected.address, &selected.host, &selected.url_path)
             .await?
         {
-            StreamType::TcpStream(stream) => protocol::process_tunnel(stream, client, target_host, rng, selected).await,
+            StreamType::TcpStream(stream) => protocol::process_udp_tunnel(stream, control, socket, client_addr, rng, selected).await,
             StreamType::UgradeStream(stream) => {
-                protocol::process_tunnel(stream, client, target_host, rng, selected).await
+                protocol::process_udp_tunnel(stream, control, socket, client_addr, rng, selected).await
             }
         }
     }
 
     async fn connect(&self, address: SocketAddr, host: &str, url_path: &Option<String>) -> Result<StreamType> {
-        let server = TcpStream::connect(address).await?;
+        let server = self.tcp_options.read().await.connect(address).await?;
         Ok(if let Some(http_path) = url_path {
             // HTTPS connect
             let host = if let Some(pos) = host.rfind(':') {
@@ -604,7 +680,7 @@ impl Proxy {
             let tls_conn = TlsConnector::from(self.tls_cfg.clone());
             let server = tls_conn.connect(domain, server).await?;
 
-            StreamType::UgradeStream(UgradeStream::from_stream(server, host, http_path))
+            StreamType::UgradeStream(Box::new(UgradeStream::from_stream(server, host, http_path)))
         } else {
             StreamType::TcpStream(server)
         })
@@ -628,13 +704,17 @@ impl Proxy {
         // socket.bind(local_address)?;
 
         // let server = socket.connect(remote_addr).await?;
-        let target_address: SocketAddr = lookup_host(&target_host)
-            .await?
-            .reduce(|acc, val| if acc.is_ipv6() && val.is_ipv4() { val } else { acc })
-            .ok_or_else(|| anyhow!("host {target_host} notfound"))?;
-        let mut server = TcpStream::connect(target_address).await?;
+        let target_addresses: Vec<SocketAddr> = lookup_host(&target_host).await?.collect();
+        if target_addresses.is_empty() {
+            anyhow::bail!("host {target_host} notfound");
+        }
+        let options = *self.tcp_options.read().await;
+        // every attempt has connect_timeout, fallback attempts are not cut off by the first one
+        let timing = Timing { attempt_timeout: options.connect_timeout(), ..Default::default() };
+        let (mut server, _) = happy_eyeballs::connect(target_addresses, timing).await?;
+        options.apply(&server)?;
 
-        tokio::io::copy_bidirectional(&mut client, &mut server).await?;
+        tcp_options::copy_bidirectional(&mut client, &mut server, options.idle_timeout()).await?;
         Ok(())
     }
 }
@@ -646,15 +726,7 @@ async fn start_tunnel(
     client_addr: SocketAddr,
 ) -> Result<()> {
     let client = TokioIo::new(upgraded);
-
-    let mut rng = ChaCha20Rng::from_entropy();
-    let selected = proxy.select_server(&target_host, &mut rng, client_addr).await?;
-    if let Some(server) = selected {
-        proxy.ensure_config_initialized(&server).await;
-        proxy.start_tunnel_with_server(client, target_host, server, rng).await
-    } else {
-        proxy.direct_connection(client, target_host).await
-    }
+    proxy.tunnel(client, target_host, client_addr).await
 }
 
 pub async fn serve_proxy_connection(
@@ -0,0 +1,129 @@
+use std::{io, net::SocketAddr, sync::Arc};
+use anyhow::{bail, Result};
+use bytes::{BufMut, BytesMut};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+use crypto::datagram::DatagramAddr;
+use crate::proxy::Proxy;
+
+// SOCKS5 (RFC 1928), no authentication
+
+const VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_NOT_ALLOWED: u8 = 0x02;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// SOCKS5 and HTTP proxy share the same port
+pub async fn is_socks5(stream: &TcpStream) -> bool {
+    let mut version = [0u8];
+    matches!(stream.peek(&mut version).await, Ok(1) if version[0] == VERSION)
+}
+
+pub async fn serve_connection(mut stream: TcpStream, proxy: Arc<Proxy>, client_addr: SocketAddr) -> Result<()> {
+    // methods
+    let version = stream.read_u8().await?;
+    let methods_len = stream.read_u8().await?;
+    let mut methods = vec![0u8; methods_len as usize];
+    stream.read_exact(&mut methods).await?;
+
+    if version != VERSION || !methods.contains(&NO_AUTH) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        bail!("SOCKS5 no acceptable methods");
+    }
+    stream.write_all(&[VERSION, NO_AUTH]).await?;
+
+    // request
+    let mut request = [0u8; 3];
+    stream.read_exact(&mut request).await?;
+    let [_, cmd, _] = request;
+    let target = read_addr(&mut stream).await?;
+
+    match cmd {
+        CMD_CONNECT => {
+            let local_addr = DatagramAddr::Ip(stream.local_addr()?);
+            reply(&mut stream, REP_SUCCEEDED, &local_addr).await?;
+            proxy.tunnel(stream, target.to_string(), client_addr).await
+        },
+        CMD_UDP_ASSOCIATE => {
+            let target_host = target.to_string();
+            let Some(selected) = proxy.select_udp_server(&target_host, client_addr).await? else {
+                reply(&mut stream, REP_NOT_ALLOWED, &target).await?;
+                bail!("UDP is supported through servers only");
+            };
+
+            // the app sends datagrams to the same ip it used for control connection
+            let socket = UdpSocket::bind(SocketAddr::new(stream.local_addr()?.ip(), 0)).await?;
+            reply(&mut stream, REP_SUCCEEDED, &DatagramAddr::Ip(socket.local_addr()?)).await?;
+
+            proxy.udp_tunnel(stream, socket, client_addr, selected).await
+        },
+        _ => {
+            reply(&mut stream, REP_COMMAND_NOT_SUPPORTED, &target).await?;
+            bail!("SOCKS5 command {cmd} is not supported");
+        },
+    }
+}
+
+/// UDP request header: reserved (2), fragment, address
+/// Returns destination and payload, fragmented datagrams are not supported
+pub fn parse_udp_request(data: &[u8]) -> Option<(DatagramAddr, &[u8])> {
+    if data.len() < 3 || data[2] != 0 {
+        return None;
+    }
+
+    let (addr, size) = DatagramAddr::parse(&data[3..]).ok()??;
+    Some((addr, &data[3 + size..]))
+}
+
+pub fn put_udp_header(buf: &mut BytesMut, addr: &DatagramAddr) -> io::Result<()> {
+    buf.put_u16(0);
+    buf.put_u8(0);
+    addr.put(buf)
+}
+
+async fn read_addr(stream: &mut TcpStream) -> Result<DatagramAddr> {
+    let atyp = stream.read_u8().await?;
+    let addr_len = match atyp {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        _ => bail!("SOCKS5 address type {atyp} is not supported"),
+    };
+
+    let mut data = Vec::with_capacity(addr_len + 4);
+    data.push(atyp);
+    if atyp == ATYP_DOMAIN {
+        data.push(addr_len as u8);
+    }
+    data.resize(data.len() + addr_len + 2, 0);
+
+    let start = data.len() - addr_len - 2;
+    stream.read_exact(&mut data[start..]).await?;
+
+    match DatagramAddr::parse(&data)? {
+        Some((addr, _)) => Ok(addr),
+        None => bail!("SOCKS5 wrong address"),
+    }
+}
+
+async fn reply(stream: &mut TcpStream, rep: u8, addr: &DatagramAddr) -> Result<()> {
+    let mut response = BytesMut::with_capacity(32);
+    response.put_slice(&[VERSION, rep, 0]);
+    addr.put(&mut response)?;
+
+    stream.write_all(&response).await?;
+    stream.flush().await?;
+    Ok(())
+}
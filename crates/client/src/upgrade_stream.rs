@@ -3,24 +3,28 @@ use std::{
 };
 use pin_project_lite::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use websocket::{Role, WsStream};
 
-const MAX_UPGRADE_RESPONSE: usize = u8::MAX as usize;
+// CDN can add a lot of headers
+const MAX_UPGRADE_RESPONSE: usize = 4096;
 
 pin_project! {
     /// A stream wrapper that helps transfer the data through https proxy
+    /// data is sent in WebSocket frames after upgrade request
     pub struct UgradeStream<S> 
     {
         #[pin]
-        inner: S,
+        inner: WsStream<S>,
 
         state: UpgradeState,
         request: String,
+        accept_key: String,
     }
 }
 
 enum UpgradeState {
     SendRequest{pos: usize},
-    WaitResponse{lf_in_row: usize, size: usize},
+    WaitResponse{lf_in_row: usize, response: Vec<u8>},
     Upgraded
 }
 
@@ -33,20 +37,39 @@ where
         host: &str,
         http_path: &str,
     ) -> Self {
+        let key = websocket::generate_key();
         Self { 
-            inner,
+            inner: WsStream::from_stream(inner, Role::Client),
             state: UpgradeState::SendRequest{pos: 0},
             request: format!("\
                 GET /{http_path} HTTP/1.1\r\n\
                 Host: {host}\r\n\
-                Connection: upgrade\r\n\
+                Connection: Upgrade\r\n\
                 Upgrade: websocket\r\n\
+                Sec-WebSocket-Key: {key}\r\n\
+                Sec-WebSocket-Version: {}\r\n\
                 \r\n\
-            "),
+            ", websocket::VERSION),
+            accept_key: websocket::accept_key(&key),
         }
     }
 }
 
+fn check_upgrade_response(response: &[u8], accept_key: &str) -> io::Result<()> {
+    let response = String::from_utf8_lossy(response);
+
+    if response.split_whitespace().nth(1) != Some("101") {
+        let status = response.lines().next().unwrap_or_default();
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("upgrade failed: {status}")));
+    }
+
+    if websocket::find_header(&response, "Sec-WebSocket-Accept") != Some(accept_key) {
+        return Err(io::Error::new(ErrorKind::InvalidData, "wrong Sec-WebSocket-Accept in upgrade response"));
+    }
+
+    Ok(())
+}
+
 impl<S> AsyncRead for UgradeStream<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
@@ -58,13 +81,14 @@ where
     ) -> Poll<io::Result<()>> {
         let mut this = self.as_mut().project();
 
-        if let UpgradeState::WaitResponse{lf_in_row, size} = this.state {
+        if let UpgradeState::WaitResponse{lf_in_row, response} = this.state {
 
             let mut byte = [0u8];
             let mut byte_buff = ReadBuf::new(&mut byte);
 
             while *lf_in_row < 2 {
-                match this.inner.as_mut().poll_read(cx, &mut byte_buff) {
+                // response is not framed
+                match Pin::new(this.inner.as_mut().get_mut().inner_mut()).poll_read(cx, &mut byte_buff) {
                     Poll::Pending => {
                         return Poll::Pending
                     },
@@ -74,8 +98,7 @@ where
                     },                
                 }
 
-                *size += 1;
-                if *size > MAX_UPGRADE_RESPONSE {
+                if response.len() >= MAX_UPGRADE_RESPONSE {
                     return Err(io::Error::new(ErrorKind::InvalidData, "uprade response too big")).into();
                 }
 
@@ -84,6 +107,7 @@ where
                     return Ok(()).into();
                 }
 
+                response.push(filled[0]);
                 if filled[0] == b'\n' {
                     *lf_in_row += 1;
                 } else if filled[0] != b'\r' {
@@ -92,7 +116,8 @@ where
 
                 byte_buff.clear();
             }
-            
+
+            check_upgrade_response(response, this.accept_key)?;
             *this.state = UpgradeState::Upgraded;
         }
 
@@ -112,7 +137,8 @@ where
         let mut this = self.project();
         if let UpgradeState::SendRequest { pos } = this.state {
             while *pos < this.request.len() {
-                match this.inner.as_mut().poll_write(cx, &this.request.as_bytes()[*pos..]) {
+                // request is not framed
+                match Pin::new(this.inner.as_mut().get_mut().inner_mut()).poll_write(cx, &this.request.as_bytes()[*pos..]) {
                     Poll::Pending => {
                         return Poll::Pending;
                     },
@@ -128,7 +154,7 @@ where
                 }
             }
 
-            *this.state = UpgradeState::WaitResponse{lf_in_row:0, size: 0};
+            *this.state = UpgradeState::WaitResponse{lf_in_row: 0, response: Vec::new()};
         }
 
         this.inner.poll_write(cx, buf)
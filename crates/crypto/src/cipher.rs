@@ -13,12 +13,17 @@ mod rustls_crypto;
 #[cfg(feature = "rust_crypto")]
 use rustls_crypto as crypto;
 
-use crypto::{CipherAes256Gcm, CipherChaCha20Poly1305, CipherBase};
+use crypto::{CipherAes256Gcm, CipherAes128Gcm, CipherChaCha20Poly1305, CipherXChaCha20Poly1305, CipherBase};
 pub use crypto::CipherType;
 
+// HChaCha20 input part of XChaCha20 nonce
+const XCHACHA_PREFIX_LEN: usize = 16;
+
 pub enum Cipher {
     Aes256Gcm(CipherAes256Gcm),
-    ChaCha20Poly1305(CipherChaCha20Poly1305)
+    ChaCha20Poly1305(CipherChaCha20Poly1305),
+    XChaCha20Poly1305(CipherXChaCha20Poly1305),
+    Aes128Gcm(CipherAes128Gcm),
 }
 
 impl Cipher {
@@ -27,6 +32,8 @@ impl Cipher {
         match cipher {
             CipherType::Aes256Gcm => Cipher::Aes256Gcm(CipherBase::new(key, rng).into()),
             CipherType::ChaCha20Poly1305 => Cipher::ChaCha20Poly1305(CipherBase::new(key, rng).into()),
+            CipherType::XChaCha20Poly1305 => Cipher::XChaCha20Poly1305(CipherXChaCha20Poly1305::new(key, rng).into()),
+            CipherType::Aes128Gcm => Cipher::Aes128Gcm(CipherBase::new(key, rng).into()),
         }
     }
 
@@ -35,6 +42,8 @@ impl Cipher {
         match cipher {
             CipherType::Aes256Gcm => Cipher::Aes256Gcm(CipherBase::new_with_nonce(key, nonce).into()),
             CipherType::ChaCha20Poly1305 => Cipher::ChaCha20Poly1305(CipherBase::new_with_nonce(key, nonce).into()),
+            CipherType::XChaCha20Poly1305 => Cipher::XChaCha20Poly1305(CipherXChaCha20Poly1305::new_with_nonce(key, nonce).into()),
+            CipherType::Aes128Gcm => Cipher::Aes128Gcm(CipherBase::new_with_nonce(key, nonce).into()),
         }
     }
 
@@ -55,10 +64,30 @@ impl Cipher {
         Ok((client_cipher, server_cipher))
     }
 
+    /// Session ciphers of the key exchange, the secret is mixed into the salt
+    pub fn new_client_server_with_secret(cipher: CipherType, kdf: Kdf, pass: &str, salt: &[u8], secret: &[u8]) -> Result<(Cipher, Cipher)> {
+        if secret.is_empty() {
+            return Self::new_client_server(cipher, kdf, pass, salt);
+        }
+
+        let mut session_salt = BytesMut::zeroed(salt.len());
+        Kdf::mix_session_salt(salt, secret, &mut session_salt)?;
+        Self::new_client_server(cipher, kdf, pass, &session_salt)
+    }
+
+    /// Cipher of the server key share
+    pub fn new_key_exchange(cipher: CipherType, kdf: Kdf, pass: &str, salt: &[u8]) -> Result<Cipher> {
+        let mut key = BytesMut::zeroed(cipher.key_size());
+        kdf.derive_key_exchange_key(pass.as_bytes(), salt, &mut key)?;
+        Ok(Cipher::new_with_nonce(cipher, &key, &salt[0..cipher.nonce_size()]))
+    }
+
     pub fn nonce(&self) -> &[u8] {
         match self {
             Self::Aes256Gcm(c) => c.nonce(),
             Self::ChaCha20Poly1305(c) => c.nonce(),
+            Self::XChaCha20Poly1305(c) => c.nonce(),
+            Self::Aes128Gcm(c) => c.nonce(),
         }
     }
 
@@ -66,6 +95,8 @@ impl Cipher {
         match self {
             Self::Aes256Gcm(c) => c.nonce_size(),
             Self::ChaCha20Poly1305(c) => c.nonce_size(),
+            Self::XChaCha20Poly1305(c) => c.nonce_size(),
+            Self::Aes128Gcm(c) => c.nonce_size(),
         }
     }
 
@@ -73,6 +104,8 @@ impl Cipher {
         match self {
             Self::Aes256Gcm(c) => c.tag_size(),
             Self::ChaCha20Poly1305(c) => c.tag_size(),
+            Self::XChaCha20Poly1305(c) => c.tag_size(),
+            Self::Aes128Gcm(c) => c.tag_size(),
         }
     }
 
@@ -80,6 +113,8 @@ impl Cipher {
         match self {
             Self::Aes256Gcm(c) => c.encrypt(data_buffer, start_pos),
             Self::ChaCha20Poly1305(c) => c.encrypt(data_buffer, start_pos),
+            Self::XChaCha20Poly1305(c) => c.encrypt(data_buffer, start_pos),
+            Self::Aes128Gcm(c) => c.encrypt(data_buffer, start_pos),
         }
     }
 
@@ -87,9 +122,13 @@ impl Cipher {
         match self {
             Self::Aes256Gcm(c) => c.decrypt(data_buffer),
             Self::ChaCha20Poly1305(c) => c.decrypt(data_buffer),
+            Self::XChaCha20Poly1305(c) => c.decrypt(data_buffer),
+            Self::Aes128Gcm(c) => c.decrypt(data_buffer),
         }
     }
 
+    /// XChaCha20 counts in the last 8 bytes, so the random 16 bytes prefix
+    /// (HChaCha20 input) and the subkey derived from it stay the same
     pub fn inc_nonce(&mut self, value: u16) {
         let mut add = value as u32;
         let mut rest = self.nonce_mut();
@@ -106,6 +145,8 @@ impl Cipher {
         match self {
             Self::Aes256Gcm(c) => c.nonce_mut(),
             Self::ChaCha20Poly1305(c) => c.nonce_mut(),
+            Self::XChaCha20Poly1305(c) => &mut c.nonce_mut()[XCHACHA_PREFIX_LEN..],
+            Self::Aes128Gcm(c) => c.nonce_mut(),
         }
     }
 }
@@ -113,8 +154,86 @@ impl Cipher {
 #[cfg(test)]
 mod tests {
 
+    use bytes::BytesMut;
+    use hex_literal::hex;
     use super::{Cipher, CipherType};
 
+    const PLAINTEXT: &[u8] = b"covert connect test vector";
+
+    /// The same for all backends, computed with an independent implementation.
+    /// Key is 0, 1, 2..., nonce is 0x40, 0x41...
+    #[test]
+    fn test_vectors() {
+        let vectors: [(CipherType, &[u8]); 4] = [
+            (CipherType::Aes256Gcm, &hex!("81d6d8465448a760a2aa7953f810332f1cb5267cc1506c571835fa75b23d21635aae5dc5431bdd793d03")),
+            (CipherType::ChaCha20Poly1305, &hex!("9b3b0ae4003ece6237a19865d89b4db9d1bf17383299221b917ecb097af4aed651bcd2d7ef90a567fcf4")),
+            (CipherType::XChaCha20Poly1305, &hex!("b7567315a2945975e09ae9dbcce845e6f7c9d9e4653c30ee0543dc900e1d83cf997ce3c110e6a13751b9")),
+            (CipherType::Aes128Gcm, &hex!("5a461faf1f3fd9d3e3d72d7a9d535d8c290ecd4b6bb7f6ead43283fdf9e5e9664bd5dccaa1f4dea8bada")),
+        ];
+
+        for (cipher_type, expected) in vectors {
+            let key: Vec<u8> = (0..cipher_type.key_size() as u8).collect();
+            let nonce: Vec<u8> = (0x40..0x40 + cipher_type.nonce_size() as u8).collect();
+            let mut cipher = Cipher::new_with_nonce(cipher_type, &key, &nonce);
+
+            let mut data = BytesMut::from(PLAINTEXT);
+            cipher.encrypt(&mut data, 0);
+            assert_eq!(data.as_ref(), expected, "{cipher_type:?}");
+
+            assert!(cipher.decrypt(&mut data), "{cipher_type:?}");
+            assert_eq!(&data[..PLAINTEXT.len()], PLAINTEXT);
+
+            let mut tampered = BytesMut::from(expected);
+            tampered[0] ^= 1;
+            assert!(!cipher.decrypt(&mut tampered), "{cipher_type:?}");
+        }
+    }
+
+    /// The counter is in the last 8 bytes, the vector is computed with an independent implementation
+    #[test]
+    fn inc_nonce_xchacha() {
+        let key: Vec<u8> = (0..32).collect();
+        let nonce: Vec<u8> = (0x40..0x58).collect();
+        let mut cipher = Cipher::new_with_nonce(CipherType::XChaCha20Poly1305, &key, &nonce);
+
+        let mut data = BytesMut::from(PLAINTEXT);
+        cipher.encrypt(&mut data, 0);
+        cipher.inc_nonce(1);
+        assert_eq!(cipher.nonce(), hex!("404142434445464748494a4b4c4d4e4f5151525354555657"));
+
+        let mut data = BytesMut::from(PLAINTEXT);
+        cipher.encrypt(&mut data, 0);
+        assert_eq!(data.as_ref(), hex!("be7cc0638236ffdd7087b24c4e0594833e8e829fb576f9ae04a2959835a7fc464be1c83bc45d445e75da"));
+
+        // the prefix is kept when the counter wraps
+        let mut cipher = Cipher::new_with_nonce(CipherType::XChaCha20Poly1305, &key, &[0xff; 24]);
+        cipher.inc_nonce(1);
+        assert_eq!(cipher.nonce(), [[0xff; 16].as_slice(), &[0; 8]].concat());
+    }
+
+    #[test]
+    fn cipher_sizes() {
+        let sizes = [
+            (CipherType::Aes256Gcm, 32, 12),
+            (CipherType::ChaCha20Poly1305, 32, 12),
+            (CipherType::XChaCha20Poly1305, 32, 24),
+            (CipherType::Aes128Gcm, 16, 12),
+        ];
+
+        for (cipher_type, key_size, nonce_size) in sizes {
+            assert_eq!(cipher_type.key_size(), key_size, "{cipher_type:?}");
+            assert_eq!(cipher_type.nonce_size(), nonce_size, "{cipher_type:?}");
+            assert_eq!(cipher_type.tag_size(), 16, "{cipher_type:?}");
+
+            let cipher = Cipher::new_with_nonce(cipher_type, &vec![0u8; key_size], &vec![0u8; nonce_size]);
+            assert_eq!(cipher.nonce_size(), nonce_size);
+            assert_eq!(cipher.tag_size(), 16);
+
+            // wire value
+            assert_eq!(CipherType::try_from(u8::from(cipher_type)).unwrap(), cipher_type);
+        }
+    }
+
     #[test]
     fn inc_nonce() {
         check_nonce(0, 1);
@@ -143,8 +262,8 @@ mod tests {
         let res = u128::from_le_bytes(res_array);
 
         let mut correct_result = nonce + inc as u128;
-        if correct_result >= ((1 as u128) << 96) {
-            correct_result &= ((1 as u128) << 96) - 1;
+        if correct_result >= (1_u128 << 96) {
+            correct_result &= (1_u128 << 96) - 1;
         }
 
         assert_eq!(res, correct_result);
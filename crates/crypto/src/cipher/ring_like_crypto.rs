@@ -1,43 +1,56 @@
 use rand_core::{CryptoRng, RngCore};
 #[cfg(feature = "aws_lc_rs")]
-use aws_lc_rs::aead::{Aad, Algorithm, Nonce, LessSafeKey, UnboundKey, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN};
+use aws_lc_rs::aead::{Aad, Algorithm, Nonce, LessSafeKey, UnboundKey, AES_128_GCM, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN};
 #[cfg(feature = "ring")]
-use ring::aead::{Aad, Algorithm, Nonce, LessSafeKey, UnboundKey, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN};
+use ring::aead::{Aad, Algorithm, Nonce, LessSafeKey, UnboundKey, AES_128_GCM, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN};
 use serde::{Deserialize, Serialize};
 use bytes::{BufMut, BytesMut};
 use num_enum::{TryFromPrimitive, IntoPrimitive};
 use std::marker::PhantomData;
 
 pub struct Aes256Algorithm;
+pub struct Aes128Algorithm;
 pub struct ChaCha20Poly1305Algorithm;
 
 pub type CipherAes256Gcm = CipherBase<Aes256Algorithm>;
+pub type CipherAes128Gcm = CipherBase<Aes128Algorithm>;
 pub type CipherChaCha20Poly1305 = CipherBase<ChaCha20Poly1305Algorithm>;
 
+const XCHACHA_NONCE_LEN: usize = 24;
+const HCHACHA_INPUT_LEN: usize = 16;
+
+/// Values are sent in get protocol response and temp keys, don't change them
 #[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, TryFromPrimitive, IntoPrimitive)]
 #[repr(u8)]
 pub enum CipherType {
-    Aes256Gcm,
-    ChaCha20Poly1305
+    Aes256Gcm = 0,
+    ChaCha20Poly1305 = 1,
+    XChaCha20Poly1305 = 2,
+    Aes128Gcm = 3,
 }
 
 impl CipherType
 {
     pub fn nonce_size(&self) -> usize {
-        NONCE_LEN
+        match self {
+            Self::XChaCha20Poly1305 => XCHACHA_NONCE_LEN,
+            _ => NONCE_LEN,
+        }
     }
 
     pub fn tag_size(&self) -> usize {
-        match self {
-            Self::Aes256Gcm => AES_256_GCM.tag_len(),
-            Self::ChaCha20Poly1305 => AES_256_GCM.tag_len(),
-        }
+        self.algo().tag_len()
     }
 
     pub fn key_size(&self) -> usize {
+        self.algo().key_len()
+    }
+
+    fn algo(&self) -> &'static Algorithm {
         match self {
-            Self::Aes256Gcm => AES_256_GCM.key_len(),
-            Self::ChaCha20Poly1305 => AES_256_GCM.key_len(),
+            Self::Aes256Gcm => &AES_256_GCM,
+            Self::ChaCha20Poly1305 | Self::XChaCha20Poly1305 => &CHACHA20_POLY1305,
+            Self::Aes128Gcm => &AES_128_GCM,
         }
     }
 }
@@ -118,8 +131,142 @@ impl GetAlgo for Aes256Algorithm {
     }
 }
 
+impl GetAlgo for Aes128Algorithm {
+    fn algo() -> &'static Algorithm {
+        &AES_128_GCM
+    }
+}
+
 impl GetAlgo for ChaCha20Poly1305Algorithm {
     fn algo() -> &'static Algorithm {
         &CHACHA20_POLY1305
     }
 }
+
+/// XChaCha20-Poly1305 (draft-irtf-cfrg-xchacha): ChaCha20-Poly1305 with a subkey derived by HChaCha20
+/// from the first 16 bytes of the nonce, the last 8 bytes go to the nonce.
+/// Not provided by ring and aws-lc-rs.
+pub struct CipherXChaCha20Poly1305 {
+    key: [u8; 32],
+    nonce: [u8; XCHACHA_NONCE_LEN],
+    /// the nonce prefix and its subkey, the prefix stays the same as the counter is in the last 8 bytes
+    subkey: Option<([u8; HCHACHA_INPUT_LEN], LessSafeKey)>,
+}
+
+impl CipherXChaCha20Poly1305 {
+    pub fn new(key: &[u8], mut rng: impl CryptoRng + RngCore) -> Self {
+        let mut nonce = [0u8; XCHACHA_NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+
+        Self::new_with_nonce(key, &nonce)
+    }
+
+    pub fn new_with_nonce(key: &[u8], nonce: &[u8]) -> Self {
+        Self {
+            key: key.try_into().unwrap(),
+            nonce: nonce.try_into().unwrap(),
+            subkey: None,
+        }
+    }
+
+    pub fn nonce(&self) -> &[u8] {
+        &self.nonce
+    }
+
+    pub fn nonce_mut(&mut self) -> &mut [u8] {
+        &mut self.nonce
+    }
+
+    pub fn nonce_size(&self) -> usize {
+        XCHACHA_NONCE_LEN
+    }
+
+    pub fn tag_size(&self) -> usize {
+        CHACHA20_POLY1305.tag_len()
+    }
+
+    pub fn encrypt(&mut self, data_buffer: &mut BytesMut, start_pos: usize) {
+        let (_, data) = data_buffer.split_at_mut(start_pos);
+
+        let (key, nonce) = self.subkey();
+        let tag = key
+            .seal_in_place_separate_tag(nonce, Aad::empty(), data)
+            .unwrap();
+        data_buffer.put(tag.as_ref());
+    }
+
+    pub fn decrypt(&mut self, data_buffer: &mut BytesMut) -> bool {
+        let (key, nonce) = self.subkey();
+        key.open_in_place(nonce, Aad::empty(), data_buffer).is_ok()
+    }
+
+    /// The subkey is derived again only if the nonce prefix is changed
+    fn subkey(&mut self) -> (&LessSafeKey, Nonce) {
+        let (input, rest) = self.nonce.split_at(HCHACHA_INPUT_LEN);
+        let input: [u8; HCHACHA_INPUT_LEN] = input.try_into().unwrap();
+
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[NONCE_LEN - rest.len()..].copy_from_slice(rest);
+
+        if self.subkey.as_ref().is_none_or(|(prefix, _)| *prefix != input) {
+            let subkey = hchacha20(&self.key, &input);
+            let key = LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &subkey).unwrap());
+            self.subkey = Some((input, key));
+        }
+
+        let (_, key) = self.subkey.as_ref().unwrap();
+        (key, Nonce::assume_unique_for_key(nonce))
+    }
+}
+
+fn hchacha20(key: &[u8; 32], input: &[u8; HCHACHA_INPUT_LEN]) -> [u8; 32] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for (word, chunk) in state[4..12].iter_mut().zip(key.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    for (word, chunk) in state[12..].iter_mut().zip(input.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut subkey = [0u8; 32];
+    for (chunk, word) in subkey.chunks_exact_mut(4).zip(state[..4].iter().chain(&state[12..])) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    subkey
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]); state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]); state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]); state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]); state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use hex_literal::hex;
+    use super::hchacha20;
+
+    #[test]
+    fn hchacha20_vector() {
+        // draft-irtf-cfrg-xchacha-03, 2.2.1
+        let key = hex!("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f");
+        let input = hex!("000000090000004a0000000031415927");
+        let subkey = hex!("82413b4227b27bfed30e42508a877d73a0f9e4d58a74a853c12ec41326d3ecdc");
+
+        assert_eq!(hchacha20(&key, &input), subkey);
+    }
+}
@@ -4,16 +4,23 @@ use aead::{
 };
 use serde::{Deserialize, Serialize};
 use bytes::{BufMut, BytesMut};
-use aes_gcm::Aes256Gcm;
-use chacha20poly1305::ChaCha20Poly1305;
+use num_enum::{TryFromPrimitive, IntoPrimitive};
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
 
 pub type CipherAes256Gcm = Box<CipherBase<Aes256Gcm>>;
+pub type CipherAes128Gcm = Box<CipherBase<Aes128Gcm>>;
 pub type CipherChaCha20Poly1305 = CipherBase<ChaCha20Poly1305>;
+pub type CipherXChaCha20Poly1305 = CipherBase<XChaCha20Poly1305>;
 
-#[derive(Clone, Copy, Deserialize, Serialize)]
+/// Values are sent in get protocol response and temp keys, don't change them
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, TryFromPrimitive, IntoPrimitive)]
+#[repr(u8)]
 pub enum CipherType {
-    Aes256Gcm,
-    ChaCha20Poly1305
+    Aes256Gcm = 0,
+    ChaCha20Poly1305 = 1,
+    XChaCha20Poly1305 = 2,
+    Aes128Gcm = 3,
 }
 
 impl CipherType
@@ -22,6 +29,8 @@ impl CipherType
         match self {
             Self::Aes256Gcm => <Aes256Gcm as AeadCore>::NonceSize::to_usize(),
             Self::ChaCha20Poly1305 => <ChaCha20Poly1305 as AeadCore>::NonceSize::to_usize(),
+            Self::XChaCha20Poly1305 => <XChaCha20Poly1305 as AeadCore>::NonceSize::to_usize(),
+            Self::Aes128Gcm => <Aes128Gcm as AeadCore>::NonceSize::to_usize(),
         }
     }
 
@@ -29,6 +38,8 @@ impl CipherType
         match self {
             Self::Aes256Gcm => <Aes256Gcm as AeadCore>::TagSize::to_usize(),
             Self::ChaCha20Poly1305 => <ChaCha20Poly1305 as AeadCore>::TagSize::to_usize(),
+            Self::XChaCha20Poly1305 => <XChaCha20Poly1305 as AeadCore>::TagSize::to_usize(),
+            Self::Aes128Gcm => <Aes128Gcm as AeadCore>::TagSize::to_usize(),
         }
     }
 
@@ -36,6 +47,8 @@ impl CipherType
         match self {
             Self::Aes256Gcm => Aes256Gcm::key_size(),
             Self::ChaCha20Poly1305 => ChaCha20Poly1305::key_size(),
+            Self::XChaCha20Poly1305 => XChaCha20Poly1305::key_size(),
+            Self::Aes128Gcm => Aes128Gcm::key_size(),
         }
     }
 }
@@ -2,8 +2,9 @@ use serde::{de::Error, Deserialize, Serialize, Deserializer, Serializer};
 use std::ops::Range;
 
 use super::{
-    kdf::Kdf,
-    cipher::CipherType
+    kdf::{Kdf, TempKey},
+    cipher::CipherType,
+    key_exchange::KeyExchange,
 };
 
 #[derive(Clone, Copy, PartialEq, Deserialize, Serialize, Debug)]
@@ -47,6 +48,11 @@ pub struct ProtocolConfig {
     /// default is usize::MAX (encrypt all data)
     #[serde(default = "defaut_encryption_limit")]
     pub encryption_limit: usize,
+
+    /// ephemeral key exchange for forward secrecy: None, X25519 or X25519MlKem768
+    /// should be the same for server and client
+    #[serde(default)]
+    pub key_exchange: KeyExchange,
 }
 
 fn default_max_connect_delay() -> u16 {
@@ -99,6 +105,22 @@ where
     Ok(start..end)
 }
 
+impl ProtocolConfig {
+    /// All parameters are included in the temporary key
+    pub fn from_temp_key(key: &str, temp_key: &TempKey) -> Self {
+        Self {
+            key: key.to_owned(),
+            kdf: temp_key.info.kdf,
+            cipher: temp_key.info.cipher,
+            max_connect_delay: temp_key.info.max_connect_delay,
+            header_padding: temp_key.info.header_padding.clone(),
+            data_padding: temp_key.info.data_padding,
+            encryption_limit: temp_key.info.encryption_limit,
+            key_exchange: temp_key.info.key_exchange,
+        }
+    }
+}
+
 impl DataPadding {
     pub fn needed(&self) -> bool {
         self.max > 0 && self.rate > 0
@@ -0,0 +1,205 @@
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use super::MIN_HOST_LEN;
+
+/// Command is sent in the host field of the header.
+/// Host name always starts with a printable char, thus plain host means TCP connect
+/// and servers without commands support just fail to resolve it.
+pub const CMD_UDP_ASSOCIATE: u8 = 0x01;
+pub const UDP_ASSOCIATE_HOST: [u8; MIN_HOST_LEN] = [CMD_UDP_ASSOCIATE, 0, 0, 0];
+
+pub const MAX_DATAGRAM_SIZE: usize = 0xFFFF;
+
+// address types (the same as in SOCKS5)
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Datagram source or destination
+#[derive(Clone, Debug, PartialEq)]
+pub enum DatagramAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl DatagramAddr {
+    /// SOCKS5 format: type, address, port
+    /// Fails if the domain is longer than 255 bytes, nothing is written then
+    pub fn put(&self, buf: &mut BytesMut) -> io::Result<()> {
+        match self {
+            Self::Ip(SocketAddr::V4(addr)) => {
+                buf.put_u8(ATYP_IPV4);
+                buf.put_slice(&addr.ip().octets());
+                buf.put_u16(addr.port());
+            },
+            Self::Ip(SocketAddr::V6(addr)) => {
+                buf.put_u8(ATYP_IPV6);
+                buf.put_slice(&addr.ip().octets());
+                buf.put_u16(addr.port());
+            },
+            Self::Domain(domain, port) => {
+                let len = u8::try_from(domain.len())
+                    .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "domain is longer than 255 bytes"))?;
+                buf.put_u8(ATYP_DOMAIN);
+                buf.put_u8(len);
+                buf.put_slice(domain.as_bytes());
+                buf.put_u16(*port);
+            },
+        }
+        Ok(())
+    }
+
+    /// Returns address and its size, None if there is not enough data
+    pub fn parse(data: &[u8]) -> io::Result<Option<(Self, usize)>> {
+        let Some(&atyp) = data.first() else {
+            return Ok(None);
+        };
+
+        let addr_len = match atyp {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => match data.get(1) {
+                Some(&len) => 1 + len as usize,
+                None => return Ok(None),
+            },
+            _ => return Err(io::Error::new(ErrorKind::InvalidData, "unknown address type")),
+        };
+
+        let size = 1 + addr_len + 2;
+        if data.len() < size {
+            return Ok(None);
+        }
+
+        let addr = &data[1..1 + addr_len];
+        let port = u16::from_be_bytes([data[size - 2], data[size - 1]]);
+
+        let addr = match atyp {
+            ATYP_IPV4 => {
+                let ip: [u8; 4] = addr.try_into().unwrap();
+                Self::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port))
+            },
+            ATYP_IPV6 => {
+                let ip: [u8; 16] = addr.try_into().unwrap();
+                Self::Ip(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port))
+            },
+            _ => {
+                let domain = std::str::from_utf8(&addr[1..])
+                    .map_err(|_| io::Error::new(ErrorKind::InvalidData, "wrong domain"))?;
+                Self::Domain(domain.to_owned(), port)
+            },
+        };
+
+        Ok(Some((addr, size)))
+    }
+}
+
+impl From<SocketAddr> for DatagramAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Ip(addr)
+    }
+}
+
+/// host:port, can be used in lookup_host
+impl fmt::Display for DatagramAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(addr) => write!(f, "{addr}"),
+            Self::Domain(domain, port) => write!(f, "{domain}:{port}"),
+        }
+    }
+}
+
+/// Datagram inside the encrypted stream: address, payload len (u16), payload
+pub fn put_datagram(buf: &mut BytesMut, addr: &DatagramAddr, payload: &[u8]) -> io::Result<()> {
+    addr.put(buf)?;
+    buf.put_u16(payload.len() as u16);
+    buf.put_slice(payload);
+    Ok(())
+}
+
+/// Reads datagrams from the stream
+#[derive(Default)]
+pub struct DatagramReader {
+    buffer: BytesMut,
+}
+
+impl DatagramReader {
+    /// Cancel safe, returns None on EOF
+    pub async fn read<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> io::Result<Option<(DatagramAddr, BytesMut)>> {
+        loop {
+            if let Some((addr, addr_size)) = DatagramAddr::parse(&self.buffer)?
+                && self.buffer.len() >= addr_size + 2
+            {
+                let size = u16::from_be_bytes([self.buffer[addr_size], self.buffer[addr_size + 1]]) as usize;
+                if self.buffer.len() >= addr_size + 2 + size {
+                    self.buffer.advance(addr_size + 2);
+                    return Ok(Some((addr, self.buffer.split_to(size))));
+                }
+            }
+
+            self.buffer.reserve(MAX_DATAGRAM_SIZE);
+            if stream.read_buf(&mut self.buffer).await? == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{put_datagram, DatagramAddr, DatagramReader};
+    use bytes::BytesMut;
+
+    #[tokio::test]
+    async fn datagrams() {
+        let datagrams = [
+            (DatagramAddr::Ip("1.2.3.4:53".parse().unwrap()), vec![1u8; 100]),
+            (DatagramAddr::Ip("[2001:db8::1]:443".parse().unwrap()), vec![]),
+            (DatagramAddr::Domain("example.com".to_owned(), 8080), vec![2u8; 0xFFFF]),
+        ];
+
+        let mut data = BytesMut::new();
+        for (addr, payload) in &datagrams {
+            put_datagram(&mut data, addr, payload).unwrap();
+        }
+
+        // small reads
+        let (mut client, mut server) = tokio::io::duplex(7);
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            client.write_all(&data).await.unwrap();
+        });
+
+        let mut reader = DatagramReader::default();
+        for (addr, payload) in &datagrams {
+            let (read_addr, read_payload) = reader.read(&mut server).await.unwrap().unwrap();
+            assert_eq!(&read_addr, addr);
+            assert_eq!(read_payload.as_ref(), payload.as_slice());
+        }
+
+        assert!(reader.read(&mut server).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn long_domain() {
+        let mut data = BytesMut::new();
+        put_datagram(&mut data, &DatagramAddr::Domain("a".repeat(255), 53), &[1]).unwrap();
+        let (addr, size) = DatagramAddr::parse(&data).unwrap().unwrap();
+        assert_eq!(addr, DatagramAddr::Domain("a".repeat(255), 53));
+        assert_eq!(size, 1 + 1 + 255 + 2);
+
+        // the length doesn't fit u8, nothing is written
+        let mut data = BytesMut::new();
+        let err = put_datagram(&mut data, &DatagramAddr::Domain("a".repeat(256), 53), &[1]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(data.is_empty());
+    }
+}
@@ -7,6 +7,9 @@ use num_enum::{TryFromPrimitive, IntoPrimitive};
 use rand::prelude::*;
 use rand_chacha::ChaCha20Rng;
 use hex_literal::hex;
+use bytes::{Buf, BufMut, BytesMut};
+use std::ops::Range;
+use crate::{cipher::{Cipher, CipherType}, config::{DataPadding, ProtocolConfig}, key_exchange::KeyExchange};
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, TryFromPrimitive, IntoPrimitive)]
@@ -22,6 +25,205 @@ const SERVER_SALT: &[u8;KEY_LEN] = &hex!("b67c9161f48f1aa8cea536ee2a733ad7b72d24
 const CLIENT_SALT: &[u8;KEY_LEN] = &hex!("d182a1c62e0008bacb12d22ea14738b7eb997faa56f0f08a4270f1d19fcf87e3");
 const HTTPS_PATH_SALT: &[u8;KEY_LEN] = &hex!("c08d712e6ba79cdeb83769f3bc9cd7ee6a2777e11beb3b96691fad255dad12b8");
 const PROTOCOL_RESPONSE_SALT: &[u8;KEY_LEN] = &hex!("3368714db61844018dbb0cd7214425800c1d87ea9ae6edeb97e5bd5d462c3808");
+const TEMP_HEADER_SALT: &[u8;KEY_LEN] = &hex!("7e0b5a3f2d9c41e88a6f13c5b2d74e90f1a83c6d5e2b9074c8f61d3a0e5b7c29");
+const TEMP_SEAL_SALT: &[u8;KEY_LEN] = &hex!("a41f6c08d3e95b27f0c2a8e1b7d4963f5e0a2c8b1d7f4e6a9c3b5d0e8f2a1c47");
+const TEMP_KEY_SALT: &[u8;KEY_LEN] = &hex!("2c9e47b1f8a3d60e5b7c1a9f4e2d8b6c0a3f5e7d9b1c4a6e8f0d2b5c7a9e1f30");
+const KEY_EXCHANGE_SALT: &[u8;KEY_LEN] = &hex!("93d1e6a7c04b2f58e1a9d73c6b0f4e25a8c17d3e9f6b2a04c5e8d1f7b3a69c02");
+const SESSION_SALT: &[u8;KEY_LEN] = &hex!("4f7a2c9e1b5d83f6a0e4c7b29d1f5a38e6c0b4d7f2a9e15c83b6d0f4a7e2c91b");
+
+const TEMP_KEY_VERSION: u8 = 1;
+const TEMP_KEY_INFO_LEN: usize = 46;
+const TEMP_KEY_SEAL_CIPHER: CipherType = CipherType::ChaCha20Poly1305;
+pub const MAX_TEMP_KEY_IDENTITY_LEN: usize = 16;
+/// nonce, sealed info and tag
+pub const TEMP_KEY_TOKEN_LEN: usize = 12 + TEMP_KEY_INFO_LEN + 16;
+const TEMP_KEY_LEN: usize = TEMP_KEY_INFO_LEN + KEY_LEN + TEMP_KEY_TOKEN_LEN + KEY_LEN;
+
+/// What a temporary key allows, it's included in the key itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct TempKeyInfo {
+    pub kdf: Kdf,
+    pub cipher: CipherType,
+    pub max_connect_delay: u16,
+    pub key_exchange: KeyExchange,
+    /// stream framing parameters, the client doesn't request them
+    pub header_padding: Range<u16>,
+    pub data_padding: DataPadding,
+    pub encryption_limit: usize,
+    /// unix time (sec)
+    pub expires: i64,
+    /// guest name for logs, empty if not set
+    pub identity: String,
+}
+
+/// Temporary key derived from the master key, the server validates it without storing.
+///
+/// header_key is the same for all temporary keys of the master key, it encrypts the header
+/// (like a regular key) and the header starts with the token, so the server can restore
+/// the info and the key from the token and the master key.
+///
+/// Any holder of a temporary key can decrypt headers of other holders (destination host and token)
+/// and send a header with their token, which is accepted and logged as the other identity.
+/// The data is still protected, the data key of the token is not known to other holders.
+/// Temporary keys should be given only to parties that trust each other
+#[derive(Debug, Clone)]
+pub struct TempKey {
+    pub info: TempKeyInfo,
+    pub header_key: String,
+    /// info sealed with the master key
+    pub token: Vec<u8>,
+    /// data key, used instead of the master key
+    pub key: String,
+}
+
+impl TempKeyInfo {
+    /// Protocol parameters are taken from the server config
+    pub fn new(protocol: &ProtocolConfig, expires: i64, identity: &str) -> Self {
+        Self {
+            kdf: protocol.kdf,
+            cipher: protocol.cipher,
+            max_connect_delay: protocol.max_connect_delay,
+            key_exchange: protocol.key_exchange,
+            header_padding: protocol.header_padding.clone(),
+            data_padding: protocol.data_padding,
+            encryption_limit: protocol.encryption_limit,
+            expires,
+            identity: identity.to_owned(),
+        }
+    }
+
+    /// The key is issued for the same protocol parameters
+    pub fn matches(&self, protocol: &ProtocolConfig) -> bool {
+        self.kdf == protocol.kdf
+            && self.cipher == protocol.cipher
+            && self.max_connect_delay == protocol.max_connect_delay
+            && self.key_exchange == protocol.key_exchange
+            && self.header_padding == protocol.header_padding
+            && self.data_padding == protocol.data_padding
+            && self.encryption_limit == protocol.encryption_limit
+    }
+
+    fn encode(&self, out: &mut BytesMut) -> Result<()> {
+        let identity = self.identity.as_bytes();
+        if identity.len() > MAX_TEMP_KEY_IDENTITY_LEN {
+            anyhow::bail!("identity is longer than {MAX_TEMP_KEY_IDENTITY_LEN} bytes");
+        }
+
+        out.put_u8(TEMP_KEY_VERSION);
+        out.put_u8(self.kdf.into());
+        out.put_u8(self.cipher.into());
+        out.put_u16(self.max_connect_delay);
+        out.put_u8(self.key_exchange.into());
+        out.put_u16(self.header_padding.start);
+        out.put_u16(self.header_padding.end);
+        out.put_u16(self.data_padding.max);
+        out.put_u8(self.data_padding.rate);
+        out.put_u64(self.encryption_limit as u64);
+        out.put_i64(self.expires);
+        out.put_u8(identity.len() as u8);
+        out.put(identity);
+        out.put_bytes(0, MAX_TEMP_KEY_IDENTITY_LEN - identity.len());
+        Ok(())
+    }
+
+    fn decode(mut data: &[u8]) -> Result<Self> {
+        if data.len() != TEMP_KEY_INFO_LEN || data.get_u8() != TEMP_KEY_VERSION {
+            anyhow::bail!("unknown temp key version");
+        }
+
+        let kdf = Kdf::try_from(data.get_u8())?;
+        let cipher = CipherType::try_from(data.get_u8())?;
+        let max_connect_delay = data.get_u16();
+        let key_exchange = KeyExchange::try_from(data.get_u8())?;
+        let header_padding = data.get_u16()..data.get_u16();
+        let data_padding = DataPadding { max: data.get_u16(), rate: data.get_u8() };
+        let encryption_limit = data.get_u64() as usize;
+        let expires = data.get_i64();
+        let identity_len = (data.get_u8() as usize).min(MAX_TEMP_KEY_IDENTITY_LEN);
+        let identity = String::from_utf8(data[..identity_len].to_vec())?;
+
+        Ok(Self {
+            kdf,
+            cipher,
+            max_connect_delay,
+            key_exchange,
+            header_padding,
+            data_padding,
+            encryption_limit,
+            expires,
+            identity,
+        })
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        now >= self.expires
+    }
+}
+
+impl TempKey {
+    /// None if it's a regular key
+    pub fn parse(key: &str) -> Option<Self> {
+        let data = URL_SAFE_NO_PAD.decode(key).ok()?;
+        if data.len() != TEMP_KEY_LEN {
+            return None;
+        }
+
+        let (info, rest) = data.split_at(TEMP_KEY_INFO_LEN);
+        let (header_key, rest) = rest.split_at(KEY_LEN);
+        let (token, data_key) = rest.split_at(TEMP_KEY_TOKEN_LEN);
+
+        Some(Self {
+            info: TempKeyInfo::decode(info).ok()?,
+            header_key: URL_SAFE_NO_PAD.encode(header_key),
+            token: token.to_vec(),
+            key: URL_SAFE_NO_PAD.encode(data_key),
+        })
+    }
+
+    /// The header key of all temporary keys of the master key, see `TempKey` for the limits
+    pub fn header_key(master_key: &str) -> Result<String> {
+        let mut header_key = [0u8; KEY_LEN];
+        Kdf::Blake3.derive_key(master_key.as_bytes(), TEMP_HEADER_SALT, &mut header_key)?;
+        Ok(URL_SAFE_NO_PAD.encode(header_key))
+    }
+
+    /// Validates the token from the header, the key is restored from the token.
+    /// Expiration is not checked
+    pub fn open(master_key: &str, token: &[u8]) -> Result<Self> {
+        if token.len() != TEMP_KEY_TOKEN_LEN {
+            anyhow::bail!("wrong temp key token size");
+        }
+
+        let cipher_type = TEMP_KEY_SEAL_CIPHER;
+        let (nonce, sealed) = token.split_at(cipher_type.nonce_size());
+
+        let mut cipher = Cipher::new_with_nonce(cipher_type, &seal_key(master_key)?, nonce);
+        let mut info = BytesMut::from(sealed);
+        if !cipher.decrypt(&mut info) {
+            anyhow::bail!("temp key token is not valid");
+        }
+        info.truncate(info.len() - cipher_type.tag_size());
+
+        Ok(Self {
+            info: TempKeyInfo::decode(&info)?,
+            header_key: Self::header_key(master_key)?,
+            token: token.to_vec(),
+            key: data_key(master_key, token)?,
+        })
+    }
+}
+
+fn seal_key(master_key: &str) -> Result<[u8; KEY_LEN]> {
+    let mut seal_key = [0u8; KEY_LEN];
+    Kdf::Blake3.derive_key(master_key.as_bytes(), TEMP_SEAL_SALT, &mut seal_key)?;
+    Ok(seal_key)
+}
+
+fn data_key(master_key: &str, token: &[u8]) -> Result<String> {
+    let mut data_key = [0u8; KEY_LEN];
+    Kdf::Blake3.derive_key2(master_key.as_bytes(), token, TEMP_KEY_SALT, &mut data_key)?;
+    Ok(URL_SAFE_NO_PAD.encode(data_key))
+}
 
 impl Kdf {
     // u16 mean 65s+ max, that should be more than enough (default is 10000ms)
@@ -47,7 +249,8 @@ impl Kdf {
                 let mut hasher = blake3::Hasher::new();
                 hasher.update(key);
                 hasher.update(salt);
-                out.copy_from_slice(hasher.finalize().as_bytes());
+                // any key size, the first 32 bytes are the same as the default hash
+                hasher.finalize_xof().fill(out);
                 Ok(())
             }
         }
@@ -61,11 +264,26 @@ impl Kdf {
         self.derive_key2(key, salt, CLIENT_SALT, out)
     }
 
+    /// Key of the server key share, the session keys are not known before the exchange
+    pub fn derive_key_exchange_key(&self, key: &[u8], salt: &[u8], out: &mut [u8]) -> Result<()> {
+        self.derive_key2(key, salt, KEY_EXCHANGE_SALT, out)
+    }
+
+    /// The key exchange secret is mixed into the salt of the session keys,
+    /// the secret is random, so a fast hash is enough
+    pub fn mix_session_salt(salt: &[u8], secret: &[u8], out: &mut [u8]) -> Result<()> {
+        Kdf::Blake3.derive_key2(secret, salt, SESSION_SALT, out)
+    }
+
     pub fn derive_protocol_response_key(&self, key: &[u8], salt: &[u8], out: &mut [u8]) -> Result<()> {
         self.derive_key2(key, salt, PROTOCOL_RESPONSE_SALT, out)
     }
 
+    /// All temporary keys of the master key have the same url path
     pub fn derive_url_path(key: &str) -> Result<String> {
+        let temp_key = TempKey::parse(key);
+        let key = temp_key.as_ref().map_or(key, |temp_key| &temp_key.header_key);
+
         // use Aragon since this used only once per start
         let mut url_path_data = [0u8; KEY_LEN];
         Kdf::Argon2.derive_key(key.as_bytes(), HTTPS_PATH_SALT, &mut url_path_data)?;
@@ -82,9 +300,122 @@ impl Kdf {
         URL_SAFE_NO_PAD.encode(key_data)
     }
 
+    /// Temporary key that is accepted by the server with the master key until it expires
+    pub fn generate_temp_key(master_key: &str, info: &TempKeyInfo) -> Result<String> {
+        let cipher_type = TEMP_KEY_SEAL_CIPHER;
+        let mut rng = ChaCha20Rng::from_entropy();
+
+        // the info is sealed, so it can't be changed by the key holder
+        let mut token = BytesMut::with_capacity(TEMP_KEY_TOKEN_LEN);
+        let mut cipher = Cipher::new(cipher_type, &seal_key(master_key)?, &mut rng);
+        token.put(cipher.nonce());
+        info.encode(&mut token)?;
+        cipher.encrypt(&mut token, cipher_type.nonce_size());
+
+        let mut key = BytesMut::with_capacity(TEMP_KEY_LEN);
+        info.encode(&mut key)?;
+        key.put(URL_SAFE_NO_PAD.decode(TempKey::header_key(master_key)?)?.as_slice());
+        key.put(token.as_ref());
+        key.put(URL_SAFE_NO_PAD.decode(data_key(master_key, &token)?)?.as_slice());
+
+        Ok(URL_SAFE_NO_PAD.encode(key))
+    }
+
     fn derive_key2(&self, key: &[u8], salt1: &[u8], salt2: &[u8], out: &mut [u8]) -> Result<()> {
         let mut salt = [0u8;32];
         self.derive_key(salt1, salt2, &mut salt)?;
         self.derive_key(key, &salt, out)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+
+    use super::{Kdf, TempKey, TempKeyInfo, MAX_TEMP_KEY_IDENTITY_LEN};
+    use crate::{cipher::CipherType, config::{DataPadding, ProtocolConfig}, key_exchange::KeyExchange};
+
+    fn info() -> TempKeyInfo {
+        TempKeyInfo {
+            kdf: Kdf::Blake3,
+            cipher: CipherType::ChaCha20Poly1305,
+            max_connect_delay: 10000,
+            key_exchange: KeyExchange::X25519,
+            header_padding: 50..777,
+            data_padding: DataPadding { max: 250, rate: 10 },
+            encryption_limit: 1024,
+            expires: 1_900_000_000,
+            identity: "guest".to_owned(),
+        }
+    }
+
+    #[test]
+    fn temp_key() {
+        let master = Kdf::generate_new_key();
+        let key = Kdf::generate_temp_key(&master, &info()).unwrap();
+
+        // client side
+        let temp = TempKey::parse(&key).unwrap();
+        assert_eq!(temp.info, info());
+        assert_eq!(temp.header_key, TempKey::header_key(&master).unwrap());
+
+        // server side
+        let opened = TempKey::open(&master, &temp.token).unwrap();
+        assert_eq!(opened.info, info());
+        assert_eq!(opened.key, temp.key);
+        assert!(!opened.info.is_expired(1_899_999_999));
+        assert!(opened.info.is_expired(1_900_000_000));
+
+        // all temporary keys have the same url path
+        let other = Kdf::generate_temp_key(&master, &TempKeyInfo { identity: String::new(), ..info() }).unwrap();
+        assert_ne!(TempKey::parse(&other).unwrap().key, temp.key);
+        assert_eq!(Kdf::derive_url_path(&other).unwrap(), Kdf::derive_url_path(&key).unwrap());
+        assert_ne!(Kdf::derive_url_path(&master).unwrap(), Kdf::derive_url_path(&key).unwrap());
+    }
+
+    #[test]
+    fn temp_key_forged() {
+        let master = Kdf::generate_new_key();
+        let temp = TempKey::parse(&Kdf::generate_temp_key(&master, &info()).unwrap()).unwrap();
+
+        // another master key
+        assert!(TempKey::open(&Kdf::generate_new_key(), &temp.token).is_err());
+
+        // changed info (e.g. expiration)
+        let mut token = temp.token.clone();
+        token[20] ^= 1;
+        assert!(TempKey::open(&master, &token).is_err());
+        assert!(TempKey::open(&master, &temp.token[1..]).is_err());
+
+        // regular keys are not temporary
+        assert!(TempKey::parse(&master).is_none());
+
+        let identity = "a".repeat(MAX_TEMP_KEY_IDENTITY_LEN + 1);
+        assert!(Kdf::generate_temp_key(&master, &TempKeyInfo { identity, ..info() }).is_err());
+    }
+
+    #[test]
+    fn temp_key_protocol() {
+        let info = info();
+        let protocol = ProtocolConfig {
+            key: Kdf::generate_new_key(),
+            kdf: info.kdf,
+            cipher: info.cipher,
+            max_connect_delay: info.max_connect_delay,
+            header_padding: info.header_padding.clone(),
+            data_padding: info.data_padding,
+            encryption_limit: info.encryption_limit,
+            key_exchange: info.key_exchange,
+        };
+        assert_eq!(TempKeyInfo::new(&protocol, info.expires, &info.identity), info);
+        assert!(info.matches(&protocol));
+
+        // the client framing depends on these values
+        assert!(!info.matches(&ProtocolConfig { header_padding: 50..778, ..protocol.clone() }));
+        assert!(!info.matches(&ProtocolConfig { data_padding: DataPadding { max: 250, rate: 20 }, ..protocol.clone() }));
+        assert!(!info.matches(&ProtocolConfig { encryption_limit: usize::MAX, ..protocol.clone() }));
+
+        let key = Kdf::generate_temp_key(&protocol.key, &info).unwrap();
+        let temp = TempKey::parse(&key).unwrap();
+        let client_protocol = ProtocolConfig::from_temp_key(&key, &temp);
+        assert_eq!(ProtocolConfig { key: key.clone(), ..protocol }, client_protocol);
+    }
+}
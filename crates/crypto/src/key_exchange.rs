@@ -0,0 +1,268 @@
+use serde::{Deserialize, Serialize};
+use anyhow::{anyhow, Result};
+use num_enum::{TryFromPrimitive, IntoPrimitive};
+
+#[cfg(feature = "aws_lc_rs")]
+use aws_lc_rs::{
+    agreement::{agree_ephemeral, EphemeralPrivateKey, UnparsedPublicKey, X25519},
+    kem::{Ciphertext, DecapsulationKey, EncapsulationKey, ML_KEM_768},
+    rand::SystemRandom,
+};
+#[cfg(feature = "ring")]
+use ring::{
+    agreement::{agree_ephemeral, EphemeralPrivateKey, UnparsedPublicKey, X25519},
+    rand::SystemRandom,
+};
+#[cfg(feature = "rust_crypto")]
+use x25519_dalek::{EphemeralSecret, PublicKey};
+#[cfg(feature = "rust_crypto")]
+use rand::SeedableRng;
+
+const X25519_KEY_LEN: usize = 32;
+const ML_KEM_768_ENCAPSULATION_KEY_LEN: usize = 1184;
+const ML_KEM_768_CIPHERTEXT_LEN: usize = 1088;
+
+/// Ephemeral key exchange in the tunnel handshake.
+/// Session keys are derived from the master key mixed with the exchanged secret,
+/// so the recorded traffic can't be decrypted if the master key leaks later.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize, TryFromPrimitive, IntoPrimitive)]
+pub enum KeyExchange {
+    /// session keys depend on the master key and the salt only
+    #[default]
+    None,
+    X25519,
+    /// X25519 hybridised with post-quantum ML-KEM-768, aws_lc_rs crypto only
+    X25519MlKem768,
+}
+
+impl KeyExchange {
+    pub fn enabled(&self) -> bool {
+        *self != Self::None
+    }
+
+    /// Client public keys, sent after the host in the header
+    pub fn client_share_size(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::X25519 => X25519_KEY_LEN,
+            Self::X25519MlKem768 => X25519_KEY_LEN + ML_KEM_768_ENCAPSULATION_KEY_LEN,
+        }
+    }
+
+    /// Server public key and ML-KEM ciphertext, sent before the data
+    pub fn server_share_size(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::X25519 => X25519_KEY_LEN,
+            Self::X25519MlKem768 => X25519_KEY_LEN + ML_KEM_768_CIPHERTEXT_LEN,
+        }
+    }
+
+    pub fn is_supported(&self) -> bool {
+        *self != Self::X25519MlKem768 || cfg!(feature = "aws_lc_rs")
+    }
+
+    pub fn check_supported(&self) -> Result<()> {
+        if !self.is_supported() {
+            anyhow::bail!("{self:?} key exchange is not supported by this build, it requires aws_lc_rs crypto");
+        }
+        Ok(())
+    }
+}
+
+/// Client side of the exchange: the share is sent in the header,
+/// the secret is computed from the server share
+pub struct ClientKeyExchange {
+    x25519: Option<X25519Secret>,
+    #[cfg(feature = "aws_lc_rs")]
+    ml_kem: Option<DecapsulationKey>,
+    share: Vec<u8>,
+}
+
+impl ClientKeyExchange {
+    pub fn new(kind: KeyExchange) -> Result<Self> {
+        kind.check_supported()?;
+
+        let mut share = Vec::with_capacity(kind.client_share_size());
+        let x25519 = match kind {
+            KeyExchange::None => None,
+            _ => {
+                let (secret, public) = X25519Secret::generate()?;
+                share.extend_from_slice(&public);
+                Some(secret)
+            },
+        };
+
+        #[cfg(feature = "aws_lc_rs")]
+        let ml_kem = match kind {
+            KeyExchange::X25519MlKem768 => {
+                let key = DecapsulationKey::generate(&ML_KEM_768)
+                    .map_err(|_| anyhow!("can't generate ML-KEM key"))?;
+                let encapsulation_key = key.encapsulation_key()
+                    .and_then(|key| key.key_bytes())
+                    .map_err(|_| anyhow!("can't get ML-KEM encapsulation key"))?;
+                share.extend_from_slice(encapsulation_key.as_ref());
+                Some(key)
+            },
+            _ => None,
+        };
+
+        Ok(Self {
+            x25519,
+            #[cfg(feature = "aws_lc_rs")]
+            ml_kem,
+            share,
+        })
+    }
+
+    pub fn share(&self) -> &[u8] {
+        &self.share
+    }
+
+    /// Returns the shared secret, empty if the exchange is disabled
+    pub fn finish(self, server_share: &[u8]) -> Result<Vec<u8>> {
+        let Some(x25519) = self.x25519 else {
+            return Ok(Vec::new());
+        };
+
+        #[cfg_attr(not(feature = "aws_lc_rs"), allow(unused_variables))]
+        let (public, ciphertext) = server_share.split_at_checked(X25519_KEY_LEN)
+            .ok_or_else(|| anyhow!("server key share is too short"))?;
+        #[cfg_attr(not(feature = "aws_lc_rs"), allow(unused_mut))]
+        let mut secret = x25519.agree(public)?;
+
+        #[cfg(feature = "aws_lc_rs")]
+        if let Some(ml_kem) = self.ml_kem {
+            if ciphertext.len() != ML_KEM_768_CIPHERTEXT_LEN {
+                anyhow::bail!("wrong ML-KEM ciphertext size");
+            }
+            let shared = ml_kem.decapsulate(Ciphertext::from(ciphertext))
+                .map_err(|_| anyhow!("ML-KEM decapsulation failed"))?;
+            secret.extend_from_slice(shared.as_ref());
+        }
+
+        Ok(secret)
+    }
+}
+
+/// Server side of the exchange, returns the server share and the shared secret
+pub fn server_key_exchange(kind: KeyExchange, client_share: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    kind.check_supported()?;
+    if client_share.len() != kind.client_share_size() {
+        anyhow::bail!("wrong client key share size");
+    }
+    if !kind.enabled() {
+        return Ok((Vec::new(), Vec::new()));
+    }
+
+    #[cfg_attr(not(feature = "aws_lc_rs"), allow(unused_variables))]
+    let (client_public, encapsulation_key) = client_share.split_at(X25519_KEY_LEN);
+    let (x25519, public) = X25519Secret::generate()?;
+
+    let mut share = Vec::with_capacity(kind.server_share_size());
+    share.extend_from_slice(&public);
+    #[cfg_attr(not(feature = "aws_lc_rs"), allow(unused_mut))]
+    let mut secret = x25519.agree(client_public)?;
+
+    #[cfg(feature = "aws_lc_rs")]
+    if kind == KeyExchange::X25519MlKem768 {
+        let (ciphertext, shared) = EncapsulationKey::new(&ML_KEM_768, encapsulation_key)
+            .map_err(|_| anyhow!("wrong ML-KEM encapsulation key"))?
+            .encapsulate()
+            .map_err(|_| anyhow!("ML-KEM encapsulation failed"))?;
+        share.extend_from_slice(ciphertext.as_ref());
+        secret.extend_from_slice(shared.as_ref());
+    }
+
+    Ok((share, secret))
+}
+
+#[cfg(any(feature = "aws_lc_rs", feature = "ring"))]
+struct X25519Secret(EphemeralPrivateKey);
+
+#[cfg(any(feature = "aws_lc_rs", feature = "ring"))]
+impl X25519Secret {
+    fn generate() -> Result<(Self, Vec<u8>)> {
+        let key = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+            .map_err(|_| anyhow!("can't generate X25519 key"))?;
+        let public = key.compute_public_key()
+            .map_err(|_| anyhow!("can't compute X25519 public key"))?;
+        Ok((Self(key), public.as_ref().to_vec()))
+    }
+
+    fn agree(self, peer: &[u8]) -> Result<Vec<u8>> {
+        let peer = UnparsedPublicKey::new(&X25519, peer);
+
+        #[cfg(feature = "aws_lc_rs")]
+        let secret = agree_ephemeral(self.0, peer, anyhow!("X25519 key agreement failed"), |secret| Ok(secret.to_vec()));
+        #[cfg(feature = "ring")]
+        let secret = agree_ephemeral(self.0, &peer, |secret| secret.to_vec())
+            .map_err(|_| anyhow!("X25519 key agreement failed"));
+
+        secret
+    }
+}
+
+#[cfg(feature = "rust_crypto")]
+struct X25519Secret(EphemeralSecret);
+
+#[cfg(feature = "rust_crypto")]
+impl X25519Secret {
+    fn generate() -> Result<(Self, Vec<u8>)> {
+        let key = EphemeralSecret::random_from_rng(rand_chacha::ChaCha20Rng::from_entropy());
+        let public = PublicKey::from(&key);
+        Ok((Self(key), public.as_bytes().to_vec()))
+    }
+
+    fn agree(self, peer: &[u8]) -> Result<Vec<u8>> {
+        let peer: [u8; X25519_KEY_LEN] = peer.try_into()
+            .map_err(|_| anyhow!("wrong X25519 public key size"))?;
+        let secret = self.0.diffie_hellman(&PublicKey::from(peer));
+        if !secret.was_contributory() {
+            anyhow::bail!("X25519 key agreement failed");
+        }
+        Ok(secret.as_bytes().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{server_key_exchange, ClientKeyExchange, KeyExchange};
+
+    fn exchange(kind: KeyExchange) {
+        let client = ClientKeyExchange::new(kind).unwrap();
+        assert_eq!(client.share().len(), kind.client_share_size());
+
+        let (server_share, server_secret) = server_key_exchange(kind, client.share()).unwrap();
+        assert_eq!(server_share.len(), kind.server_share_size());
+
+        let client_secret = client.finish(&server_share).unwrap();
+        assert_eq!(client_secret, server_secret);
+        assert_eq!(client_secret.is_empty(), !kind.enabled());
+    }
+
+    #[test]
+    fn key_exchange() {
+        exchange(KeyExchange::None);
+        exchange(KeyExchange::X25519);
+
+        if KeyExchange::X25519MlKem768.is_supported() {
+            exchange(KeyExchange::X25519MlKem768);
+        } else {
+            assert!(ClientKeyExchange::new(KeyExchange::X25519MlKem768).is_err());
+        }
+    }
+
+    #[test]
+    fn key_exchange_wrong_share() {
+        let client = ClientKeyExchange::new(KeyExchange::X25519).unwrap();
+        assert!(server_key_exchange(KeyExchange::X25519, &client.share()[1..]).is_err());
+
+        // the other side's secret doesn't match
+        let (server_share, server_secret) = server_key_exchange(KeyExchange::X25519, client.share()).unwrap();
+        let other = ClientKeyExchange::new(KeyExchange::X25519).unwrap();
+        assert_ne!(other.finish(&server_share).unwrap(), server_secret);
+    }
+}
@@ -1,6 +1,9 @@
 pub mod stream;
 pub mod kdf;
 pub mod cipher;
+pub mod datagram;
+pub mod version;
+pub mod key_exchange;
 
 pub mod config;
 pub use config::DataPadding;
@@ -424,7 +424,20 @@ mod tests {
         check_stream_with_params(padding, enc_limit).await;
     }
    
+    #[tokio::test]
+    async fn encrypted_stream_ciphers() {
+        let padding = DataPadding {max: 250, rate: 10};
+        for cipher in [CipherType::ChaCha20Poly1305, CipherType::XChaCha20Poly1305, CipherType::Aes128Gcm] {
+            check_stream_with_cipher(cipher, padding, usize::MAX).await;
+            check_stream_with_cipher(cipher, padding, 100).await;
+        }
+    }
+
     async fn check_stream_with_params(padding: DataPadding, enc_limit: usize) {
+        check_stream_with_cipher(CipherType::Aes256Gcm, padding, enc_limit).await;
+    }
+
+    async fn check_stream_with_cipher(cipher: CipherType, padding: DataPadding, enc_limit: usize) {
         let fake_stream = FakeStream::new();
 
         let pass = "QrD15a25tK0wVXdnlECwyNBemc6yLsa4iYnf1vRBx7A";
@@ -432,7 +445,7 @@ mod tests {
         let rng = ChaCha20Rng::from_entropy();
         
         let (read_cipher, write_cipher) =
-            new_client_server(CipherType::Aes256Gcm, Kdf::Blake3, pass, &salt).unwrap();
+            new_client_server(cipher, Kdf::Blake3, pass, salt).unwrap();
 
         let mut stream =
             EncryptedStream::from_stream(fake_stream, read_cipher, write_cipher, padding, enc_limit, rng);
@@ -575,7 +588,6 @@ mod tests {
     
     impl FakeStream
     {
-        /// 
         pub fn new() -> Self {
             Self { 
                 buffer: BytesMut::new(),
@@ -0,0 +1,159 @@
+use std::fmt;
+use anyhow::Result;
+use bytes::{Buf, BufMut, BytesMut};
+
+/// Wire protocol version, increased on incompatible changes
+pub const PROTOCOL_VERSION: u8 = 2;
+/// The oldest peer version this side can talk to
+pub const MIN_PROTOCOL_VERSION: u8 = 2;
+
+/// Optional features supported by a peer, unknown bits are ignored.
+/// New extensions are advertised here, so they can be rolled out gradually.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const UDP_ASSOCIATE: Self = Self(1 << 0);
+    pub const TEMP_KEYS: Self = Self(1 << 1);
+    /// X25519MlKem768 key exchange is supported by the build
+    pub const ML_KEM: Self = Self(1 << 2);
+
+    const NAMES: [(Self, &'static str); 3] = [
+        (Self::UDP_ASSOCIATE, "udp_associate"),
+        (Self::TEMP_KEYS, "temp_keys"),
+        (Self::ML_KEM, "ml_kem"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    pub fn with(mut self, other: Self, enabled: bool) -> Self {
+        if enabled {
+            self.insert(other);
+        }
+        self
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = Self::NAMES.iter()
+            .filter(|(cap, _)| self.contains(*cap))
+            .map(|(_, name)| *name)
+            .collect();
+
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+/// Version and capabilities of a peer, sent in the tunnel header (client)
+/// and in the get protocol response (server)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerVersion {
+    pub version: u8,
+    /// the oldest version of the other side the peer can talk to
+    pub min_version: u8,
+    pub capabilities: Capabilities,
+}
+
+impl PeerVersion {
+    /// version u8, min version u8, capabilities u32
+    pub const SIZE: usize = 6;
+
+    pub fn current(capabilities: Capabilities) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    pub fn put(&self, buf: &mut BytesMut) {
+        buf.put_u8(self.version);
+        buf.put_u8(self.min_version);
+        buf.put_u32(self.capabilities.bits());
+    }
+
+    /// buf should have at least SIZE bytes
+    pub fn get(buf: &mut impl Buf) -> Self {
+        Self {
+            version: buf.get_u8(),
+            min_version: buf.get_u8(),
+            capabilities: Capabilities::from_bits(buf.get_u32()),
+        }
+    }
+
+    /// peer - "server" or "client", used in the error
+    pub fn check(&self, peer: &str) -> Result<()> {
+        if self.version < MIN_PROTOCOL_VERSION {
+            anyhow::bail!("{peer} is too old: protocol version {}, at least {MIN_PROTOCOL_VERSION} is supported, please update the {peer}",
+                self.version);
+        }
+
+        if self.min_version > PROTOCOL_VERSION {
+            anyhow::bail!("{peer} is too new: protocol version {} requires at least {}, this side has {PROTOCOL_VERSION}, please update",
+                self.version, self.min_version);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use bytes::BytesMut;
+    use super::{Capabilities, PeerVersion, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+    #[test]
+    fn peer_version() {
+        let caps = Capabilities::UDP_ASSOCIATE.with(Capabilities::TEMP_KEYS, true);
+        let version = PeerVersion::current(caps);
+
+        let mut buf = BytesMut::new();
+        version.put(&mut buf);
+        assert_eq!(buf.len(), PeerVersion::SIZE);
+
+        let received = PeerVersion::get(&mut buf);
+        assert_eq!(received, version);
+        assert!(received.capabilities.contains(Capabilities::TEMP_KEYS));
+        assert!(received.check("server").is_ok());
+        assert_eq!(received.capabilities.to_string(), "udp_associate, temp_keys");
+        assert_eq!(Capabilities::empty().to_string(), "none");
+
+        let old = PeerVersion { version: MIN_PROTOCOL_VERSION - 1, ..version };
+        assert!(old.check("server").unwrap_err().to_string().contains("too old"));
+
+        let new = PeerVersion { version: PROTOCOL_VERSION + 2, min_version: PROTOCOL_VERSION + 1, ..version };
+        assert!(new.check("server").unwrap_err().to_string().contains("too new"));
+
+        // newer peer that still supports this version
+        let compatible = PeerVersion { version: PROTOCOL_VERSION + 1, capabilities: Capabilities::from_bits(u32::MAX), ..version };
+        assert!(compatible.check("client").is_ok());
+    }
+}
@@ -0,0 +1,201 @@
+//! Happy Eyeballs (RFC 8305) connection racing: attempts to all resolved addresses are started one by one
+//! with a small delay, address families are interleaved and the first established connection wins.
+//! Destination with one broken address family still works without waiting for the full TCP timeout.
+
+use std::{
+    future::Future,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    time::Duration,
+};
+use futures::{stream::FuturesUnordered, StreamExt};
+use tokio::{
+    net::TcpStream,
+    select,
+    time::{sleep_until, timeout, Instant},
+};
+
+// RFC 8305 recommends 250 ms
+pub const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+pub const DEFAULT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Timing {
+    /// delay before the next attempt if the previous one is not finished yet
+    pub attempt_delay: Duration,
+
+    /// every attempt is failed after the timeout
+    pub attempt_timeout: Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            attempt_delay: DEFAULT_ATTEMPT_DELAY,
+            attempt_timeout: DEFAULT_ATTEMPT_TIMEOUT,
+        }
+    }
+}
+
+/// Alternates address families starting with the family of the first address,
+/// order inside of the family is kept
+pub fn interleave(addrs: impl IntoIterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+    let addrs: Vec<SocketAddr> = addrs.into_iter().collect();
+    let Some(first_is_v4) = addrs.first().map(SocketAddr::is_ipv4) else {
+        return addrs;
+    };
+
+    let mut result = Vec::with_capacity(addrs.len());
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs.into_iter()
+        .partition(|addr| addr.is_ipv4() == first_is_v4);
+
+    let mut other = other.into_iter();
+    for addr in preferred {
+        result.push(addr);
+        result.extend(other.next());
+    }
+    result.extend(other);
+
+    result
+}
+
+/// Connects to the first available address, returns the stream and its address
+pub async fn connect(addrs: impl IntoIterator<Item = SocketAddr>, timing: Timing) -> io::Result<(TcpStream, SocketAddr)> {
+    connect_with(addrs, timing, TcpStream::connect).await
+}
+
+/// The same as connect, but a connection is established by the given function (e.g. bound socket).
+/// It's finished when the last attempt is timed out at most, no outer timeout is needed
+pub async fn connect_with<T, F, Fut>(
+    addrs: impl IntoIterator<Item = SocketAddr>,
+    timing: Timing,
+    connect: F,
+) -> io::Result<(T, SocketAddr)>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut addrs = interleave(addrs).into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut next_attempt = Instant::now();
+    let mut last_err = None;
+
+    loop {
+        select! {
+            biased;
+
+            Some((addr, result)) = attempts.next(), if !attempts.is_empty() => match result {
+                Ok(stream) => return Ok((stream, addr)),
+                Err(err) => {
+                    // the next address is tried without waiting for the delay
+                    last_err = Some(err);
+                    next_attempt = Instant::now();
+                },
+            },
+            _ = sleep_until(next_attempt), if addrs.peek().is_some() => {
+                let addr = addrs.next().unwrap();
+                let attempt = timeout(timing.attempt_timeout, connect(addr));
+                attempts.push(async move {
+                    let result = match attempt.await {
+                        Ok(Ok(stream)) => Ok(stream),
+                        Ok(Err(err)) => Err(io::Error::new(err.kind(), format!("connect {addr}: {err}"))),
+                        Err(_) => Err(io::Error::new(ErrorKind::TimedOut, format!("connect {addr}: timed out"))),
+                    };
+                    (addr, result)
+                });
+                next_attempt = Instant::now() + timing.attempt_delay;
+            },
+            else => break,
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no addresses to connect")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect, connect_with, interleave, Timing};
+    use std::{io, net::SocketAddr, time::Duration};
+    use tokio::{net::TcpListener, time::Instant};
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn interleaving() {
+        let v6_first = addrs(&["[2001:db8::1]:80", "[2001:db8::2]:80", "[2001:db8::3]:80", "1.1.1.1:80", "2.2.2.2:80"]);
+        assert_eq!(interleave(v6_first), addrs(&[
+            "[2001:db8::1]:80", "1.1.1.1:80", "[2001:db8::2]:80", "2.2.2.2:80", "[2001:db8::3]:80"
+        ]));
+
+        let v4_first = addrs(&["1.1.1.1:80", "[2001:db8::1]:80", "2.2.2.2:80"]);
+        assert_eq!(interleave(v4_first), addrs(&["1.1.1.1:80", "[2001:db8::1]:80", "2.2.2.2:80"]));
+
+        assert!(interleave(Vec::new()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn racing() {
+        let timing = Timing {
+            attempt_delay: Duration::from_millis(50),
+            attempt_timeout: Duration::from_millis(500),
+        };
+
+        // blackholed ipv6 doesn't block ipv4, the attempt is started after the delay
+        let start = Instant::now();
+        let (stream, addr) = connect_with(addrs(&["[2001:db8::1]:80", "1.1.1.1:80"]), timing, |addr| async move {
+            if addr.is_ipv6() {
+                std::future::pending::<()>().await;
+            }
+            Ok(addr)
+        }).await.unwrap();
+        assert_eq!((stream, addr), ("1.1.1.1:80".parse().unwrap(), "1.1.1.1:80".parse().unwrap()));
+        assert!(start.elapsed() >= timing.attempt_delay);
+        assert!(start.elapsed() < timing.attempt_timeout);
+
+        // refused address is skipped immediately
+        let start = Instant::now();
+        let (_, addr) = connect_with(addrs(&["1.1.1.1:80", "[2001:db8::1]:80"]), timing, |addr| async move {
+            match addr.is_ipv4() {
+                true => Err(io::ErrorKind::ConnectionRefused.into()),
+                false => Ok(()),
+            }
+        }).await.unwrap();
+        assert_eq!(addr, "[2001:db8::1]:80".parse().unwrap());
+        assert!(start.elapsed() < timing.attempt_delay);
+
+        // the fallback attempt has its own timeout, the race takes longer than one attempt
+        let start = Instant::now();
+        let (_, addr) = connect_with(addrs(&["1.1.1.1:80", "2.2.2.2:80"]), timing, |addr| async move {
+            match addr.ip().to_string().as_str() {
+                "1.1.1.1" => std::future::pending().await,
+                _ => tokio::time::sleep(timing.attempt_timeout - timing.attempt_delay / 2).await,
+            }
+            Ok(())
+        }).await.unwrap();
+        assert_eq!(addr, "2.2.2.2:80".parse().unwrap());
+        assert!(start.elapsed() > timing.attempt_timeout);
+
+        // all attempts are timed out
+        let err = connect_with(addrs(&["1.1.1.1:80", "2.2.2.2:80"]), timing, |_| std::future::pending::<io::Result<()>>())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let err = connect(Vec::new(), timing).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // nothing is listening on ::1 with the same port (or ipv6 is not available)
+        let (_, connected) = connect(addrs(&[&format!("[::1]:{}", addr.port()), &addr.to_string()]), Timing::default())
+            .await
+            .unwrap();
+        assert_eq!(connected, addr);
+    }
+}
@@ -0,0 +1,261 @@
+//! TCP socket options and timeouts shared by the server and the client:
+//! the same settings are applied to inbound, outbound and client-to-server sockets.
+
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+use serde::Deserialize;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    select,
+    time::{Instant, sleep_until, timeout},
+};
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TcpOptions {
+    /// tunnel is closed if no data is transferred in either direction for this time (sec), 0 - disabled
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+
+    /// outbound connect timeout (sec)
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+
+    /// TCP keepalive idle time and probe interval (sec), 0 - disabled
+    #[serde(default = "default_keepalive")]
+    pub keepalive: u64,
+
+    /// disables Nagle's algorithm
+    #[serde(default = "default_nodelay")]
+    pub nodelay: bool,
+
+    /// SO_SNDBUF in bytes, OS default if not set
+    pub send_buffer_size: Option<usize>,
+
+    /// SO_RCVBUF in bytes, OS default if not set
+    pub recv_buffer_size: Option<usize>,
+}
+
+fn default_idle_timeout() -> u64 {
+    300
+}
+
+fn default_connect_timeout() -> u64 {
+    10
+}
+
+fn default_keepalive() -> u64 {
+    60
+}
+
+fn default_nodelay() -> bool {
+    true
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            idle_timeout: default_idle_timeout(),
+            connect_timeout: default_connect_timeout(),
+            keepalive: default_keepalive(),
+            nodelay: default_nodelay(),
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+}
+
+impl TcpOptions {
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout > 0).then(|| Duration::from_secs(self.idle_timeout))
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout)
+    }
+
+    /// Applies socket options to accepted or connected stream
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+
+        let socket = SockRef::from(stream);
+        if self.keepalive > 0 {
+            let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(self.keepalive));
+            #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios", windows))]
+            let keepalive = keepalive.with_interval(Duration::from_secs(self.keepalive));
+
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+
+        Ok(())
+    }
+
+    /// Connects with connect_timeout and applies socket options
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let stream = timeout(self.connect_timeout(), TcpStream::connect(addr))
+            .await
+            .map_err(|_| io::Error::new(ErrorKind::TimedOut, format!("connect {addr}: timeout")))??;
+
+        self.apply(&stream)?;
+        Ok(stream)
+    }
+}
+
+/// tokio::io::copy_bidirectional that fails with TimedOut error
+/// if no data is transferred in either direction for idle_timeout
+pub async fn copy_bidirectional<A, B>(a: &mut A, b: &mut B, idle_timeout: Option<Duration>) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let Some(idle_timeout) = idle_timeout else {
+        return tokio::io::copy_bidirectional(a, b).await;
+    };
+
+    // traffic in both directions passes through `a`
+    let activity = Activity::new();
+    let mut a = ActivityStream { inner: a, activity: &activity };
+
+    let copy = tokio::io::copy_bidirectional(&mut a, b);
+    tokio::pin!(copy);
+
+    loop {
+        select! {
+            result = &mut copy => return result,
+            _ = sleep_until(activity.last() + idle_timeout) => {
+                if activity.last() + idle_timeout <= Instant::now() {
+                    return Err(io::Error::new(ErrorKind::TimedOut, "idle timeout"));
+                }
+            },
+        }
+    }
+}
+
+/// Time of the last transfer
+struct Activity {
+    started: Instant,
+    last_ms: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self { started: Instant::now(), last_ms: AtomicU64::new(0) }
+    }
+
+    fn touch(&self) {
+        self.last_ms.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.started + Duration::from_millis(self.last_ms.load(Ordering::Relaxed))
+    }
+}
+
+struct ActivityStream<'a, S: ?Sized> {
+    inner: &'a mut S,
+    activity: &'a Activity,
+}
+
+impl<S: AsyncRead + Unpin + ?Sized> AsyncRead for ActivityStream<'_, S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result
+            && buf.filled().len() > filled
+        {
+            self.activity.touch();
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin + ?Sized> AsyncWrite for ActivityStream<'_, S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(size)) = result
+            && size > 0
+        {
+            self.activity.touch();
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TcpOptions, copy_bidirectional};
+    use std::{io::ErrorKind, time::Duration};
+    use socket2::SockRef;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        time::{Instant, sleep},
+    };
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let (mut client, mut a) = tokio::io::duplex(64);
+        let (mut b, mut remote) = tokio::io::duplex(64);
+
+        let started = Instant::now();
+        let copy = tokio::spawn(async move {
+            copy_bidirectional(&mut a, &mut b, Some(Duration::from_millis(200))).await
+        });
+
+        // activity in both directions postpones the timeout
+        let mut buf = [0u8; 4];
+        for _ in 0..3 {
+            sleep(Duration::from_millis(100)).await;
+            client.write_all(b"ping").await.unwrap();
+            remote.read_exact(&mut buf).await.unwrap();
+
+            sleep(Duration::from_millis(100)).await;
+            remote.write_all(b"pong").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+        }
+
+        let err = copy.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() >= Duration::from_millis(800));
+    }
+
+    #[tokio::test]
+    async fn apply() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let options = TcpOptions { keepalive: 30, nodelay: true, ..Default::default() };
+
+        let stream = options.connect(listener.local_addr().unwrap()).await.unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+
+        let options = TcpOptions { keepalive: 0, nodelay: false, ..Default::default() };
+        let (accepted, _) = listener.accept().await.unwrap();
+        options.apply(&accepted).unwrap();
+        assert!(!accepted.nodelay().unwrap());
+        assert!(!SockRef::from(&accepted).keepalive().unwrap());
+    }
+}
@@ -1,11 +1,21 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use tokio::time::{sleep, Duration};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream, UdpSocket},
+    time::{sleep, Duration},
+};
 
 use anyhow::Result;
-use crypto::{cipher::CipherType, config::ProtocolConfig, kdf::Kdf, DataPadding};
+use crypto::{cipher::CipherType, config::ProtocolConfig, kdf::{Kdf, TempKeyInfo}, key_exchange::KeyExchange, DataPadding};
 use client::proxy::{ProxyState, Proxy};
+use cc_server::{admin::AdminConfig, bans::BanConfig, limits::LimitsConfig, proxy_protocol::ProxyProtocolConfig, server::ShutdownHandle, tls::TlsConfig, traffic::{QuotaPeriod, TrafficConfig}, unix::UnixSocketConfig, users::{PreviousKey, UserConfig}};
+use tokio_rustls::{TlsConnector, rustls::{self, RootCertStore, pki_types::ServerName}};
 
 const KEY: &str = r#"ZrDj5S25tK0wVXFnlEC_yNBemc6yLsa4iYnf1vRB_7A"#;
+const KEY2: &str = r#"Ba8oJYu3mEqgS5q6AqKJ0mzy0Cf9y3n2tI8hYQZl3kU"#;
 
 #[tokio::test]
 async fn get_server_protocol() -> Result<()> {
@@ -17,6 +27,7 @@ async fn get_server_protocol() -> Result<()> {
         max_connect_delay: 10000,
         header_padding: 50..777,
         encryption_limit: 1024,
+        key_exchange: KeyExchange::None,
         data_padding: DataPadding { 
             max: 250,
             rate: 10
@@ -31,9 +42,27 @@ async fn get_server_protocol() -> Result<()> {
     tokio::task::spawn(async move {
         let cfg = cc_server::config::AppConfig {
             address: srv_address,
+            unix_socket: None,
             protocol: srv_protocol,
+            previous_keys: vec![],
+            temp_keys: false,
             out_address: None,
+            outbound: None,
             unauth_cooldown: 55..777,
+            users: vec![],
+            destination: Default::default(),
+            replay_cache_size: 1024,
+            fallback: None,
+            tls: None,
+            udp: Default::default(),
+            resolver: Default::default(),
+            admin: None,
+            limits: Default::default(),
+            ban: None,
+            traffic: None,
+            proxy_protocol: None,
+            tcp: Default::default(),
+            drain_timeout: 30,
         };
 
         let url_path = Kdf::derive_url_path(&cfg.protocol.key)?;
@@ -53,6 +82,9 @@ async fn get_server_protocol() -> Result<()> {
         url_path: None,
     };
 
+    // wait for server start
+    sleep(Duration::from_millis(300)).await;
+
     let client = Proxy::new(proxy_port, ProxyState::Off)?;
     client.update_pac_content().await;
     client.add_server(srv_cfg.clone()).await;
@@ -63,6 +95,74 @@ async fn get_server_protocol() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn get_server_protocol_user_key() -> Result<()> {
+    let protocol = ProtocolConfig {
+        key: KEY.to_owned(),
+        kdf: Kdf::Blake3,
+        cipher: CipherType::Aes256Gcm,
+        max_connect_delay: 10000,
+        header_padding: 50..777,
+        encryption_limit: 1024,
+        key_exchange: KeyExchange::None,
+        data_padding: DataPadding { 
+            max: 250,
+            rate: 10
+        }
+    };
+
+    let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8387);
+
+    // start server without main key
+    let mut srv_protocol = protocol.clone();
+    srv_protocol.key = String::new();
+    tokio::task::spawn(async move {
+        let cfg = cc_server::config::AppConfig {
+            address: srv_address,
+            unix_socket: None,
+            protocol: srv_protocol,
+            previous_keys: vec![],
+            temp_keys: false,
+            out_address: None,
+            outbound: None,
+            unauth_cooldown: 55..777,
+            users: vec![
+                UserConfig { name: "alice".to_owned(), key: KEY.to_owned(), previous_keys: vec![], enabled: true, traffic: None },
+                UserConfig { name: "bob".to_owned(), key: KEY2.to_owned(), previous_keys: vec![], enabled: false, traffic: None },
+            ],
+            destination: Default::default(),
+            replay_cache_size: 1024,
+            fallback: None,
+            tls: None,
+            udp: Default::default(),
+            resolver: Default::default(),
+            admin: None,
+            limits: Default::default(),
+            ban: None,
+            traffic: None,
+            proxy_protocol: None,
+            tcp: Default::default(),
+            drain_timeout: 30,
+        };
+
+        cc_server::server::serve(cfg, String::new(), false).await
+    });
+
+    // wait for server start
+    sleep(Duration::from_millis(300)).await;
+
+    let client = Proxy::new(1089, ProxyState::Off)?;
+    let host = srv_address.to_string();
+
+    let srv_protocol = client.get_server_protocol(&host, KEY).await?;
+    assert_eq!(protocol, srv_protocol);
+
+    // disabled user
+    assert!(client.get_server_protocol(&host, KEY2).await.is_err());
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn simple_connect() -> Result<()> {
     let protocol = ProtocolConfig {
@@ -72,6 +172,7 @@ async fn simple_connect() -> Result<()> {
         max_connect_delay: 10000,
         header_padding: 50..777,
         encryption_limit: 1024,
+        key_exchange: KeyExchange::None,
         data_padding: DataPadding { 
             max: 250,
             rate: 10
@@ -86,9 +187,27 @@ async fn simple_connect() -> Result<()> {
     tokio::task::spawn(async move {
         let cfg = cc_server::config::AppConfig {
             address: srv_address,
+            unix_socket: None,
             protocol: srv_protocol,
+            previous_keys: vec![],
+            temp_keys: false,
             out_address: None,
+            outbound: None,
             unauth_cooldown: 55..777,
+            users: vec![],
+            destination: Default::default(),
+            replay_cache_size: 1024,
+            fallback: None,
+            tls: None,
+            udp: Default::default(),
+            resolver: Default::default(),
+            admin: None,
+            limits: Default::default(),
+            ban: None,
+            traffic: None,
+            proxy_protocol: None,
+            tcp: Default::default(),
+            drain_timeout: 30,
         };
 
         let url_path = Kdf::derive_url_path(&cfg.protocol.key)?;
@@ -137,4 +256,1552 @@ async fn simple_connect() -> Result<()> {
     assert!(body.len() > 10000);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn local_connect_destination_policy() -> Result<()> {
+    let protocol = ProtocolConfig {
+        key: KEY.to_owned(),
+        kdf: Kdf::Blake3,
+        cipher: CipherType::Aes256Gcm,
+        max_connect_delay: 10000,
+        header_padding: 50..777,
+        encryption_limit: 1024,
+        key_exchange: KeyExchange::None,
+        data_padding: DataPadding {
+            max: 250,
+            rate: 10
+        }
+    };
+
+    let echo_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8389);
+    let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8391);
+    let strict_srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8393);
+    let proxy_port: u16 = 1091;
+    let strict_proxy_port: u16 = 1093;
+
+    tokio::task::spawn(echo_server(echo_address));
+
+    // loopback is denied by default
+    let mut destination = cc_server::destination::DestinationPolicy::default();
+    destination.allow_cidr.push("127.0.0.0/8".parse()?);
+
+    for (address, destination) in [(srv_address, destination), (strict_srv_address, Default::default())] {
+        let cfg = cc_server::config::AppConfig {
+            address,
+            unix_socket: None,
+            protocol: protocol.clone(),
+            previous_keys: vec![],
+            temp_keys: false,
+            out_address: None,
+            outbound: None,
+            unauth_cooldown: 55..777,
+            users: vec![],
+            destination,
+            replay_cache_size: 1024,
+            fallback: None,
+            tls: None,
+            udp: Default::default(),
+            resolver: Default::default(),
+            admin: None,
+            limits: Default::default(),
+            ban: None,
+            traffic: None,
+            proxy_protocol: None,
+            tcp: Default::default(),
+            drain_timeout: 30,
+        };
+        tokio::task::spawn(cc_server::server::serve(cfg, String::new(), false));
+    }
+
+    for (address, port) in [(srv_address, proxy_port), (strict_srv_address, strict_proxy_port)] {
+        let srv_cfg = client::config::ServerConfig {
+            caption: None,
+            host: address.to_string(),
+            weight: None,
+            domains: None,
+            apps: None,
+            enabled: true,
+            protocol: protocol.clone(),
+            address,
+            url_path: None,
+        };
+
+        let client = Proxy::new(port, ProxyState::Off)?;
+        client.update_pac_content().await;
+        client.add_server(srv_cfg).await;
+        tokio::task::spawn(client.serve());
+    }
+
+    // wait for servers start
+    sleep(Duration::from_millis(300)).await;
+
+    let mut stream = connect_via_proxy(proxy_port, echo_address).await?;
+    stream.write_all(b"ping").await?;
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"ping");
+
+    let mut stream = connect_via_proxy(strict_proxy_port, echo_address).await?;
+    stream.write_all(b"ping").await?;
+    assert!(stream.read_exact(&mut buf).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn unauthenticated_fallback() -> Result<()> {
+    let protocol = ProtocolConfig {
+        key: KEY.to_owned(),
+        kdf: Kdf::Blake3,
+        cipher: CipherType::Aes256Gcm,
+        max_connect_delay: 10000,
+        header_padding: 50..777,
+        encryption_limit: 1024,
+        key_exchange: KeyExchange::None,
+        data_padding: DataPadding {
+            max: 250,
+            rate: 10
+        }
+    };
+
+    let fallback_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8395);
+    let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8397);
+
+    tokio::task::spawn(echo_server(fallback_address));
+
+    let cfg = cc_server::config::AppConfig {
+        address: srv_address,
+        unix_socket: None,
+        protocol,
+        previous_keys: vec![],
+        temp_keys: false,
+        out_address: None,
+        outbound: None,
+        unauth_cooldown: 55..777,
+        users: vec![],
+        destination: Default::default(),
+        replay_cache_size: 1024,
+        fallback: Some(fallback_address.to_string()),
+        tls: None,
+        udp: Default::default(),
+        resolver: Default::default(),
+        admin: None,
+        limits: Default::default(),
+        ban: None,
+        traffic: None,
+        proxy_protocol: None,
+        tcp: Default::default(),
+        drain_timeout: 30,
+    };
+    tokio::task::spawn(cc_server::server::serve(cfg, String::new(), false));
+
+    // wait for servers start
+    sleep(Duration::from_millis(300)).await;
+
+    // prober should get response from the fallback (echo)
+    let request = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    let mut stream = TcpStream::connect(srv_address).await?;
+    stream.write_all(request).await?;
+
+    let mut response = vec![0u8; request.len()];
+    stream.read_exact(&mut response).await?;
+    assert_eq!(&response, request);
+
+    // the rest of the connection is spliced too
+    stream.write_all(b"more").await?;
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"more");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn builtin_tls() -> Result<()> {
+    let protocol = ProtocolConfig {
+        key: KEY.to_owned(),
+        kdf: Kdf::Blake3,
+        cipher: CipherType::Aes256Gcm,
+        max_connect_delay: 10000,
+        header_padding: 50..777,
+        encryption_limit: 1024,
+        key_exchange: KeyExchange::None,
+        data_padding: DataPadding {
+            max: 250,
+            rate: 10
+        }
+    };
+
+    let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8399);
+
+    // self-signed certificate
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])?;
+    let dir = std::env::temp_dir().join(format!("cc-server-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("cert.pem"), cert.cert.pem())?;
+    std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem())?;
+
+    let url_path = Kdf::derive_url_path(KEY)?;
+    let cfg = cc_server::config::AppConfig {
+        address: srv_address,
+        unix_socket: None,
+        protocol: protocol.clone(),
+        previous_keys: vec![],
+        temp_keys: false,
+        out_address: None,
+        outbound: None,
+        unauth_cooldown: 55..777,
+        users: vec![],
+        destination: Default::default(),
+        replay_cache_size: 1024,
+        fallback: None,
+        tls: Some(TlsConfig {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            reload_interval: 3600,
+        }),
+        udp: Default::default(),
+        resolver: Default::default(),
+        admin: None,
+        limits: LimitsConfig { handshake_timeout: 1000, ..Default::default() },
+        ban: None,
+        traffic: None,
+        proxy_protocol: None,
+        tcp: Default::default(),
+        drain_timeout: 30,
+    };
+    tokio::task::spawn(cc_server::server::serve(cfg, url_path.clone(), false));
+
+    // wait for server start
+    sleep(Duration::from_millis(300)).await;
+
+    // https with upgrade
+    let mut root_store = RootCertStore::empty();
+    root_store.add(cert.cert.der().clone())?;
+    let tls_cfg = rustls::ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    let connector = TlsConnector::from(Arc::new(tls_cfg));
+
+    // legacy client without WebSocket framing and WebSocket client
+    for (ws_headers, expected) in [
+        ("", "Upgrade: cconnect\r\n"),
+        ("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n",
+            "Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"),
+    ] {
+        let stream = TcpStream::connect(srv_address).await?;
+        let mut stream = connector.connect(ServerName::try_from("localhost")?, stream).await?;
+        stream.write_all(format!("GET /{url_path} HTTP/1.1\r\nHost: localhost\r\n\
+            Upgrade: websocket\r\nConnection: Upgrade\r\n{ws_headers}\r\n").as_bytes()).await?;
+
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(stream.read_u8().await?);
+        }
+        let response = String::from_utf8(response)?;
+        assert!(response.starts_with("HTTP/1.1 101"));
+        assert!(response.contains(expected), "{response}");
+    }
+
+    // silent connection is closed after the handshake timeout
+    let mut stream = TcpStream::connect(srv_address).await?;
+    let closed = tokio::time::timeout(Duration::from_millis(3000), stream.read_u8()).await;
+    assert!(matches!(closed, Ok(Err(_))), "{closed:?}");
+
+    // raw protocol on the same port
+    let client = Proxy::new(1095, ProxyState::Off)?;
+    let srv_protocol = client.get_server_protocol(&srv_address.to_string(), KEY).await?;
+    assert_eq!(protocol, srv_protocol);
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socks5_udp_associate() -> Result<()> {
+    let protocol = ProtocolConfig {
+        key: KEY.to_owned(),
+        kdf: Kdf::Blake3,
+        cipher: CipherType::ChaCha20Poly1305,
+        max_connect_delay: 10000,
+        header_padding: 50..777,
+        encryption_limit: 1024,
+        key_exchange: KeyExchange::None,
+        data_padding: DataPadding {
+            max: 250,
+            rate: 10
+        }
+    };
+
+    let echo_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8401);
+    let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8403);
+    let proxy_port: u16 = 1097;
+
+    tokio::task::spawn(echo_server(echo_address));
+    tokio::task::spawn(udp_echo_server(echo_address));
+
+    let mut destination = cc_server::destination::DestinationPolicy::default();
+    destination.allow_cidr.push("127.0.0.0/8".parse()?);
+
+    let cfg = cc_server::config::AppConfig {
+        address: srv_address,
+        unix_socket: None,
+        protocol: protocol.clone(),
+        previous_keys: vec![],
+        temp_keys: false,
+        out_address: None,
+        outbound: None,
+        unauth_cooldown: 55..777,
+        users: vec![],
+        destination,
+        replay_cache_size: 1024,
+        fallback: None,
+        tls: None,
+        udp: Default::default(),
+        resolver: Default::default(),
+        admin: None,
+        limits: Default::default(),
+        ban: None,
+        traffic: None,
+        proxy_protocol: None,
+        tcp: Default::default(),
+        drain_timeout: 30,
+    };
+    tokio::task::spawn(cc_server::server::serve(cfg, String::new(), false));
+
+    let srv_cfg = client::config::ServerConfig {
+        caption: None,
+        host: srv_address.to_string(),
+        weight: None,
+        domains: None,
+        apps: None,
+        enabled: true,
+        protocol,
+        address: srv_address,
+        url_path: None,
+    };
+
+    let client = Proxy::new(proxy_port, ProxyState::Off)?;
+    client.update_pac_content().await;
+    client.add_server(srv_cfg).await;
+    tokio::task::spawn(client.serve());
+
+    // wait for servers start
+    sleep(Duration::from_millis(300)).await;
+
+    let proxy_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), proxy_port);
+
+    // SOCKS5 CONNECT
+    let mut stream = TcpStream::connect(proxy_address).await?;
+    stream.write_all(&[5, 1, 0]).await?;
+    stream.write_all(&[5, 1, 0, 1, 127, 0, 0, 1, 0x20, 0xD1]).await?;
+    let mut reply = [0u8; 12];
+    stream.read_exact(&mut reply).await?;
+    assert_eq!(&reply[..4], &[5, 0, 5, 0]);
+
+    stream.write_all(b"ping").await?;
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"ping");
+
+    // SOCKS5 UDP ASSOCIATE
+    let mut control = TcpStream::connect(proxy_address).await?;
+    control.write_all(&[5, 1, 0]).await?;
+    control.write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+    control.read_exact(&mut reply).await?;
+    assert_eq!(&reply[..4], &[5, 0, 5, 0]);
+    let relay_address = SocketAddr::new(
+        IpAddr::V4(Ipv4Addr::new(reply[6], reply[7], reply[8], reply[9])),
+        u16::from_be_bytes([reply[10], reply[11]])
+    );
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let request = [0, 0, 0, 1, 127, 0, 0, 1, 0x20, 0xD1, b'd', b'n', b's'];
+    for _ in 0..2 {
+        socket.send_to(&request, relay_address).await?;
+
+        let mut response = [0u8; 64];
+        let (size, _) = tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut response)).await??;
+        assert_eq!(&response[..size], &request);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_api() -> Result<()> {
+    let protocol = ProtocolConfig {
+        key: KEY.to_owned(),
+        kdf: Kdf::Blake3,
+        cipher: CipherType::Aes256Gcm,
+        max_connect_delay: 10000,
+        header_padding: 50..777,
+        encryption_limit: 1024,
+        key_exchange: KeyExchange::None,
+        data_padding: DataPadding {
+            max: 250,
+            rate: 10
+        }
+    };
+
+    let echo_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8405);
+    let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8407);
+    let admin_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8409);
+    let proxy_port: u16 = 1099;
+    let token = "admin-token-0123456789";
+
+    tokio::task::spawn(echo_server(echo_address));
+
+    let mut destination = cc_server::destination::DestinationPolicy::default();
+    destination.allow_cidr.push("127.0.0.0/8".parse()?);
+
+    let cfg = cc_server::config::AppConfig {
+        address: srv_address,
+        unix_socket: None,
+        protocol: protocol.clone(),
+        previous_keys: vec![],
+        temp_keys: false,
+        out_address: None,
+        outbound: None,
+        unauth_cooldown: 55..777,
+        users: vec![],
+        destination,
+        replay_cache_size: 1024,
+        fallback: None,
+        tls: None,
+        udp: Default::default(),
+        resolver: Default::default(),
+        admin: Some(AdminConfig { listen: admin_address.to_string(), token: token.to_owned() }),
+        limits: Default::default(),
+        ban: None,
+        traffic: None,
+        proxy_protocol: None,
+        tcp: Default::default(),
+        drain_timeout: 30,
+    };
+    tokio::task::spawn(cc_server::server::serve(cfg, String::new(), false));
+
+    let srv_cfg = client::config::ServerConfig {
+        caption: None,
+        host: srv_address.to_string(),
+        weight: None,
+        domains: None,
+        apps: None,
+        enabled: true,
+        protocol,
+        address: srv_address,
+        url_path: None,
+    };
+
+    let client = Proxy::new(proxy_port, ProxyState::Off)?;
+    client.update_pac_content().await;
+    client.add_server(srv_cfg).await;
+    tokio::task::spawn(client.serve());
+
+    // wait for servers start
+    sleep(Duration::from_millis(300)).await;
+
+    let mut stream = connect_via_proxy(proxy_port, echo_address).await?;
+    stream.write_all(b"ping").await?;
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"ping");
+
+    // unauthenticated connection
+    let mut probe = TcpStream::connect(srv_address).await?;
+    probe.write_all(&[0u8; 16]).await?;
+    let _ = probe.read(&mut buf).await;
+
+    let admin = reqwest::Client::new();
+    let url = format!("http://{admin_address}");
+
+    let response = admin.get(format!("{url}/tunnels")).send().await?;
+    assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    let tunnels: serde_json::Value = serde_json::from_str(
+        &admin.get(format!("{url}/tunnels")).bearer_auth(token).send().await?.text().await?
+    )?;
+    let tunnels = tunnels.as_array().unwrap();
+    assert_eq!(tunnels.len(), 1);
+    assert_eq!(tunnels[0]["user"], "default");
+    assert_eq!(tunnels[0]["destination"], echo_address.to_string());
+    assert_eq!(tunnels[0]["rx"], 4);
+    assert_eq!(tunnels[0]["tx"], 4);
+
+    let stats: serde_json::Value = serde_json::from_str(
+        &admin.get(format!("{url}/stats")).bearer_auth(token).send().await?.text().await?
+    )?;
+    assert_eq!(stats["active"], 1);
+    assert_eq!(stats["failed_auth"], 1);
+    assert_eq!(stats["failed_auth_by_ip"]["127.0.0.1"], 1);
+
+    // close the tunnel
+    let id = tunnels[0]["id"].as_u64().unwrap();
+    let response = admin.delete(format!("{url}/tunnels/{id}")).bearer_auth(token).send().await?;
+    assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+    assert!(matches!(tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await?, Ok(0) | Err(_)));
+
+    let response = admin.delete(format!("{url}/tunnels/{id}")).bearer_auth(token).send().await?;
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+    let stats: serde_json::Value = serde_json::from_str(
+        &admin.get(format!("{url}/stats")).bearer_auth(token).send().await?.text().await?
+    )?;
+    assert_eq!(stats["active"], 0);
+    assert_eq!(stats["rx"], 4);
+
+    let metrics = admin.get(format!("{url}/metrics")).bearer_auth(token).send().await?.text().await?;
+    let metrics: Vec<&str> = metrics.lines().collect();
+    assert!(metrics.contains(&"cc_server_handshakes_total{result=\"success\"} 1"));
+    assert!(metrics.contains(&"cc_server_handshakes_total{result=\"bad_size\"} 1"));
+    assert!(metrics.contains(&"cc_server_active_tunnels 0"));
+    assert!(metrics.contains(&"cc_server_bytes_total{user=\"default\",direction=\"in\"} 4"));
+    assert!(metrics.contains(&"cc_server_outbound_connect_seconds_count 1"));
+    assert!(metrics.contains(&"cc_server_tunnel_duration_seconds_count 1"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn config_reload() -> Result<()> {
+    let echo_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8411);
+    let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8413);
+    let new_srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8415);
+    let proxy_port: u16 = 1101;
+
+    tokio::task::spawn(echo_server(echo_address));
+
+    let cfg_path = std::env::temp_dir().join(format!("cc-server-reload-{}.yaml", std::process::id()));
+    let cfg_content = |address: SocketAddr| format!(
+        "address: \"{address}\"\nkey: \"{KEY}\"\ndestination:\n  allow_cidr: [\"127.0.0.0/8\"]\n"
+    );
+    std::fs::write(&cfg_path, cfg_content(srv_address))?;
+
+    let cfg = cc_server::config::AppConfig::new(&cfg_path)?;
+    let protocol = cfg.protocol.clone();
+    let handle = cc_server::reload::ConfigHandle::new(cfg, String::new(), Some(cfg_path.clone())).await?;
+    tokio::task::spawn(cc_server::server::serve_with(handle.clone(), ShutdownHandle::default(), false));
+
+    let srv_cfg = client::config::ServerConfig {
+        caption: None,
+        host: srv_address.to_string(),
+        weight: None,
+        domains: None,
+        apps: None,
+        enabled: true,
+        protocol,
+        address: srv_address,
+        url_path: None,
+    };
+
+    let client = Proxy::new(proxy_port, ProxyState::Off)?;
+    client.update_pac_content().await;
+    client.add_server(srv_cfg).await;
+    tokio::task::spawn(client.serve());
+
+    // wait for servers start
+    sleep(Duration::from_millis(300)).await;
+
+    let mut stream = connect_via_proxy(proxy_port, echo_address).await?;
+    let mut buf = [0u8; 4];
+    stream.write_all(b"ping").await?;
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"ping");
+
+    // invalid config is rejected, the running one is kept
+    std::fs::write(&cfg_path, "address: \"127.0.0.1:8415\"\nkey: \"\"\n")?;
+    assert!(handle.reload().await.is_err());
+    assert_eq!(handle.current().cfg.address, srv_address);
+
+    // the listener is rebound, the tunnel in flight is untouched
+    std::fs::write(&cfg_path, cfg_content(new_srv_address))?;
+    handle.reload().await?;
+    assert_eq!(handle.current().cfg.address, new_srv_address);
+    sleep(Duration::from_millis(100)).await;
+
+    assert!(TcpStream::connect(new_srv_address).await.is_ok());
+    assert!(TcpStream::connect(srv_address).await.is_err());
+
+    stream.write_all(b"pong").await?;
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"pong");
+
+    std::fs::remove_file(&cfg_path)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn graceful_shutdown() -> Result<()> {
+    let protocol = ProtocolConfig {
+        key: KEY.to_owned(),
+        kdf: Kdf::Blake3,
+        cipher: CipherType::Aes256Gcm,
+        max_connect_delay: 10000,
+        header_padding: 50..777,
+        encryption_limit: 1024,
+        key_exchange: KeyExchange::None,
+        data_padding: DataPadding {
+            max: 250,
+            rate: 10
+        }
+    };
+
+    let echo_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8417);
+    let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8419);
+    let proxy_port: u16 = 1103;
+
+    tokio::task::spawn(echo_server(echo_address));
+
+    let mut destination = cc_server::destination::DestinationPolicy::default();
+    destination.allow_cidr.push("127.0.0.0/8".parse()?);
+
+    let cfg = cc_server::config::AppConfig {
+        address: srv_address,
+        unix_socket: None,
+        protocol: protocol.clone(),
+        previous_keys: vec![],
+        temp_keys: false,
+        out_address: None,
+        outbound: None,
+        unauth_cooldown: 55..777,
+        users: vec![],
+        destination,
+        replay_cache_size: 1024,
+        fallback: None,
+        tls: None,
+        udp: Default::default(),
+        resolver: Default::default(),
+        admin: None,
+        limits: Default::default(),
+        ban: None,
+        traffic: None,
+        proxy_protocol: None,
+        tcp: Default::default(),
+        drain_timeout: 2,
+    };
+    let handle = cc_server::reload::ConfigHandle::new(cfg, String::new(), None).await?;
+    let shutdown = ShutdownHandle::default();
+    let server = tokio::task::spawn(cc_server::server::serve_with(handle, shutdown.clone(), false));
+
+    let srv_cfg = client::config::ServerConfig {
+        caption: None,
+        host: srv_address.to_string(),
+        weight: None,
+        domains: None,
+        apps: None,
+        enabled: true,
+        protocol,
+        address: srv_address,
+        url_path: None,
+    };
+
+    let client = Proxy::new(proxy_port, ProxyState::Off)?;
+    client.update_pac_content().await;
+    client.add_server(srv_cfg).await;
+    tokio::task::spawn(client.serve());
+
+    // wait for servers start
+    sleep(Duration::from_millis(300)).await;
+
+    let mut stream = connect_via_proxy(proxy_port, echo_address).await?;
+    let mut buf = [0u8; 4];
+    stream.write_all(b"ping").await?;
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"ping");
+
+    // new connections are not accepted, the active tunnel is drained
+    shutdown.shutdown();
+    sleep(Duration::from_millis(100)).await;
+    assert!(TcpStream::connect(srv_address).await.is_err());
+
+    stream.write_all(b"pong").await?;
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"pong");
+    assert!(!server.is_finished());
+
+    // and cancelled after the drain timeout
+    tokio::time::timeout(Duration::from_secs(5), server).await???;
+    assert!(matches!(tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await?, Ok(0) | Err(_)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn ban_probing_ip() -> Result<()> {
+    let protocol = ProtocolConfig {
+        key: KEY.to_owned(),
+        kdf: Kdf::Blake3,
+        cipher: CipherType::Aes256Gcm,
+        max_connect_delay: 10000,
+        header_padding: 50..777,
+        encryption_limit: 1024,
+        key_exchange: KeyExchange::None,
+        data_padding: DataPadding {
+            max: 250,
+            rate: 10
+        }
+    };
+
+    let echo_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8421);
+    let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8423);
+    let proxy_port: u16 = 1105;
+
+    tokio::task::spawn(echo_server(echo_address));
+
+    let mut destination = cc_server::destination::DestinationPolicy::default();
+    destination.allow_cidr.push("127.0.0.0/8".parse()?);
+
+    // loopback is allowed by default
+    let ban = BanConfig {
+        threshold: 2,
+        decay: 600,
+        ban_time: 3600,
+        allow_cidr: vec![],
+        file: None,
+    };
+
+    let cfg = cc_server::config::AppConfig {
+        address: srv_address,
+        unix_socket: None,
+        protocol: protocol.clone(),
+        previous_keys: vec![],
+        temp_keys: false,
+        out_address: None,
+        outbound: None,
+        unauth_cooldown: 10..50,
+        users: vec![],
+        destination,
+        replay_cache_size: 1024,
+        fallback: None,
+        tls: None,
+        udp: Default::default(),
+        resolver: Default::default(),
+        admin: None,
+        limits: Default::default(),
+        ban: Some(ban),
+        traffic: None,
+        proxy_protocol: None,
+        tcp: Default::default(),
+        drain_timeout: 30,
+    };
+    tokio::task::spawn(cc_server::server::serve(cfg, String::new(), false));
+
+    let srv_cfg = client::config::ServerConfig {
+        caption: None,
+        host: srv_address.to_string(),
+        weight: None,
+        domains: None,
+        apps: None,
+        enabled: true,
+        protocol,
+        address: srv_address,
+        url_path: None,
+    };
+
+    let client = Proxy::new(proxy_port, ProxyState::Off)?;
+    client.update_pac_content().await;
+    client.add_server(srv_cfg).await;
+    tokio::task::spawn(client.serve());
+
+    // wait for servers start
+    sleep(Duration::from_millis(300)).await;
+
+    let mut buf = [0u8; 4];
+    let mut stream = connect_via_proxy(proxy_port, echo_address).await?;
+    stream.write_all(b"ping").await?;
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"ping");
+
+    // junk headers
+    for _ in 0..2 {
+        let mut probe = TcpStream::connect(srv_address).await?;
+        probe.write_all(&[0x42; 1024]).await?;
+        let _ = tokio::time::timeout(Duration::from_secs(1), probe.read(&mut buf)).await;
+    }
+
+    // the address is banned, valid clients from it are rejected too
+    let banned = async {
+        let mut stream = connect_via_proxy(proxy_port, echo_address).await?;
+        stream.write_all(b"ping").await?;
+        stream.read_exact(&mut buf).await?;
+        anyhow::Ok(())
+    };
+    assert!(!matches!(tokio::time::timeout(Duration::from_secs(3), banned).await, Ok(Ok(()))));
+
+    // active tunnels are kept
+    stream.write_all(b"pong").await?;
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"pong");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn proxy_protocol_client_address() -> Result<()> {
+    let protocol = ProtocolConfig {
+        key: KEY.to_owned(),
+        kdf: Kdf::Blake3,
+        cipher: CipherType::Aes256Gcm,
+        max_connect_delay: 10000,
+        header_padding: 50..777,
+        encryption_limit: 1024,
+        key_exchange: KeyExchange::None,
+        data_padding: DataPadding {
+            max: 250,
+            rate: 10
+        }
+    };
+
+    let echo_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8425);
+    let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8427);
+    let forwarder_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8429);
+    let proxy_port: u16 = 1107;
+
+    tokio::task::spawn(echo_server(echo_address));
+    tokio::task::spawn(proxy_protocol_forwarder(forwarder_address, srv_address));
+
+    let mut destination = cc_server::destination::DestinationPolicy::default();
+    destination.allow_cidr.push("127.0.0.0/8".parse()?);
+
+    // loopback is never banned, so the ban below is for the address from the header
+    let ban = BanConfig {
+        threshold: 1,
+        decay: 600,
+        ban_time: 3600,
+        allow_cidr: vec!["127.0.0.0/8".parse()?],
+        file: None,
+    };
+
+    let cfg = cc_server::config::AppConfig {
+        address: srv_address,
+        unix_socket: None,
+        protocol: protocol.clone(),
+        previous_keys: vec![],
+        temp_keys: false,
+        out_address: None,
+        outbound: None,
+        unauth_cooldown: 10..50,
+        users: vec![],
+        destination,
+        replay_cache_size: 1024,
+        fallback: None,
+        tls: None,
+        udp: Default::default(),
+        resolver: Default::default(),
+        admin: None,
+        limits: Default::default(),
+        ban: Some(ban),
+        traffic: None,
+        proxy_protocol: Some(ProxyProtocolConfig { trusted: vec!["127.0.0.2/32".parse()?] }),
+        tcp: Default::default(),
+        drain_timeout: 30,
+    };
+    tokio::task::spawn(cc_server::server::serve(cfg, String::new(), false));
+
+    let srv_cfg = client::config::ServerConfig {
+        caption: None,
+        host: forwarder_address.to_string(),
+        weight: None,
+        domains: None,
+        apps: None,
+        enabled: true,
+        protocol,
+        address: forwarder_address,
+        url_path: None,
+    };
+
+    let client = Proxy::new(proxy_port, ProxyState::Off)?;
+    client.update_pac_content().await;
+    client.add_server(srv_cfg).await;
+    tokio::task::spawn(client.serve());
+
+    // wait for servers start
+    sleep(Duration::from_millis(300)).await;
+
+    let mut buf = [0u8; 4];
+    let mut stream = connect_via_proxy(proxy_port, echo_address).await?;
+    stream.write_all(b"ping").await?;
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"ping");
+
+    // the header is not accepted from untrusted addresses
+    let mut probe = TcpStream::connect(srv_address).await?;
+    probe.write_all(b"PROXY TCP4 198.51.100.7 127.0.0.1 40000 8427\r\n").await?;
+    probe.write_all(&[0x42; 1024]).await?;
+    let _ = tokio::time::timeout(Duration::from_secs(1), probe.read(&mut buf)).await;
+
+    let mut stream = connect_via_proxy(proxy_port, echo_address).await?;
+    stream.write_all(b"ping").await?;
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"ping");
+
+    // junk header from the client address
+    let mut probe = TcpStream::connect(forwarder_address).await?;
+    probe.write_all(&[0x42; 1024]).await?;
+    let _ = tokio::time::timeout(Duration::from_secs(1), probe.read(&mut buf)).await;
+
+    let banned = async {
+        let mut stream = connect_via_proxy(proxy_port, echo_address).await?;
+        stream.write_all(b"ping").await?;
+        stream.read_exact(&mut buf).await?;
+        anyhow::Ok(())
+    };
+    assert!(!matches!(tokio::time::timeout(Duration::from_secs(3), banned).await, Ok(Ok(()))));
+
+    Ok(())
+}
+
+/// Reverse proxy at 127.0.0.2 sending PROXY protocol v1 header with the fixed client address
+async fn proxy_protocol_forwarder(address: SocketAddr, server: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let socket = TcpSocket::new_v4()?;
+        socket.bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), 0))?;
+        let mut upstream = socket.connect(server).await?;
+
+        tokio::task::spawn(async move {
+            upstream.write_all(format!("PROXY TCP4 198.51.100.7 {} 40000 {}\r\n", server.ip(), server.port()).as_bytes()).await?;
+            tokio::io::copy_bidirectional(&mut stream, &mut upstream).await
+        });
+    }
+}
+
+#[tokio::test]
+async fn reverse_proxy_connection_limit() -> Result<()> {
+    let protocol = ProtocolConfig {
+        key: KEY.to_owned(),
+        kdf: Kdf::Blake3,
+        cipher: CipherType::Aes256Gcm,
+        max_connect_delay: 10000,
+        header_padding: 50..777,
+        encryption_limit: 1024,
+        key_exchange: KeyExchange::None,
+        data_padding: DataPadding {
+            max: 250,
+            rate: 10
+        }
+    };
+
+    let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8453);
+
+    let cfg = cc_server::config::AppConfig {
+        address: srv_address,
+        unix_socket: None,
+        protocol: protocol.clone(),
+        previous_keys: vec![],
+        temp_keys: false,
+        out_address: None,
+        outbound: None,
+        unauth_cooldown: 55..777,
+        users: vec![],
+        destination: Default::default(),
+        replay_cache_size: 1024,
+        fallback: None,
+        tls: None,
+        udp: Default::default(),
+        resolver: Default::default(),
+        admin: None,
+        limits: LimitsConfig { max_connections_per_ip: 1, ..Default::default() },
+        ban: None,
+        traffic: None,
+        proxy_protocol: None,
+        tcp: Default::default(),
+        drain_timeout: 30,
+    };
+    tokio::task::spawn(cc_server::server::serve(cfg, Kdf::derive_url_path(KEY)?, false));
+
+    // wait for server start
+    sleep(Duration::from_millis(300)).await;
+
+    // nginx on localhost without PROXY protocol, its connections are not limited per ip
+    let _first = TcpStream::connect(srv_address).await?;
+    let _second = TcpStream::connect(srv_address).await?;
+    sleep(Duration::from_millis(100)).await;
+
+    let client = Proxy::new(1125, ProxyState::Off)?;
+    let srv_protocol = tokio::time::timeout(Duration::from_secs(5), client.get_server_protocol(&srv_address.to_string(), KEY)).await??;
+    assert_eq!(protocol, srv_protocol);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket_upgrade() -> Result<()> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let protocol = ProtocolConfig {
+        key: KEY.to_owned(),
+        kdf: Kdf::Blake3,
+        cipher: CipherType::Aes256Gcm,
+        max_connect_delay: 10000,
+        header_padding: 50..777,
+        encryption_limit: 1024,
+        key_exchange: KeyExchange::None,
+        data_padding: DataPadding {
+            max: 250,
+            rate: 10
+        }
+    };
+
+    let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8431);
+    let socket_path = std::env::temp_dir().join(format!("cc-server-{}.sock", std::process::id()));
+
+    let url_path = Kdf::derive_url_path(KEY)?;
+    let cfg = cc_server::config::AppConfig {
+        address: srv_address,
+        unix_socket: Some(UnixSocketConfig { path: socket_path.clone(), mode: "600".to_owned() }),
+        protocol,
+        previous_keys: vec![],
+        temp_keys: false,
+        out_address: None,
+        outbound: None,
+        unauth_cooldown: 55..777,
+        users: vec![],
+        destination: Default::default(),
+        replay_cache_size: 1024,
+        fallback: None,
+        tls: None,
+        udp: Default::default(),
+        resolver: Default::default(),
+        admin: None,
+        limits: LimitsConfig { max_connections_per_ip: 1, ..Default::default() },
+        ban: None,
+        traffic: None,
+        proxy_protocol: None,
+        tcp: Default::default(),
+        drain_timeout: 30,
+    };
+    let handle = cc_server::reload::ConfigHandle::new(cfg, url_path.clone(), None).await?;
+    let shutdown = ShutdownHandle::default();
+    let server = tokio::task::spawn(cc_server::server::serve_with(handle, shutdown.clone(), false));
+
+    // wait for server start
+    sleep(Duration::from_millis(300)).await;
+
+    let meta = std::fs::metadata(&socket_path)?;
+    assert!(meta.file_type().is_socket());
+    assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+
+    // connections on the unix socket are from nginx, upgrade is accepted,
+    // the per ip limit is not applied as the client address is unknown
+    let mut streams = Vec::new();
+    for _ in 0..2 {
+        let mut stream = tokio::net::UnixStream::connect(&socket_path).await?;
+        stream.write_all(format!("GET /{url_path} HTTP/1.1\r\nHost: localhost\r\n\
+            Upgrade: websocket\r\nConnection: Upgrade\r\n\r\n").as_bytes()).await?;
+
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(stream.read_u8().await?);
+        }
+        assert!(String::from_utf8(response)?.starts_with("HTTP/1.1 101"));
+        streams.push(stream);
+    }
+    drop(streams);
+
+    // the socket file is removed on shutdown
+    shutdown.shutdown();
+    tokio::time::timeout(Duration::from_secs(5), server).await???;
+    assert!(!socket_path.exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn key_rotation() -> Result<()> {
+    let protocol = ProtocolConfig {
+        key: KEY2.to_owned(),
+        kdf: Kdf::Blake3,
+        cipher: CipherType::Aes256Gcm,
+        max_connect_delay: 10000,
+        header_padding: 50..777,
+        encryption_limit: 1024,
+        key_exchange: KeyExchange::None,
+        data_padding: DataPadding {
+            max: 250,
+            rate: 10
+        }
+    };
+
+    let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8433);
+
+    // KEY is replaced with KEY2, alice's previous key is expired
+    let alice_key = Kdf::generate_new_key();
+    let expired_key = Kdf::generate_new_key();
+    let now = chrono::Utc::now();
+
+    let cfg = cc_server::config::AppConfig {
+        address: srv_address,
+        unix_socket: None,
+        protocol,
+        previous_keys: vec![PreviousKey { key: KEY.to_owned(), expires: Some(now + chrono::Duration::hours(1)) }],
+        temp_keys: false,
+        out_address: None,
+        outbound: None,
+        unauth_cooldown: 55..777,
+        users: vec![
+            UserConfig {
+                name: "alice".to_owned(),
+                key: alice_key.clone(),
+                previous_keys: vec![PreviousKey { key: expired_key.clone(), expires: Some(now - chrono::Duration::hours(1)) }],
+                enabled: true,
+                traffic: None,
+            },
+        ],
+        destination: Default::default(),
+        replay_cache_size: 1024,
+        fallback: None,
+        tls: None,
+        udp: Default::default(),
+        resolver: Default::default(),
+        admin: None,
+        limits: Default::default(),
+        ban: None,
+        traffic: None,
+        proxy_protocol: None,
+        tcp: Default::default(),
+        drain_timeout: 30,
+    };
+    tokio::task::spawn(cc_server::server::serve(cfg, Kdf::derive_url_path(KEY2)?, false));
+
+    // wait for server start
+    sleep(Duration::from_millis(300)).await;
+
+    let client = Proxy::new(1109, ProxyState::Off)?;
+    let host = srv_address.to_string();
+
+    // both keys are accepted during the grace period
+    assert!(client.get_server_protocol(&host, KEY2).await.is_ok());
+    assert!(client.get_server_protocol(&host, KEY).await.is_ok());
+    assert!(client.get_server_protocol(&host, &alice_key).await.is_ok());
+
+    // and the expired one is not
+    assert!(client.get_server_protocol(&host, &expired_key).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn temp_key() -> Result<()> {
+    let protocol = ProtocolConfig {
+        key: KEY.to_owned(),
+        kdf: Kdf::Blake3,
+        cipher: CipherType::ChaCha20Poly1305,
+        max_connect_delay: 10000,
+        // not defaults, the client takes them from the key
+        header_padding: 60..700,
+        encryption_limit: 1024,
+        key_exchange: KeyExchange::None,
+        data_padding: DataPadding { max: 250, rate: 10 },
+    };
+
+    let echo_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8435);
+    let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8437);
+    let guest_proxy_port: u16 = 1111;
+    let expired_proxy_port: u16 = 1113;
+
+    tokio::task::spawn(echo_server(echo_address));
+
+    let mut destination = cc_server::destination::DestinationPolicy::default();
+    destination.allow_cidr.push("127.0.0.0/8".parse()?);
+
+    let cfg = cc_server::config::AppConfig {
+        address: srv_address,
+        unix_socket: None,
+        protocol: protocol.clone(),
+        previous_keys: vec![],
+        temp_keys: true,
+        out_address: None,
+        outbound: None,
+        unauth_cooldown: 55..777,
+        users: vec![],
+        destination,
+        replay_cache_size: 1024,
+        fallback: None,
+        tls: None,
+        udp: Default::default(),
+        resolver: Default::default(),
+        admin: None,
+        limits: Default::default(),
+        ban: None,
+        traffic: None,
+        proxy_protocol: None,
+        tcp: Default::default(),
+        drain_timeout: 30,
+    };
+    tokio::task::spawn(cc_server::server::serve(cfg, Kdf::derive_url_path(KEY)?, false));
+
+    let now = chrono::Utc::now().timestamp();
+    let guest_key = Kdf::generate_temp_key(KEY, &TempKeyInfo::new(&protocol, now + 3600, "guest"))?;
+    let expired_key = Kdf::generate_temp_key(KEY, &TempKeyInfo::new(&protocol, now - 1, "late guest"))?;
+
+    for (key, port) in [(&guest_key, guest_proxy_port), (&expired_key, expired_proxy_port)] {
+        let client = Proxy::new(port, ProxyState::Off)?;
+
+        // the protocol is taken from the key itself
+        let guest_protocol = client.get_server_protocol(&srv_address.to_string(), key).await?;
+        assert_eq!(guest_protocol, ProtocolConfig { key: key.clone(), ..protocol.clone() });
+
+        let srv_cfg = client::config::ServerConfig {
+            caption: None,
+            host: srv_address.to_string(),
+            weight: None,
+            domains: None,
+            apps: None,
+            enabled: true,
+            protocol: guest_protocol,
+            address: srv_address,
+            url_path: None,
+        };
+
+        client.update_pac_content().await;
+        client.add_server(srv_cfg).await;
+        tokio::task::spawn(client.serve());
+    }
+
+    // wait for servers start
+    sleep(Duration::from_millis(300)).await;
+
+    // more than encryption_limit, so the data is framed as the server expects
+    let data: Vec<u8> = (0..4096u32).map(|i| i as u8).collect();
+    let mut stream = connect_via_proxy(guest_proxy_port, echo_address).await?;
+    stream.write_all(&data).await?;
+    let mut echo = vec![0u8; data.len()];
+    stream.read_exact(&mut echo).await?;
+    assert_eq!(echo, data);
+
+    let mut buf = [0u8; 4];
+    let mut stream = connect_via_proxy(expired_proxy_port, echo_address).await?;
+    stream.write_all(b"ping").await?;
+    assert!(stream.read_exact(&mut buf).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn traffic_limits() -> Result<()> {
+    let protocol = ProtocolConfig {
+        key: KEY.to_owned(),
+        kdf: Kdf::Blake3,
+        cipher: CipherType::Aes256Gcm,
+        max_connect_delay: 10000,
+        header_padding: 50..777,
+        encryption_limit: 1024,
+        key_exchange: KeyExchange::None,
+        data_padding: DataPadding {
+            max: 250,
+            rate: 10
+        }
+    };
+
+    let echo_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8439);
+    let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8441);
+    let proxy_port: u16 = 1115;
+
+    tokio::task::spawn(echo_server(echo_address));
+
+    let mut destination = cc_server::destination::DestinationPolicy::default();
+    destination.allow_cidr.push("127.0.0.0/8".parse()?);
+
+    let cfg = cc_server::config::AppConfig {
+        address: srv_address,
+        unix_socket: None,
+        protocol: protocol.clone(),
+        previous_keys: vec![],
+        temp_keys: false,
+        out_address: None,
+        outbound: None,
+        unauth_cooldown: 55..777,
+        users: vec![],
+        destination,
+        replay_cache_size: 1024,
+        fallback: None,
+        tls: None,
+        udp: Default::default(),
+        resolver: Default::default(),
+        admin: None,
+        limits: Default::default(),
+        ban: None,
+        traffic: Some(TrafficConfig { upload: 0, download: 10_000, quota: 40_000, period: QuotaPeriod::Daily, file: None }),
+        proxy_protocol: None,
+        tcp: Default::default(),
+        drain_timeout: 30,
+    };
+    tokio::task::spawn(cc_server::server::serve(cfg, String::new(), false));
+
+    let srv_cfg = client::config::ServerConfig {
+        caption: None,
+        host: srv_address.to_string(),
+        weight: None,
+        domains: None,
+        apps: None,
+        enabled: true,
+        protocol,
+        address: srv_address,
+        url_path: None,
+    };
+
+    let client = Proxy::new(proxy_port, ProxyState::Off)?;
+    client.update_pac_content().await;
+    client.add_server(srv_cfg).await;
+    tokio::task::spawn(client.serve());
+
+    // wait for servers start
+    sleep(Duration::from_millis(300)).await;
+
+    // one second of download is sent at once, the rest is throttled
+    let started = std::time::Instant::now();
+    let mut stream = connect_via_proxy(proxy_port, echo_address).await?;
+    let data = vec![7u8; 14_000];
+    stream.write_all(&data).await?;
+    let mut buf = vec![0u8; data.len()];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(buf, data);
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    drop(stream);
+
+    // the tunnel in flight is closed when the quota is exceeded (both directions)
+    let mut stream = connect_via_proxy(proxy_port, echo_address).await?;
+    let data = vec![7u8; 20_000];
+    stream.write_all(&data).await?;
+    let mut buf = vec![0u8; data.len()];
+    assert!(tokio::time::timeout(Duration::from_secs(10), stream.read_exact(&mut buf)).await?.is_err());
+
+    // and new ones are rejected
+    let mut stream = connect_via_proxy(proxy_port, echo_address).await?;
+    stream.write_all(b"ping").await?;
+    let mut buf = [0u8; 4];
+    assert!(stream.read_exact(&mut buf).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn key_exchange() -> Result<()> {
+    let protocol = ProtocolConfig {
+        key: KEY.to_owned(),
+        kdf: Kdf::Blake3,
+        cipher: CipherType::ChaCha20Poly1305,
+        max_connect_delay: 10000,
+        header_padding: 50..777,
+        encryption_limit: 1024,
+        data_padding: DataPadding::default(),
+        key_exchange: KeyExchange::X25519,
+    };
+
+    let echo_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8443);
+    let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8445);
+    let proxy_port: u16 = 1117;
+    let static_proxy_port: u16 = 1119;
+
+    tokio::task::spawn(echo_server(echo_address));
+
+    let mut destination = cc_server::destination::DestinationPolicy::default();
+    destination.allow_cidr.push("127.0.0.0/8".parse()?);
+
+    let cfg = cc_server::config::AppConfig {
+        address: srv_address,
+        unix_socket: None,
+        protocol: protocol.clone(),
+        previous_keys: vec![],
+        temp_keys: false,
+        out_address: None,
+        outbound: None,
+        unauth_cooldown: 55..777,
+        users: vec![],
+        destination,
+        replay_cache_size: 1024,
+        fallback: None,
+        tls: None,
+        udp: Default::default(),
+        resolver: Default::default(),
+        admin: None,
+        limits: Default::default(),
+        ban: None,
+        traffic: None,
+        proxy_protocol: None,
+        tcp: Default::default(),
+        drain_timeout: 30,
+    };
+    tokio::task::spawn(cc_server::server::serve(cfg, String::new(), false));
+
+    // wait for server start
+    sleep(Duration::from_millis(300)).await;
+
+    // the client without key exchange can't connect
+    let static_protocol = ProtocolConfig { key_exchange: KeyExchange::None, ..protocol.clone() };
+    for (protocol, port) in [(None, proxy_port), (Some(static_protocol), static_proxy_port)] {
+        let client = Proxy::new(port, ProxyState::Off)?;
+
+        // key exchange is discovered with the rest of the protocol
+        let protocol = match protocol {
+            Some(protocol) => protocol,
+            None => {
+                let srv_protocol = client.get_server_protocol(&srv_address.to_string(), KEY).await?;
+                assert_eq!(srv_protocol.key_exchange, KeyExchange::X25519);
+                srv_protocol
+            },
+        };
+
+        let srv_cfg = client::config::ServerConfig {
+            caption: None,
+            host: srv_address.to_string(),
+            weight: None,
+            domains: None,
+            apps: None,
+            enabled: true,
+            protocol,
+            address: srv_address,
+            url_path: None,
+        };
+
+        client.update_pac_content().await;
+        client.add_server(srv_cfg).await;
+        tokio::task::spawn(client.serve());
+    }
+
+    // wait for proxies start
+    sleep(Duration::from_millis(300)).await;
+
+    let mut stream = connect_via_proxy(proxy_port, echo_address).await?;
+    let data = vec![7u8; 5000];
+    stream.write_all(&data).await?;
+    let mut buf = vec![0u8; data.len()];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(buf, data);
+
+    let mut stream = connect_via_proxy(static_proxy_port, echo_address).await?;
+    stream.write_all(b"ping").await?;
+    let mut buf = [0u8; 4];
+    assert!(stream.read_exact(&mut buf).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn extra_ciphers() -> Result<()> {
+    let echo_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8447);
+    tokio::task::spawn(echo_server(echo_address));
+
+    let ciphers = [
+        (CipherType::XChaCha20Poly1305, Kdf::Blake3, 8449, 1121),
+        (CipherType::Aes128Gcm, Kdf::Argon2, 8451, 1123),
+    ];
+
+    for (cipher, kdf, srv_port, proxy_port) in ciphers {
+        let protocol = ProtocolConfig {
+            key: KEY.to_owned(),
+            kdf,
+            cipher,
+            max_connect_delay: 10000,
+            header_padding: 50..777,
+            encryption_limit: 1024,
+            data_padding: DataPadding::default(),
+            key_exchange: KeyExchange::X25519,
+        };
+
+        let srv_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), srv_port);
+
+        let mut destination = cc_server::destination::DestinationPolicy::default();
+        destination.allow_cidr.push("127.0.0.0/8".parse()?);
+
+        let cfg = cc_server::config::AppConfig {
+            address: srv_address,
+            unix_socket: None,
+            protocol: protocol.clone(),
+            previous_keys: vec![],
+            temp_keys: false,
+            out_address: None,
+            outbound: None,
+            unauth_cooldown: 55..777,
+            users: vec![],
+            destination,
+            replay_cache_size: 1024,
+            fallback: None,
+            tls: None,
+            udp: Default::default(),
+            resolver: Default::default(),
+            admin: None,
+            limits: Default::default(),
+            ban: None,
+            traffic: None,
+            proxy_protocol: None,
+            tcp: Default::default(),
+            drain_timeout: 30,
+        };
+        tokio::task::spawn(cc_server::server::serve(cfg, String::new(), false));
+
+        // wait for server start
+        sleep(Duration::from_millis(300)).await;
+
+        let client = Proxy::new(proxy_port, ProxyState::Off)?;
+        let srv_protocol = client.get_server_protocol(&srv_address.to_string(), KEY).await?;
+        assert_eq!(srv_protocol, protocol);
+
+        let srv_cfg = client::config::ServerConfig {
+            caption: None,
+            host: srv_address.to_string(),
+            weight: None,
+            domains: None,
+            apps: None,
+            enabled: true,
+            protocol: srv_protocol,
+            address: srv_address,
+            url_path: None,
+        };
+
+        client.update_pac_content().await;
+        client.add_server(srv_cfg).await;
+        tokio::task::spawn(client.serve());
+        sleep(Duration::from_millis(300)).await;
+
+        let mut stream = connect_via_proxy(proxy_port, echo_address).await?;
+        let data = vec![7u8; 5000];
+        stream.write_all(&data).await?;
+        let mut buf = vec![0u8; data.len()];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(buf, data, "{cipher:?}");
+    }
+
+    Ok(())
+}
+
+async fn udp_echo_server(address: SocketAddr) -> Result<()> {
+    let socket = UdpSocket::bind(address).await?;
+    let mut buf = [0u8; 0xFFFF];
+    loop {
+        let (size, from) = socket.recv_from(&mut buf).await?;
+        socket.send_to(&buf[..size], from).await?;
+    }
+}
+
+async fn echo_server(address: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        tokio::task::spawn(async move {
+            let (mut rd, mut wr) = stream.split();
+            tokio::io::copy(&mut rd, &mut wr).await
+        });
+    }
+}
+
+async fn connect_via_proxy(proxy_port: u16, target: SocketAddr) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), proxy_port)).await?;
+    stream.write_all(format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n").as_bytes()).await?;
+
+    // read response headers
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        response.push(stream.read_u8().await?);
+    }
+    assert!(response.starts_with(b"HTTP/1.1 200"));
+
+    Ok(stream)
+}
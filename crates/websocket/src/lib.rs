@@ -0,0 +1,58 @@
+//! Minimal WebSocket (RFC 6455) support: handshake helpers and binary framing over AsyncRead + AsyncWrite.
+//! Enough to carry the tunnel through CDN and reverse proxies that understand WebSocket.
+
+pub mod stream;
+
+pub use stream::{Role, WsStream};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use rand::RngCore;
+use sha1::{Digest, Sha1};
+
+pub const VERSION: &str = "13";
+
+// from RFC 6455
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Random Sec-WebSocket-Key for the client request
+pub fn generate_key() -> String {
+    let mut key = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut key);
+    STANDARD.encode(key)
+}
+
+/// Sec-WebSocket-Accept for the given Sec-WebSocket-Key
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+/// Header value from HTTP request or response (header name is case insensitive)
+pub fn find_header<'a>(http: &'a str, name: &str) -> Option<&'a str> {
+    http.lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept_key, find_header};
+
+    #[test]
+    fn handshake() {
+        // example from RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let response = "HTTP/1.1 101 Switching Protocols\r\n\
+            upgrade: websocket\r\n\
+            Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+            \r\n";
+        assert_eq!(find_header(response, "Upgrade"), Some("websocket"));
+        assert_eq!(find_header(response, "sec-websocket-accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert_eq!(find_header(response, "Sec-WebSocket-Key"), None);
+    }
+}
@@ -0,0 +1,424 @@
+use std::{
+    cmp,
+    io::{self, ErrorKind},
+    pin::{Pin, pin},
+    task::{Context, Poll},
+};
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{ready, Future};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+// bigger writes are split into several frames
+const MAX_FRAME_SIZE: usize = 0x10000;
+const MAX_CONTROL_FRAME_SIZE: u64 = 125;
+const READ_BUFFER_SIZE: usize = 0x4000;
+
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+const RESERVED: u8 = 0x70;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Role {
+    /// masks outgoing frames, expects unmasked frames
+    Client,
+    /// expects masked frames
+    Server,
+}
+
+/// A stream wrapper that transfers data in binary WebSocket frames.
+/// Pings are answered with pongs, close frame is sent on shutdown
+/// and received close frame is EOF (like TCP half-close).
+pub struct WsStream<S> {
+    inner: S,
+    role: Role,
+
+    read_buffer: BytesMut,
+    read_state: ReadState,
+
+    write_buffer: BytesMut,
+    write_pos: usize,
+    close_sent: bool,
+}
+
+enum ReadState {
+    Header,
+    Data{size: u64, mask: Option<[u8; 4]>, pos: u64},
+    Closed,
+}
+
+struct FrameHeader {
+    fin: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    size: u64,
+    header_size: usize,
+}
+
+impl<S> WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// stream should be already upgraded
+    pub fn from_stream(inner: S, role: Role) -> Self {
+        Self {
+            inner,
+            role,
+            read_buffer: BytesMut::with_capacity(READ_BUFFER_SIZE),
+            read_state: ReadState::Header,
+            write_buffer: BytesMut::new(),
+            write_pos: 0,
+            close_sent: false,
+        }
+    }
+
+    /// Borrow the inner type.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Mut borrow the inner type.
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Consume this wrapper and get the inner type.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn poll_fill_buffer(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        self.read_buffer.reserve(READ_BUFFER_SIZE);
+        let read_buff = self.inner.read_buf(&mut self.read_buffer);
+        pin!(read_buff).poll(cx)
+    }
+
+    fn poll_write_buffer(&mut self, cx: &mut Context<'_>, no_pending: bool) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buffer.len() {
+            match Pin::new(&mut self.inner).poll_write(cx, &self.write_buffer[self.write_pos..]) {
+                Poll::Pending => {
+                    if no_pending {
+                        return Poll::Ready(Ok(()));
+                    }
+                    return Poll::Pending;
+                },
+                Poll::Ready(Ok(n)) => {
+                    if n == 0 {
+                        return Err(ErrorKind::UnexpectedEof.into()).into();
+                    }
+                    self.write_pos += n;
+                },
+                Poll::Ready(Err(err)) => {
+                    return Poll::Ready(Err(err))
+                },
+            }
+        }
+
+        self.write_pos = 0;
+        self.write_buffer.clear();
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn put_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let masked = if self.role == Role::Client { MASKED } else { 0 };
+
+        self.write_buffer.reserve(payload.len() + 14);
+        self.write_buffer.put_u8(FIN | opcode);
+
+        if payload.len() <= MAX_CONTROL_FRAME_SIZE as usize {
+            self.write_buffer.put_u8(masked | payload.len() as u8);
+        } else if payload.len() <= u16::MAX as usize {
+            self.write_buffer.put_u8(masked | 126);
+            self.write_buffer.put_u16(payload.len() as u16);
+        } else {
+            self.write_buffer.put_u8(masked | 127);
+            self.write_buffer.put_u64(payload.len() as u64);
+        }
+
+        if self.role == Role::Client {
+            let mask: [u8; 4] = rand::random();
+            self.write_buffer.put_slice(&mask);
+
+            let start = self.write_buffer.len();
+            self.write_buffer.put_slice(payload);
+            apply_mask(&mut self.write_buffer[start..], mask, 0);
+        } else {
+            self.write_buffer.put_slice(payload);
+        }
+    }
+
+    fn check_header(&self, header: &FrameHeader) -> io::Result<()> {
+        // client must mask frames, server must not
+        if header.mask.is_some() != (self.role == Role::Server) {
+            return Err(io::Error::new(ErrorKind::InvalidData, "wrong websocket frame masking"));
+        }
+
+        match header.opcode {
+            OPCODE_CONTINUATION | OPCODE_BINARY => Ok(()),
+            OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => {
+                if !header.fin || header.size > MAX_CONTROL_FRAME_SIZE {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "wrong websocket control frame"));
+                }
+                Ok(())
+            },
+            _ => Err(io::Error::new(ErrorKind::InvalidData, "unsupported websocket frame")),
+        }
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            match this.read_state {
+                ReadState::Closed => return Ok(()).into(),
+                ReadState::Header => {
+                    let header = match parse_header(&this.read_buffer)? {
+                        Some(header) if header.opcode < OPCODE_CLOSE
+                            || this.read_buffer.len() >= header.header_size + header.size as usize => header,
+                        // control frames are processed at once
+                        Some(header) if header.size > MAX_CONTROL_FRAME_SIZE => {
+                            return Err(io::Error::new(ErrorKind::InvalidData, "wrong websocket control frame")).into();
+                        },
+                        _ => {
+                            if ready!(this.poll_fill_buffer(cx))? == 0 {
+                                if this.read_buffer.is_empty() {
+                                    // EOF
+                                    return Ok(()).into();
+                                }
+                                return Err(ErrorKind::UnexpectedEof.into()).into();
+                            }
+                            continue;
+                        },
+                    };
+
+                    this.check_header(&header)?;
+                    this.read_buffer.advance(header.header_size);
+
+                    match header.opcode {
+                        OPCODE_CONTINUATION | OPCODE_BINARY => {
+                            this.read_state = ReadState::Data { size: header.size, mask: header.mask, pos: 0 };
+                        },
+                        opcode => {
+                            let mut payload = this.read_buffer.split_to(header.size as usize);
+                            if let Some(mask) = header.mask {
+                                apply_mask(&mut payload, mask, 0);
+                            }
+
+                            if opcode == OPCODE_CLOSE {
+                                // our close frame is sent on shutdown
+                                this.read_state = ReadState::Closed;
+                            } else if opcode == OPCODE_PING && !this.close_sent {
+                                this.put_frame(OPCODE_PONG, &payload);
+                                ready!(this.poll_write_buffer(cx, true))?;
+                            }
+                        },
+                    }
+                },
+                ReadState::Data{size, mask, pos} => {
+                    if pos == size {
+                        this.read_state = ReadState::Header;
+                        continue;
+                    }
+
+                    if buf.remaining() == 0 {
+                        return Ok(()).into();
+                    }
+
+                    if this.read_buffer.is_empty() && ready!(this.poll_fill_buffer(cx))? == 0 {
+                        return Err(ErrorKind::UnexpectedEof.into()).into();
+                    }
+
+                    let len = cmp::min(size - pos, cmp::min(this.read_buffer.len(), buf.remaining()) as u64);
+                    let mut data = this.read_buffer.split_to(len as usize);
+                    if let Some(mask) = mask {
+                        apply_mask(&mut data, mask, pos);
+                    }
+
+                    buf.put_slice(&data);
+                    this.read_state = ReadState::Data { size, mask, pos: pos + len };
+
+                    return Ok(()).into();
+                },
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        mut buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+
+        if buf.len() > MAX_FRAME_SIZE {
+            buf = &buf[..MAX_FRAME_SIZE];
+        }
+
+        // flush buffer
+        ready!(this.poll_write_buffer(cx, false))?;
+
+        if this.close_sent {
+            return Err(ErrorKind::BrokenPipe.into()).into();
+        }
+
+        if buf.is_empty() {
+            return Ok(0).into();
+        }
+
+        this.put_frame(OPCODE_BINARY, buf);
+
+        // try to flush buffer
+        ready!(this.poll_write_buffer(cx, true))?;
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+
+        // write if buffer not empty
+        ready!(this.poll_write_buffer(cx, false))?;
+
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+
+        if !this.close_sent {
+            this.put_frame(OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes());
+            this.close_sent = true;
+        }
+
+        // write if buffer not empty
+        ready!(this.poll_write_buffer(cx, false))?;
+
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Returns None if there is not enough data
+fn parse_header(data: &[u8]) -> io::Result<Option<FrameHeader>> {
+    if data.len() < 2 {
+        return Ok(None);
+    }
+
+    if data[0] & RESERVED != 0 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "websocket extensions are not supported"));
+    }
+
+    let fin = data[0] & FIN != 0;
+    let opcode = data[0] & 0x0F;
+    let masked = data[1] & MASKED != 0;
+
+    let (size, mut header_size) = match data[1] & 0x7F {
+        126 if data.len() >= 4 => (u16::from_be_bytes([data[2], data[3]]) as u64, 4),
+        127 if data.len() >= 10 => (u64::from_be_bytes(data[2..10].try_into().unwrap()), 10),
+        126 | 127 => return Ok(None),
+        size => (size as u64, 2),
+    };
+
+    let mask = if masked {
+        if data.len() < header_size + 4 {
+            return Ok(None);
+        }
+        let mask = data[header_size..header_size + 4].try_into().unwrap();
+        header_size += 4;
+        Some(mask)
+    } else {
+        None
+    };
+
+    Ok(Some(FrameHeader { fin, opcode, mask, size, header_size }))
+}
+
+/// offset - position of data in the frame payload
+fn apply_mask(data: &mut [u8], mask: [u8; 4], offset: u64) {
+    let offset = (offset % 4) as usize;
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[(offset + i) % 4];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Role, WsStream, MAX_FRAME_SIZE};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn ws_stream() {
+        let (client, server) = duplex(1024);
+        let mut client = WsStream::from_stream(client, Role::Client);
+        let mut server = WsStream::from_stream(server, Role::Server);
+
+        let data: Vec<u8> = (0..MAX_FRAME_SIZE * 2 + 100).map(|i| i as u8).collect();
+
+        let send = data.clone();
+        let writer = tokio::spawn(async move {
+            client.write_all(&send).await.unwrap();
+            client.write_all(b"small").await.unwrap();
+            client.shutdown().await.unwrap();
+
+            // server data after client close
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            response
+        });
+
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(&received[..data.len()], &data[..]);
+        assert_eq!(&received[data.len()..], b"small");
+
+        server.write_all(b"response").await.unwrap();
+        server.shutdown().await.unwrap();
+
+        assert_eq!(writer.await.unwrap(), b"response");
+    }
+
+    #[tokio::test]
+    async fn ws_ping_and_masking() {
+        let (client, mut server) = duplex(1024);
+        let mut client = WsStream::from_stream(client, Role::Client);
+
+        // unmasked ping and binary frame from server
+        server.write_all(&[0x89, 0x02, b'h', b'i', 0x82, 0x03, b'a', b'b', b'c']).await.unwrap();
+
+        let mut buf = [0u8; 3];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"abc");
+
+        // masked pong
+        let mut pong = [0u8; 8];
+        server.read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong[0], 0x8A);
+        assert_eq!(pong[1], 0x82);
+        assert_eq!([pong[6] ^ pong[2], pong[7] ^ pong[3]], *b"hi");
+
+        // client doesn't accept masked frames
+        server.write_all(&[0x82, 0x81, 1, 2, 3, 4, 5]).await.unwrap();
+        assert!(client.read(&mut buf).await.is_err());
+    }
+}
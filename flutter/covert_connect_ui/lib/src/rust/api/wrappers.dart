@@ -8,7 +8,7 @@ import 'package:flutter_rust_bridge/flutter_rust_bridge_for_generated.dart';
 
 // These function are ignored because they are on traits that is not defined in current crate (put an empty `#[frb]` on it to unignore): `clone`, `clone`, `clone`, `fmt`, `fmt`, `fmt`, `from`, `from`, `into`, `into`
 
-enum CipherType { aes256Gcm, chaCha20Poly1305 }
+enum CipherType { aes256Gcm, chaCha20Poly1305, xChaCha20Poly1305, aes128Gcm }
 
 class DataPadding {
   final int max;
@@ -48,6 +48,8 @@ class HeaderPadding {
 
 enum Kdf { argon2, blake3 }
 
+enum KeyExchange { none, x25519, x25519MlKem768 }
+
 class ProtocolConfig {
   final String key;
   final Kdf kdf;
@@ -56,6 +58,7 @@ class ProtocolConfig {
   final HeaderPadding headerPadding;
   final DataPadding dataPadding;
   final BigInt encryptionLimit;
+  final KeyExchange keyExchange;
 
   const ProtocolConfig({
     required this.key,
@@ -65,6 +68,7 @@ class ProtocolConfig {
     required this.headerPadding,
     required this.dataPadding,
     required this.encryptionLimit,
+    required this.keyExchange,
   });
 
   @override
@@ -75,7 +79,8 @@ class ProtocolConfig {
       maxConnectDelay.hashCode ^
       headerPadding.hashCode ^
       dataPadding.hashCode ^
-      encryptionLimit.hashCode;
+      encryptionLimit.hashCode ^
+      keyExchange.hashCode;
 
   @override
   bool operator ==(Object other) =>
@@ -88,7 +93,8 @@ class ProtocolConfig {
           maxConnectDelay == other.maxConnectDelay &&
           headerPadding == other.headerPadding &&
           dataPadding == other.dataPadding &&
-          encryptionLimit == other.encryptionLimit;
+          encryptionLimit == other.encryptionLimit &&
+          keyExchange == other.keyExchange;
 }
 
 class ServerConfig {
@@ -1671,6 +1671,12 @@ class RustLibApiImpl extends RustLibApiImplPlatform implements RustLibApi {
     return Kdf.values[raw as int];
   }
 
+  @protected
+  KeyExchange dco_decode_key_exchange(dynamic raw) {
+    // Codec=Dco (DartCObject based), see doc to use other codecs
+    return KeyExchange.values[raw as int];
+  }
+
   @protected
   List<String> dco_decode_list_String(dynamic raw) {
     // Codec=Dco (DartCObject based), see doc to use other codecs
@@ -1741,8 +1747,8 @@ class RustLibApiImpl extends RustLibApiImplPlatform implements RustLibApi {
   ProtocolConfig dco_decode_protocol_config(dynamic raw) {
     // Codec=Dco (DartCObject based), see doc to use other codecs
     final arr = raw as List<dynamic>;
-    if (arr.length != 7)
-      throw Exception('unexpected arr length: expect 7 but see ${arr.length}');
+    if (arr.length != 8)
+      throw Exception('unexpected arr length: expect 8 but see ${arr.length}');
     return ProtocolConfig(
       key: dco_decode_String(arr[0]),
       kdf: dco_decode_kdf(arr[1]),
@@ -1751,6 +1757,7 @@ class RustLibApiImpl extends RustLibApiImplPlatform implements RustLibApi {
       headerPadding: dco_decode_header_padding(arr[4]),
       dataPadding: dco_decode_data_padding(arr[5]),
       encryptionLimit: dco_decode_usize(arr[6]),
+      keyExchange: dco_decode_key_exchange(arr[7]),
     );
   }
 
@@ -2061,6 +2068,13 @@ class RustLibApiImpl extends RustLibApiImplPlatform implements RustLibApi {
     return Kdf.values[inner];
   }
 
+  @protected
+  KeyExchange sse_decode_key_exchange(SseDeserializer deserializer) {
+    // Codec=Sse (Serialization based), see doc to use other codecs
+    var inner = sse_decode_i_32(deserializer);
+    return KeyExchange.values[inner];
+  }
+
   @protected
   List<String> sse_decode_list_String(SseDeserializer deserializer) {
     // Codec=Sse (Serialization based), see doc to use other codecs
@@ -2180,6 +2194,7 @@ class RustLibApiImpl extends RustLibApiImplPlatform implements RustLibApi {
     var var_headerPadding = sse_decode_header_padding(deserializer);
     var var_dataPadding = sse_decode_data_padding(deserializer);
     var var_encryptionLimit = sse_decode_usize(deserializer);
+    var var_keyExchange = sse_decode_key_exchange(deserializer);
     return ProtocolConfig(
       key: var_key,
       kdf: var_kdf,
@@ -2188,6 +2203,7 @@ class RustLibApiImpl extends RustLibApiImplPlatform implements RustLibApi {
       headerPadding: var_headerPadding,
       dataPadding: var_dataPadding,
       encryptionLimit: var_encryptionLimit,
+      keyExchange: var_keyExchange,
     );
   }
 
@@ -2529,6 +2545,12 @@ class RustLibApiImpl extends RustLibApiImplPlatform implements RustLibApi {
     sse_encode_i_32(self.index, serializer);
   }
 
+  @protected
+  void sse_encode_key_exchange(KeyExchange self, SseSerializer serializer) {
+    // Codec=Sse (Serialization based), see doc to use other codecs
+    sse_encode_i_32(self.index, serializer);
+  }
+
   @protected
   void sse_encode_list_String(List<String> self, SseSerializer serializer) {
     // Codec=Sse (Serialization based), see doc to use other codecs
@@ -2644,6 +2666,7 @@ class RustLibApiImpl extends RustLibApiImplPlatform implements RustLibApi {
     sse_encode_header_padding(self.headerPadding, serializer);
     sse_encode_data_padding(self.dataPadding, serializer);
     sse_encode_usize(self.encryptionLimit, serializer);
+    sse_encode_key_exchange(self.keyExchange, serializer);
   }
 
   @protected
@@ -127,6 +127,9 @@ abstract class RustLibApiImplPlatform extends BaseApiImpl<RustLibWire> {
   @protected
   Kdf dco_decode_kdf(dynamic raw);
 
+  @protected
+  KeyExchange dco_decode_key_exchange(dynamic raw);
+
   @protected
   List<String> dco_decode_list_String(dynamic raw);
 
@@ -288,6 +291,9 @@ abstract class RustLibApiImplPlatform extends BaseApiImpl<RustLibWire> {
   @protected
   Kdf sse_decode_kdf(SseDeserializer deserializer);
 
+  @protected
+  KeyExchange sse_decode_key_exchange(SseDeserializer deserializer);
+
   @protected
   List<String> sse_decode_list_String(SseDeserializer deserializer);
 
@@ -472,6 +478,9 @@ abstract class RustLibApiImplPlatform extends BaseApiImpl<RustLibWire> {
   @protected
   void sse_encode_kdf(Kdf self, SseSerializer serializer);
 
+  @protected
+  void sse_encode_key_exchange(KeyExchange self, SseSerializer serializer);
+
   @protected
   void sse_encode_list_String(List<String> self, SseSerializer serializer);
 
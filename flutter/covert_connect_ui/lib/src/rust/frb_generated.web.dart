@@ -129,6 +129,9 @@ abstract class RustLibApiImplPlatform extends BaseApiImpl<RustLibWire> {
   @protected
   Kdf dco_decode_kdf(dynamic raw);
 
+  @protected
+  KeyExchange dco_decode_key_exchange(dynamic raw);
+
   @protected
   List<String> dco_decode_list_String(dynamic raw);
 
@@ -290,6 +293,9 @@ abstract class RustLibApiImplPlatform extends BaseApiImpl<RustLibWire> {
   @protected
   Kdf sse_decode_kdf(SseDeserializer deserializer);
 
+  @protected
+  KeyExchange sse_decode_key_exchange(SseDeserializer deserializer);
+
   @protected
   List<String> sse_decode_list_String(SseDeserializer deserializer);
 
@@ -474,6 +480,9 @@ abstract class RustLibApiImplPlatform extends BaseApiImpl<RustLibWire> {
   @protected
   void sse_encode_kdf(Kdf self, SseSerializer serializer);
 
+  @protected
+  void sse_encode_key_exchange(KeyExchange self, SseSerializer serializer);
+
   @protected
   void sse_encode_list_String(List<String> self, SseSerializer serializer);
 
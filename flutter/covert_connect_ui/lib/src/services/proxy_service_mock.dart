@@ -378,6 +378,7 @@ List<ServerInfo> servers = [
         headerPadding: HeaderPadding(start: 50, end: 777),
         dataPadding: DataPadding(max: 250, rate: 10),
         encryptionLimit: BigInt.parse("18446744073709551615"),
+        keyExchange: KeyExchange.none,
       ),
     ),
     ip: "14.55.141.189",
@@ -404,6 +405,7 @@ List<ServerInfo> servers = [
         headerPadding: HeaderPadding(start: 50, end: 777),
         dataPadding: DataPadding(max: 250, rate: 10),
         encryptionLimit: BigInt.parse("18446744073709551615"),
+        keyExchange: KeyExchange.none,
       ),
     ),
     ip: "3.155.36.44",
@@ -428,6 +430,7 @@ List<ServerInfo> servers = [
         headerPadding: HeaderPadding(start: 50, end: 777),
         dataPadding: DataPadding(max: 250, rate: 10),
         encryptionLimit: BigInt.parse("18446744073709551615"),
+        keyExchange: KeyExchange.none,
       ),
     ),
     ip: "5.255.96.144",
@@ -457,6 +460,7 @@ ServerInfo newServer = ServerInfo(
       headerPadding: HeaderPadding(start: 50, end: 777),
       dataPadding: DataPadding(max: 250, rate: 10),
       encryptionLimit: BigInt.parse("18446744073709551615"),
+      keyExchange: KeyExchange.none,
     ),
   ),
   ip: "2.143.89.114",
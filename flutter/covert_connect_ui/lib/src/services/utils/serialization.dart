@@ -24,6 +24,7 @@ const kHPaddingEnd = "h_end";
 const kDPaddingMax = "d_max";
 const kDPaddingRate = "d_rate";
 const kEncryptionLimit = "encryptionLimit";
+const kKeyExchange = "keyExchange";
 
 ProxyConfig proxyConfigFromString(String configStr) {
   final json = jsonDecode(configStr);
@@ -58,6 +59,7 @@ ProtocolConfig protocolConfigFromJson(Map<String, dynamic> json) {
     headerPadding: HeaderPadding(start: json[kHPaddingStart] as int, end: json[kHPaddingEnd] as int),
     dataPadding: DataPadding(max: json[kDPaddingRate] as int, rate: json[kDPaddingRate] as int),
     encryptionLimit: BigInt.parse(json[kEncryptionLimit] as String),
+    keyExchange: KeyExchange.values.byName(json[kKeyExchange] as String? ?? KeyExchange.none.name),
   );
 }
 
@@ -89,4 +91,5 @@ Map<String, dynamic> protocolConfigToJson(ProtocolConfig value) => {
   kDPaddingMax: value.dataPadding.max,
   kDPaddingRate: value.dataPadding.rate,
   kEncryptionLimit: value.encryptionLimit.toString(),
+  kKeyExchange: value.keyExchange.name,
 };
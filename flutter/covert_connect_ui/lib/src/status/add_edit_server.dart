@@ -184,6 +184,7 @@ class _AddEditServerPageState extends State<AddEditServerPage> {
         _ProtocolItem("Data padding maximum length", "${_protocol!.dataPadding.max} bytes"),
         _ProtocolItem("Header padding", "${_protocol!.headerPadding.start}..${_protocol!.headerPadding.end}"),
         _ProtocolItem("Encription limit per connection", _protocol!.encryptionLimit.toString()),
+        _ProtocolItem("Key exchange", _protocol!.keyExchange.toString()),
       ],
     );
   }
@@ -109,6 +109,12 @@ impl WriterNotifier {
     }
 }
 
+impl Default for WriterNotifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct WriterNotifierWrapper(Arc<WriterNotifier>);
 
 impl WriterNotifier {
@@ -118,7 +124,7 @@ impl WriterNotifier {
     ) -> Result<u64> {
         let id = self.next_id.load(Ordering::Relaxed);
         self.callbacks.write().await.push(Callback {
-            id: id,
+            id,
             callback: Box::new(callback),
         });
         self.next_id.fetch_add(1, Ordering::Relaxed);
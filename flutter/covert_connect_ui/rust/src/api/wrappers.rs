@@ -4,7 +4,7 @@ use std::ops::Range;
 
 pub use client::config::ServerConfig as ClientServerConfig;
 pub use crypto::config::{DataPadding, ProtocolConfig as CryptoProtocolConfig};
-pub use crypto::{cipher::CipherType, kdf::Kdf};
+pub use crypto::{cipher::CipherType, kdf::Kdf, key_exchange::KeyExchange};
 
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
@@ -17,30 +17,30 @@ pub struct ServerConfig {
     pub protocol: ProtocolConfig,
 }
 
-impl Into<ClientServerConfig> for ServerConfig {
-    fn into(self) -> ClientServerConfig {
-        return ClientServerConfig {
-            caption: self.caption.clone(),
-            host: self.host.clone(),
-            weight: self.weight.clone(),
-            domains: self.domains.clone(),
-            apps: self.apps.clone(),
-            enabled: self.enabled,
-            protocol: self.protocol.into(),
+impl From<ServerConfig> for ClientServerConfig {
+    fn from(cfg: ServerConfig) -> Self {
+        ClientServerConfig {
+            caption: cfg.caption,
+            host: cfg.host,
+            weight: cfg.weight,
+            domains: cfg.domains,
+            apps: cfg.apps,
+            enabled: cfg.enabled,
+            protocol: cfg.protocol.into(),
             url_path: None,
             address: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0u16),
-        };
+        }
     }
 }
 
 impl From<ClientServerConfig> for ServerConfig {
     fn from(cfg: ClientServerConfig) -> Self {
-        return ServerConfig {
-            caption: cfg.caption.clone(),
-            host: cfg.host.clone(),
-            weight: cfg.weight.clone(),
-            domains: cfg.domains.clone(),
-            apps: cfg.apps.clone(),
+        ServerConfig {
+            caption: cfg.caption,
+            host: cfg.host,
+            weight: cfg.weight,
+            domains: cfg.domains,
+            apps: cfg.apps,
             enabled: cfg.enabled,
             protocol: cfg.protocol.into(),
         }
@@ -56,28 +56,30 @@ pub struct ProtocolConfig {
     pub header_padding: HeaderPadding,
     pub data_padding: DataPadding,
     pub encryption_limit: usize,
+    pub key_exchange: KeyExchange,
 }
 
-impl Into<CryptoProtocolConfig> for ProtocolConfig {
-    fn into(self) -> CryptoProtocolConfig {
-        return CryptoProtocolConfig {
-            key: self.key,
-            kdf: self.kdf,
-            cipher: self.cipher,
-            max_connect_delay: self.max_connect_delay,
+impl From<ProtocolConfig> for CryptoProtocolConfig {
+    fn from(cfg: ProtocolConfig) -> Self {
+        CryptoProtocolConfig {
+            key: cfg.key,
+            kdf: cfg.kdf,
+            cipher: cfg.cipher,
+            max_connect_delay: cfg.max_connect_delay,
             header_padding: Range {
-                start: self.header_padding.start,
-                end: self.header_padding.end,
+                start: cfg.header_padding.start,
+                end: cfg.header_padding.end,
             },
-            data_padding: self.data_padding,
-            encryption_limit: self.encryption_limit,
-        };
+            data_padding: cfg.data_padding,
+            encryption_limit: cfg.encryption_limit,
+            key_exchange: cfg.key_exchange,
+        }
     }
 }
 
 impl From<CryptoProtocolConfig> for ProtocolConfig {
     fn from(cfg: CryptoProtocolConfig) -> Self {
-        return ProtocolConfig {
+        ProtocolConfig {
             key: cfg.key,
             kdf: cfg.kdf,
             cipher: cfg.cipher,
@@ -88,6 +90,7 @@ impl From<CryptoProtocolConfig> for ProtocolConfig {
             },
             data_padding: cfg.data_padding,
             encryption_limit: cfg.encryption_limit,
+            key_exchange: cfg.key_exchange,
         }
     }
 }
@@ -114,4 +117,13 @@ pub enum _Kdf {
 pub enum _CipherType {
     Aes256Gcm,
     ChaCha20Poly1305,
+    XChaCha20Poly1305,
+    Aes128Gcm,
+}
+
+#[frb(mirror(KeyExchange))]
+pub enum _KeyExchange {
+    None,
+    X25519,
+    X25519MlKem768,
 }
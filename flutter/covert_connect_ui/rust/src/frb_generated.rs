@@ -1601,6 +1601,8 @@ impl SseDecode for crate::api::wrappers::CipherType {
         return match inner {
             0 => crate::api::wrappers::CipherType::Aes256Gcm,
             1 => crate::api::wrappers::CipherType::ChaCha20Poly1305,
+            2 => crate::api::wrappers::CipherType::XChaCha20Poly1305,
+            3 => crate::api::wrappers::CipherType::Aes128Gcm,
             _ => unreachable!("Invalid variant for CipherType: {}", inner),
         };
     }
@@ -1656,6 +1658,19 @@ impl SseDecode for crate::api::wrappers::Kdf {
     }
 }
 
+impl SseDecode for crate::api::wrappers::KeyExchange {
+    // Codec=Sse (Serialization based), see doc to use other codecs
+    fn sse_decode(deserializer: &mut flutter_rust_bridge::for_generated::SseDeserializer) -> Self {
+        let mut inner = <i32>::sse_decode(deserializer);
+        return match inner {
+            0 => crate::api::wrappers::KeyExchange::None,
+            1 => crate::api::wrappers::KeyExchange::X25519,
+            2 => crate::api::wrappers::KeyExchange::X25519MlKem768,
+            _ => unreachable!("Invalid variant for KeyExchange: {}", inner),
+        };
+    }
+}
+
 impl SseDecode for Vec<String> {
     // Codec=Sse (Serialization based), see doc to use other codecs
     fn sse_decode(deserializer: &mut flutter_rust_bridge::for_generated::SseDeserializer) -> Self {
@@ -1782,6 +1797,7 @@ impl SseDecode for crate::api::wrappers::ProtocolConfig {
         let mut var_headerPadding = <crate::api::wrappers::HeaderPadding>::sse_decode(deserializer);
         let mut var_dataPadding = <crate::api::wrappers::DataPadding>::sse_decode(deserializer);
         let mut var_encryptionLimit = <usize>::sse_decode(deserializer);
+        let mut var_keyExchange = <crate::api::wrappers::KeyExchange>::sse_decode(deserializer);
         return crate::api::wrappers::ProtocolConfig {
             key: var_key,
             kdf: var_kdf,
@@ -1790,6 +1806,7 @@ impl SseDecode for crate::api::wrappers::ProtocolConfig {
             header_padding: var_headerPadding,
             data_padding: var_dataPadding,
             encryption_limit: var_encryptionLimit,
+            key_exchange: var_keyExchange,
         };
     }
 }
@@ -2039,6 +2056,8 @@ impl flutter_rust_bridge::IntoDart for FrbWrapper<crate::api::wrappers::CipherTy
         match self.0 {
             crate::api::wrappers::CipherType::Aes256Gcm => 0.into_dart(),
             crate::api::wrappers::CipherType::ChaCha20Poly1305 => 1.into_dart(),
+            crate::api::wrappers::CipherType::XChaCha20Poly1305 => 2.into_dart(),
+            crate::api::wrappers::CipherType::Aes128Gcm => 3.into_dart(),
             _ => unreachable!(),
         }
     }
@@ -2102,6 +2121,23 @@ impl flutter_rust_bridge::IntoIntoDart<FrbWrapper<crate::api::wrappers::Kdf>> fo
     }
 }
 // Codec=Dco (DartCObject based), see doc to use other codecs
+impl flutter_rust_bridge::IntoDart for FrbWrapper<crate::api::wrappers::KeyExchange> {
+    fn into_dart(self) -> flutter_rust_bridge::for_generated::DartAbi {
+        match self.0 {
+            crate::api::wrappers::KeyExchange::None => 0.into_dart(),
+            crate::api::wrappers::KeyExchange::X25519 => 1.into_dart(),
+            crate::api::wrappers::KeyExchange::X25519MlKem768 => 2.into_dart(),
+            _ => unreachable!(),
+        }
+    }
+}
+impl flutter_rust_bridge::for_generated::IntoDartExceptPrimitive for FrbWrapper<crate::api::wrappers::KeyExchange> {}
+impl flutter_rust_bridge::IntoIntoDart<FrbWrapper<crate::api::wrappers::KeyExchange>> for crate::api::wrappers::KeyExchange {
+    fn into_into_dart(self) -> FrbWrapper<crate::api::wrappers::KeyExchange> {
+        self.into()
+    }
+}
+// Codec=Dco (DartCObject based), see doc to use other codecs
 impl flutter_rust_bridge::IntoDart for crate::api::log::LogLine {
     fn into_dart(self) -> flutter_rust_bridge::for_generated::DartAbi {
         [
@@ -2128,6 +2164,7 @@ impl flutter_rust_bridge::IntoDart for crate::api::wrappers::ProtocolConfig {
             self.header_padding.into_into_dart().into_dart(),
             self.data_padding.into_into_dart().into_dart(),
             self.encryption_limit.into_into_dart().into_dart(),
+            self.key_exchange.into_into_dart().into_dart(),
         ]
         .into_dart()
     }
@@ -2342,6 +2379,8 @@ impl SseEncode for crate::api::wrappers::CipherType {
             match self {
                 crate::api::wrappers::CipherType::Aes256Gcm => 0,
                 crate::api::wrappers::CipherType::ChaCha20Poly1305 => 1,
+                crate::api::wrappers::CipherType::XChaCha20Poly1305 => 2,
+                crate::api::wrappers::CipherType::Aes128Gcm => 3,
                 _ => {
                     unimplemented!("");
                 }
@@ -2397,6 +2436,23 @@ impl SseEncode for crate::api::wrappers::Kdf {
     }
 }
 
+impl SseEncode for crate::api::wrappers::KeyExchange {
+    // Codec=Sse (Serialization based), see doc to use other codecs
+    fn sse_encode(self, serializer: &mut flutter_rust_bridge::for_generated::SseSerializer) {
+        <i32>::sse_encode(
+            match self {
+                crate::api::wrappers::KeyExchange::None => 0,
+                crate::api::wrappers::KeyExchange::X25519 => 1,
+                crate::api::wrappers::KeyExchange::X25519MlKem768 => 2,
+                _ => {
+                    unimplemented!("");
+                }
+            },
+            serializer,
+        );
+    }
+}
+
 impl SseEncode for Vec<String> {
     // Codec=Sse (Serialization based), see doc to use other codecs
     fn sse_encode(self, serializer: &mut flutter_rust_bridge::for_generated::SseSerializer) {
@@ -2505,6 +2561,7 @@ impl SseEncode for crate::api::wrappers::ProtocolConfig {
         <crate::api::wrappers::HeaderPadding>::sse_encode(self.header_padding, serializer);
         <crate::api::wrappers::DataPadding>::sse_encode(self.data_padding, serializer);
         <usize>::sse_encode(self.encryption_limit, serializer);
+        <crate::api::wrappers::KeyExchange>::sse_encode(self.key_exchange, serializer);
     }
 }
 